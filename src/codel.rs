@@ -31,7 +31,7 @@ impl Codel {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Direction {
     Right,
     Left,
@@ -40,6 +40,16 @@ pub enum Direction {
 }
 
 impl Direction {
+    pub fn name(self) -> &'static str {
+        use Direction::*;
+        match self {
+            Right => "right",
+            Left => "left",
+            Down => "down",
+            Up => "up",
+        }
+    }
+
     pub fn to_vector(self) -> (isize, isize) {
         use Direction::*;
         match self {
@@ -0,0 +1,324 @@
+use codel::*;
+use interpreter::*;
+use picture::*;
+use pietcolor::*;
+use serde_json::Value;
+use std::*;
+
+/* Coverage of a program by the runs of a test suite: which color blocks were entered and which
+ * exits were taken, an exit being a block left with a given dp and cc. Blocks are identified by
+ * their first codel in reading order. White and black blocks aren't counted, as the interpreter
+ * doesn't leave white blocks through exits and never enters black ones.
+ * */
+
+pub const COVERAGE_VERSION: u64 = 2;
+
+const DIRECTIONS: [Direction; 4] =
+    [Direction::Right, Direction::Down, Direction::Left, Direction::Up];
+const CHOOSERS: [Direction; 2] = [Direction::Left, Direction::Right];
+
+/* width, height: The size of the picture in codels.
+ * hash: The hash of the picture, which has to match when runs are merged.
+ * */
+pub struct Coverage {
+    blocks: Blocks,
+    width: usize,
+    height: usize,
+    hash: String,
+    runs: u64,
+    entered: collections::BTreeSet<(usize, usize)>,
+    exits: collections::BTreeSet<(usize, usize, &'static str, &'static str)>,
+}
+
+fn counted(picture: &[Vec<Codel>], (x, y): (usize, usize)) -> bool {
+    let hue = picture[y][x].color.hue;
+    hue != Hue::White && hue != Hue::Black
+}
+
+/* Returns the FNV-1a hash of the size and colors of a picture as hexadecimal. It is computed by
+ * hand as the hashers of std don't promise to give the same hash from one version to the next.
+ * */
+fn hash(picture: &[Vec<Codel>]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut add = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    add(&(picture[0].len() as u64).to_le_bytes());
+    add(&(picture.len() as u64).to_le_bytes());
+    for codel in picture.iter().flat_map(|row| row.iter()) {
+        add(&codel.color.to_rgb());
+    }
+    format!("{:016x}", hash)
+}
+
+fn direction(name: &str, choices: &[Direction]) -> Option<&'static str> {
+    choices.iter().map(|d| d.name()).find(|&d| d == name)
+}
+
+impl Coverage {
+    /* Loads the coverage accumulated in a file, or returns an empty coverage if it doesn't exist.
+     * The block the program starts in counts as entered.
+     * */
+    pub fn load(path: &str, picture: &[Vec<Codel>]) -> Result<Coverage, String> {
+        let mut coverage = Coverage {
+            blocks: get_blocks(picture),
+            width: picture[0].len(),
+            height: picture.len(),
+            hash: hash(picture),
+            runs: 0,
+            entered: collections::BTreeSet::new(),
+            exits: collections::BTreeSet::new(),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(coverage),
+            Err(e) => return Err(format!("Error: {}: {}", path, e)),
+        };
+        let invalid =
+            || format!("Error: {} isn't a version {} coverage file.", path, COVERAGE_VERSION);
+        let saved: Value = serde_json::from_str(&text).map_err(|_| invalid())?;
+        if saved["format"] != "pieti-coverage" || saved["version"] != COVERAGE_VERSION {
+            return Err(invalid());
+        }
+        if saved["width"] != coverage.width || saved["height"] != coverage.height {
+            return Err(format!("Error: {} is the coverage of a picture of another size.", path));
+        }
+        if saved["picture"] != coverage.hash.as_str() {
+            return Err(format!("Error: {} is the coverage of another picture.", path));
+        }
+        coverage.runs = saved["runs"].as_u64().ok_or_else(invalid)?;
+        let position = |value: &Value| -> Option<(usize, usize)> {
+            let (x, y) = (value[0].as_u64()? as usize, value[1].as_u64()? as usize);
+            if y < coverage.height && x < coverage.width { Some((x, y)) } else { None }
+        };
+        let mut entered = collections::BTreeSet::new();
+        for block in saved["blocks"].as_array().ok_or_else(invalid)? {
+            entered.insert(position(block).ok_or_else(invalid)?);
+        }
+        let mut exits = collections::BTreeSet::new();
+        for exit in saved["exits"].as_array().ok_or_else(invalid)? {
+            let (x, y) = position(exit).ok_or_else(invalid)?;
+            let dp = exit[2].as_str().and_then(|dp| direction(dp, &DIRECTIONS));
+            let cc = exit[3].as_str().and_then(|cc| direction(cc, &CHOOSERS));
+            exits.insert((x, y, dp.ok_or_else(invalid)?, cc.ok_or_else(invalid)?));
+        }
+        coverage.entered = entered;
+        coverage.exits = exits;
+        Ok(coverage)
+    }
+
+    fn block_id(&self, codel: &Codel) -> (usize, usize) {
+        self.blocks.block_of(codel).codels[0]
+    }
+
+    /* Starts covering a new run. */
+    pub fn start(&mut self, interpreter: &Interpreter) {
+        self.runs += 1;
+        let start = self.block_id(&interpreter.codel);
+        self.entered.insert(start);
+    }
+
+    pub fn record(&mut self, step: &Step) {
+        let (x, y) = self.block_id(&step.from);
+        if step.from.color.hue != Hue::White {
+            self.exits.insert((x, y, step.dp.name(), step.cc.name()));
+        }
+        let entered = self.block_id(&step.to);
+        self.entered.insert(entered);
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let blocks: Vec<Value> = self.entered.iter().map(|&(x, y)| json!([x, y])).collect();
+        let exits: Vec<Value> =
+            self.exits.iter().map(|&(x, y, dp, cc)| json!([x, y, dp, cc])).collect();
+        let coverage = json!({
+            "format": "pieti-coverage",
+            "version": COVERAGE_VERSION,
+            "width": self.width,
+            "height": self.height,
+            "picture": self.hash,
+            "runs": self.runs,
+            "blocks": blocks,
+            "exits": exits,
+        });
+        fs::write(path, format!("{}\n", coverage))
+    }
+
+    /* Returns the percentages of blocks and exits covered, and the blocks that were never
+     * entered.
+     * */
+    pub fn report(&self, picture: &[Vec<Codel>]) -> String {
+        let blocks: Vec<(usize, usize)> = self.blocks
+            .blocks
+            .iter()
+            .map(|block| block.codels[0])
+            .filter(|&codel| counted(picture, codel))
+            .collect();
+        let entered = blocks.iter().filter(|codel| self.entered.contains(codel)).count();
+        let exits = self.exits.iter().filter(|&&(x, y, _, _)| counted(picture, (x, y))).count();
+        let percentage = |count: usize, total: usize| {
+            if total == 0 { 100.0 } else { count as f64 * 100.0 / total as f64 }
+        };
+        let mut report = format!(
+            "Runs: {}\nBlocks: {}/{} ({:.1}%)\nExits: {}/{} ({:.1}%)\n",
+            self.runs,
+            entered,
+            blocks.len(),
+            percentage(entered, blocks.len()),
+            exits,
+            blocks.len() * 8,
+            percentage(exits, blocks.len() * 8)
+        );
+        for &(x, y) in blocks.iter().filter(|codel| !self.entered.contains(codel)) {
+            let color = picture[y][x].color;
+            report.push_str(&format!(
+                "Never entered: ({}, {}) {:?} {:?}\n",
+                x,
+                y,
+                color.lightness,
+                color.hue
+            ));
+        }
+        report
+    }
+
+    /* Returns the colors of the picture with the blocks that were entered faded out, so that
+     * the ones that weren't stand out.
+     * */
+    pub fn render(&self, picture: &[Vec<Codel>]) -> Vec<Vec<[u8; 3]>> {
+        picture
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|codel| {
+                        let color = codel.color.to_rgb();
+                        let id = self.block_id(codel);
+                        if !counted(picture, id) || !self.entered.contains(&id) {
+                            return color;
+                        }
+                        let fade = |channel: u8| ((channel as u16 + 3 * 0xFF) / 4) as u8;
+                        [fade(color[0]), fade(color[1]), fade(color[2])]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use machine::*;
+    use numbers::*;
+
+    fn temporary(name: &str) -> String {
+        let path = env::temp_dir().join(format!("pieti-coverage-{}-{}.json", name, process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn color(hue: Hue, lightness: Lightness) -> PietColor {
+        PietColor { hue, lightness }
+    }
+
+    fn picture(colors: &[Vec<PietColor>]) -> Vec<Vec<Codel>> {
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        to_codels(&pixels, 1, color(Hue::White, Lightness::Normal), false)
+    }
+
+    fn program(text: &str) -> Vec<Vec<Codel>> {
+        let mut synthesizer =
+            Synthesizer::new(CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 });
+        picture(&layout(&print_program(text, &mut synthesizer)))
+    }
+
+    /* Covers a run of up to `count` steps. */
+    fn run(picture: &[Vec<Codel>], coverage: &mut Coverage, count: usize) {
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()));
+        let mut interpreter = Interpreter::new(picture, machine);
+        coverage.start(&interpreter);
+        for _ in 0..count {
+            match interpreter.step() {
+                Some(step) => coverage.record(&step),
+                None => break,
+            }
+        }
+    }
+
+    #[test]
+    fn merges_runs() {
+        let path = temporary("merge");
+        let picture = program("Hi");
+        let mut first = Coverage::load(&path, &picture).unwrap();
+        run(&picture, &mut first, 3);
+        first.save(&path).unwrap();
+
+        let mut merged = Coverage::load(&path, &picture).unwrap();
+        assert_eq!(merged.runs, 1);
+        assert_eq!(merged.entered, first.entered);
+        assert_eq!(merged.exits, first.exits);
+        run(&picture, &mut merged, usize::MAX);
+        merged.save(&path).unwrap();
+
+        let mut whole = Coverage::load(&temporary("missing"), &picture).unwrap();
+        run(&picture, &mut whole, usize::MAX);
+        let merged = Coverage::load(&path, &picture).unwrap();
+        assert_eq!(merged.runs, 2);
+        assert!(first.entered.len() < whole.entered.len());
+        assert_eq!(merged.entered, whole.entered);
+        assert_eq!(merged.exits, whole.exits);
+        assert!(merged.report(&picture).starts_with("Runs: 2\n"));
+        fs::remove_file(&path).unwrap_or(());
+    }
+
+    #[test]
+    fn counts_eight_exits_per_block() {
+        let picture = picture(&[vec![
+            color(Hue::Red, Lightness::Light),
+            color(Hue::Red, Lightness::Normal),
+            color(Hue::White, Lightness::Normal),
+            color(Hue::Black, Lightness::Normal),
+            color(Hue::Blue, Lightness::Dark),
+        ]]);
+        let mut coverage = Coverage::load(&temporary("missing"), &picture).unwrap();
+        assert_eq!(
+            coverage.report(&picture),
+            "Runs: 0\nBlocks: 0/3 (0.0%)\nExits: 0/24 (0.0%)\n\
+             Never entered: (0, 0) Light Red\n\
+             Never entered: (1, 0) Normal Red\n\
+             Never entered: (4, 0) Dark Blue\n"
+        );
+
+        // Pushes going right, then comes back to pop going left
+        run(&picture, &mut coverage, 2);
+        let report = coverage.report(&picture);
+        assert!(report.starts_with("Runs: 1\nBlocks: 2/3 (66.7%)\nExits: 2/24 (8.3%)\n"));
+        assert!(report.ends_with("Never entered: (4, 0) Dark Blue\n"));
+    }
+
+    #[test]
+    fn refuses_other_pictures() {
+        let path = temporary("other");
+        let picture = program("Hi");
+        let mut coverage = Coverage::load(&path, &picture).unwrap();
+        run(&picture, &mut coverage, usize::MAX);
+        coverage.save(&path).unwrap();
+
+        let mut other = picture.clone();
+        let last = other[0].len() - 1;
+        other[0][last].color = color(Hue::Green, Lightness::Dark);
+        let error = Coverage::load(&path, &other).err().unwrap();
+        assert!(error.ends_with("is the coverage of another picture."), "{}", error);
+        let error = Coverage::load(&path, &program("Hello")).err().unwrap();
+        assert!(error.ends_with("is the coverage of a picture of another size."), "{}", error);
+
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::write(&path, saved.replace("\"version\":2", "\"version\":1")).unwrap();
+        let error = Coverage::load(&path, &picture).err().unwrap();
+        assert!(error.ends_with("isn't a version 2 coverage file."), "{}", error);
+        fs::remove_file(&path).unwrap_or(());
+    }
+}
@@ -0,0 +1,173 @@
+use codel::*;
+use interpreter::*;
+use std::*;
+
+/* Detection of programs stuck in a loop that doesn't read or write anything. Without I/O, a
+ * program whose state (codel, dp, cc and stack) repeats will repeat it forever.
+ *
+ * States are compared with Brent's algorithm: the state after each step is compared to a saved
+ * one, which is replaced after 1, 2, 4, 8... steps. A loop is found after at most about twice the
+ * number of steps it takes to enter it and go around it once, and most comparisons stop at the
+ * position of the interpreter, so that the detector is cheap enough to be left on. I/O starts
+ * the search over, as reading can change what the program does.
+ * */
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    x: usize,
+    y: usize,
+    dp: Direction,
+    cc: Direction,
+    stack: Vec<i64>,
+}
+
+impl State {
+    fn of(interpreter: &Interpreter) -> State {
+        State {
+            x: interpreter.codel.x,
+            y: interpreter.codel.y,
+            dp: interpreter.dp,
+            cc: interpreter.cc,
+            stack: interpreter.machine.stack.clone(),
+        }
+    }
+
+    fn is(&self, interpreter: &Interpreter) -> bool {
+        self.x == interpreter.codel.x
+            && self.y == interpreter.codel.y
+            && self.dp == interpreter.dp
+            && self.cc == interpreter.cc
+            && self.stack == interpreter.machine.stack
+    }
+}
+
+/* saved: The state the current state is compared to.
+ * io_count: The number of I/O operations the machine had performed at the last step.
+ * length: The number of steps since `saved`.
+ * power: The number of steps after which `saved` is replaced.
+ * */
+pub struct LoopDetector {
+    saved: Option<State>,
+    io_count: u64,
+    length: u64,
+    power: u64,
+}
+
+impl LoopDetector {
+    pub fn new() -> LoopDetector {
+        LoopDetector { saved: None, io_count: 0, length: 0, power: 1 }
+    }
+
+    /* Looks at the state of the interpreter after each step.
+     * returns: The number of steps of the loop the program is stuck in, None if it isn't known to
+     * be stuck yet.
+     * */
+    pub fn check(&mut self, interpreter: &Interpreter) -> Option<u64> {
+        let io = interpreter.machine.io_count != self.io_count;
+        self.io_count = interpreter.machine.io_count;
+        match self.saved {
+            Some(ref saved) if !io => {
+                self.length += 1;
+                if saved.is(interpreter) {
+                    return Some(self.length);
+                }
+                if self.length < self.power {
+                    return None;
+                }
+                self.power *= 2;
+            },
+            _ => self.power = 1,
+        }
+        self.saved = Some(State::of(interpreter));
+        self.length = 0;
+        None
+    }
+}
+
+// Number of blocks of a loop given in its description
+const DESCRIBED_BLOCKS: usize = 16;
+
+/* Goes around a loop found by LoopDetector once.
+ * returns: A description of the loop, with the codels through which it enters blocks.
+ * */
+pub fn describe_loop(interpreter: &mut Interpreter, length: u64) -> String {
+    let mut blocks = vec![];
+    for _ in 0..length {
+        match interpreter.step() {
+            Some(ref step) if blocks.len() < DESCRIBED_BLOCKS => {
+                blocks.push(format!("({}, {})", step.to.x, step.to.y));
+            },
+            Some(_) => {},
+            None => break,
+        }
+    }
+    if length as usize > blocks.len() {
+        blocks.push(String::from("..."));
+    }
+    format!("a loop of {} steps going through {}", length, blocks.join(" -> "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::*;
+    use picture::*;
+    use pietcolor::*;
+
+    fn picture(row: Vec<PietColor>) -> Vec<Vec<Codel>> {
+        let pixels = vec![row.iter().map(|color| color.to_rgb()).collect()];
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, false)
+    }
+
+    fn interpreter<'a>(picture: &'a [Vec<Codel>]) -> Interpreter<'a> {
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()));
+        Interpreter::new(picture, machine)
+    }
+
+    #[test]
+    fn finds_loops_without_io() {
+        // Pushes 1 going right and pops it going left, forever
+        let picture = picture(vec![
+            PietColor { hue: Hue::Red, lightness: Lightness::Light },
+            PietColor { hue: Hue::Red, lightness: Lightness::Normal },
+        ]);
+        let mut interpreter = interpreter(&picture);
+        let mut detector = LoopDetector::new();
+        let mut found = None;
+        for _ in 0..100 {
+            interpreter.step().unwrap();
+            found = detector.check(&interpreter);
+            if found.is_some() {
+                break;
+            }
+        }
+        let length = found.expect("the loop wasn't found");
+
+        // The state comes back after `length` steps, and not before
+        let state = State::of(&interpreter);
+        for step in 1..length + 1 {
+            interpreter.step().unwrap();
+            assert_eq!(state.is(&interpreter), step == length, "after {} steps", step);
+        }
+        let description = describe_loop(&mut interpreter, length);
+        assert!(description.starts_with(&format!("a loop of {} steps going through (", length)));
+    }
+
+    #[test]
+    fn ignores_loops_with_io() {
+        // Reads a character going right, which there isn't, and adds nothing going left
+        let picture = picture(vec![
+            PietColor { hue: Hue::Red, lightness: Lightness::Normal },
+            PietColor { hue: Hue::Magenta, lightness: Lightness::Normal },
+        ]);
+        let mut interpreter = interpreter(&picture);
+        let mut detector = LoopDetector::new();
+        for _ in 0..1000 {
+            interpreter.step().unwrap();
+            assert_eq!(detector.check(&interpreter), None);
+        }
+        assert!(interpreter.machine.io_count >= 500);
+        assert!(interpreter.machine.stack.is_empty());
+    }
+}
@@ -0,0 +1,576 @@
+use codel::*;
+use debugger::*;
+use history::*;
+use instruction::*;
+use machine::*;
+use picture::*;
+use pietcolor::*;
+use serde_json::Value;
+use std::*;
+
+use std::io::prelude::*;
+
+/* A Debug Adapter Protocol server, serving a single debugging session over stdin and stdout.
+ *
+ * The codel (x, y) is at line y and column x, counted from 1 unless the client asks otherwise
+ * in the initialize request. Breakpoints are set on codels with setBreakpoints, which needs a
+ * column for every breakpoint, and on instructions with setFunctionBreakpoints, using the names
+ * of the debugger (push, outc...). The Piet stack is shown as the Stack scope, bottom first, and
+ * dp, cc and the current block as the Registers scope.
+ *
+ * As stdin and stdout carry the protocol, the program reads its input from the `input` string
+ * of the launch request and its output is sent as output events. Requests are handled one at a
+ * time, so a program that never hits a breakpoint can't be paused.
+ * */
+
+/* The arguments of the launch request.
+ * picture: The codels of the program, read when the request is parsed.
+ * */
+struct Launch {
+    program: String,
+    picture: Vec<Vec<Codel>>,
+    input: String,
+    stop_on_entry: bool,
+    history: HistorySize,
+}
+
+impl Launch {
+    fn parse(arguments: &Value) -> Result<Launch, String> {
+        let program = match arguments["program"].as_str() {
+            Some(program) => String::from(program),
+            None => return Err(String::from("The launch request needs a program.")),
+        };
+        let pixels = read_pixels(&program).map_err(|e| format!("Can't read {}", e))?;
+        if pixels.is_empty() || pixels[0].is_empty() {
+            return Err(format!("{} is empty.", program));
+        }
+        let codel_size = cmp::max(arguments["codelSize"].as_u64().unwrap_or(1) as usize, 1);
+        let default_color = if arguments["black"].as_bool().unwrap_or(false) {
+            PietColor { hue: Hue::Black, lightness: Lightness::Normal }
+        } else {
+            PietColor { hue: Hue::White, lightness: Lightness::Normal }
+        };
+        let syscalls = arguments["syscalls"].as_bool().unwrap_or(false);
+        Ok(Launch {
+            program,
+            picture: to_codels(&pixels, codel_size, default_color, syscalls),
+            input: String::from(arguments["input"].as_str().unwrap_or("")),
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            history: HistorySize {
+                memory: (arguments["history"].as_u64().unwrap_or(64) as usize) << 20,
+                interval: arguments["checkpoints"].as_u64().unwrap_or(1000) as usize,
+            },
+        })
+    }
+}
+
+/* Reads and writes protocol messages.
+ * line_base, column_base: The line and column of the first codel of the picture.
+ * */
+struct Connection {
+    input: Box<dyn io::BufRead>,
+    output: Box<dyn io::Write>,
+    seq: u64,
+    line_base: usize,
+    column_base: usize,
+}
+
+impl Connection {
+    /* Reads the next message, skipping the ones that aren't valid JSON.
+     * returns: None when the client closed the connection.
+     * */
+    fn receive(&mut self) -> Option<Value> {
+        loop {
+            let mut length = None;
+            loop {
+                let mut line = String::new();
+                if self.input.read_line(&mut line).ok()? == 0 {
+                    return None;
+                }
+                let line = line.trim();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("Content-Length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+            if let Some(length) = length {
+                let mut body = vec![0; length];
+                self.input.read_exact(&mut body).ok()?;
+                if let Ok(message) = serde_json::from_slice(&body) {
+                    return Some(message);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        let written = write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| self.output.flush());
+        if let Err(e) = written {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            },
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            },
+        }
+        self.send(response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    /* Returns the line and column of a codel. */
+    fn position(&self, x: usize, y: usize) -> (usize, usize) {
+        (y + self.line_base, x + self.column_base)
+    }
+}
+
+/* Replaces the breakpoints set on codels.
+ * returns: The breakpoints for the response to setBreakpoints.
+ * */
+fn set_breakpoints(connection: &Connection, debugger: &mut Debugger, arguments: &Value) -> Value {
+    for breakpoint in debugger.breakpoints.iter_mut() {
+        if let Some(Breakpoint::Codel(..)) = *breakpoint {
+            *breakpoint = None;
+        }
+    }
+    let picture = debugger.interpreter.picture;
+    let mut result = vec![];
+    let requested = arguments["breakpoints"].as_array().cloned().unwrap_or(vec![]);
+    for breakpoint in requested.iter() {
+        let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+        let column = breakpoint["column"].as_u64().map(|column| column as usize);
+        let codel = match column {
+            Some(column) if line >= connection.line_base && column >= connection.column_base => {
+                Some((column - connection.column_base, line - connection.line_base))
+            },
+            _ => None,
+        };
+        match codel {
+            Some((x, y)) if y < picture.len() && x < picture[y].len() => {
+                result.push(json!({
+                    "id": debugger.breakpoints.len(),
+                    "verified": true,
+                    "line": line,
+                    "column": column,
+                }));
+                debugger.breakpoints.push(Some(Breakpoint::Codel(x, y)));
+            },
+            _ => {
+                result.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "Breakpoints need the line and column of a codel of the picture.",
+                }));
+            },
+        }
+    }
+    json!({ "breakpoints": result })
+}
+
+/* Replaces the breakpoints set on instructions.
+ * returns: The breakpoints for the response to setFunctionBreakpoints.
+ * */
+fn set_function_breakpoints(debugger: &mut Debugger, arguments: &Value) -> Value {
+    for breakpoint in debugger.breakpoints.iter_mut() {
+        if let Some(Breakpoint::Instruction(..)) = *breakpoint {
+            *breakpoint = None;
+        }
+    }
+    let mut result = vec![];
+    let requested = arguments["breakpoints"].as_array().cloned().unwrap_or(vec![]);
+    for breakpoint in requested.iter() {
+        let name = breakpoint["name"].as_str().unwrap_or("");
+        match Instruction::from_name(name) {
+            Some(instruction) => {
+                result.push(json!({ "id": debugger.breakpoints.len(), "verified": true }));
+                debugger.breakpoints.push(Some(Breakpoint::Instruction(instruction)));
+            },
+            None => {
+                let message = format!("Unknown instruction {}.", name);
+                result.push(json!({ "verified": false, "message": message }));
+            },
+        }
+    }
+    json!({ "breakpoints": result })
+}
+
+fn stack_trace(connection: &Connection, debugger: &Debugger, launch: &Launch) -> Value {
+    let codel = &debugger.interpreter.codel;
+    let (line, column) = connection.position(codel.x, codel.y);
+    let name = match debugger.interpreter.peek() {
+        _ if debugger.terminated => "terminated",
+        Some((_, instruction)) => instruction.name(),
+        None => "terminate",
+    };
+    json!({
+        "stackFrames": [{
+            "id": 1,
+            "name": name,
+            "source": { "name": launch.program, "path": launch.program },
+            "line": line,
+            "column": column,
+        }],
+        "totalFrames": 1,
+    })
+}
+
+fn variables(debugger: &Debugger, reference: u64) -> Value {
+    let variable = |name: String, value: String| {
+        json!({ "name": name, "value": value, "variablesReference": 0 })
+    };
+    let interpreter = &debugger.interpreter;
+    let variables: Vec<Value> = match reference {
+        1 => {
+            let stack = interpreter.machine.stack.iter().enumerate();
+            stack.map(|(i, value)| variable(i.to_string(), value.to_string())).collect()
+        },
+        2 => {
+            let codel = &interpreter.codel;
+            let color = format!("{:?} {:?}", codel.color.lightness, codel.color.hue);
+            vec![
+                variable(String::from("dp"), format!("{:?}", interpreter.dp)),
+                variable(String::from("cc"), format!("{:?}", interpreter.cc)),
+                variable(String::from("block"), format!("({}, {})", codel.x, codel.y)),
+                variable(String::from("block size"), debugger.block_size().to_string()),
+                variable(String::from("color"), color),
+                variable(String::from("steps"), interpreter.steps.to_string()),
+            ]
+        },
+        _ => vec![],
+    };
+    json!({ "variables": variables })
+}
+
+/* Sends the output of the program and tells the client why the program stopped.
+ * stop: Why the program stopped, the message of the crash if it crashed.
+ * reason: The reason given to the client, deduced from `stop` if None.
+ * */
+fn report(
+    connection: &mut Connection,
+    debugger: &Debugger,
+    output: &SharedBuffer,
+    stop: Result<Stop, String>,
+    reason: Option<&str>,
+) {
+    let text = output.take();
+    if !text.is_empty() {
+        connection.event("output", json!({ "category": "stdout", "output": text }));
+    }
+    let stop = match stop {
+        Ok(stop) => stop,
+        Err(message) => {
+            let message = format!("{}\n", message);
+            connection.event("output", json!({ "category": "stderr", "output": message }));
+            connection.event("exited", json!({ "exitCode": 1 }));
+            connection.event("terminated", json!({}));
+            return;
+        },
+    };
+    let deduced = match stop {
+        Stop::Terminated => {
+            connection.event("exited", json!({ "exitCode": 0 }));
+            connection.event("terminated", json!({}));
+            return;
+        },
+        Stop::Breakpoint(id) => match debugger.breakpoints[id] {
+            Some(Breakpoint::Instruction(..)) => "function breakpoint",
+            _ => "breakpoint",
+        },
+        Stop::Done | Stop::HistoryStart => "step",
+    };
+    let reason = reason.unwrap_or(deduced);
+    let mut body = json!({ "reason": reason, "threadId": 1, "allThreadsStopped": true });
+    if let Stop::Breakpoint(id) = stop {
+        body["hitBreakpointIds"] = json!([id]);
+    }
+    connection.event("stopped", body);
+}
+
+/* Handles the requests of a launched program until the client disconnects. */
+fn session(connection: &mut Connection, launch: &Launch) {
+    let output = SharedBuffer::new();
+    let input = io::Cursor::new(launch.input.clone().into_bytes());
+    let machine = Machine::with_io(false, Box::new(input), Box::new(output.clone()));
+    let mut debugger = Debugger::new(&launch.picture, machine, launch.history);
+    connection.event("initialized", json!({}));
+
+    while let Some(request) = connection.receive() {
+        let arguments = &request["arguments"];
+        let mut stop = None;
+        let mut reason = None;
+        let result = match request["command"].as_str().unwrap_or("") {
+            "setBreakpoints" => Ok(set_breakpoints(connection, &mut debugger, arguments)),
+            "setFunctionBreakpoints" => Ok(set_function_breakpoints(&mut debugger, arguments)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => {
+                if launch.stop_on_entry {
+                    stop = Some(Ok(Stop::Done));
+                    reason = Some("entry");
+                } else {
+                    stop = Some(debugger.guarded(|debugger| debugger.resume(None)));
+                }
+                Ok(json!({}))
+            },
+            "threads" => Ok(json!({ "threads": [{ "id": 1, "name": "main" }] })),
+            "stackTrace" => Ok(stack_trace(connection, &debugger, launch)),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Stack", "variablesReference": 1, "expensive": false },
+                    {
+                        "name": "Registers",
+                        "presentationHint": "registers",
+                        "variablesReference": 2,
+                        "expensive": false,
+                    },
+                ],
+            })),
+            "variables" => {
+                Ok(variables(&debugger, arguments["variablesReference"].as_u64().unwrap_or(0)))
+            },
+            "continue" => {
+                stop = Some(debugger.guarded(|debugger| debugger.resume(None)));
+                Ok(json!({ "allThreadsContinued": true }))
+            },
+            "next" | "stepIn" | "stepOut" => {
+                stop = Some(debugger.guarded(|debugger| debugger.resume(Some(1))));
+                Ok(json!({}))
+            },
+            "stepBack" => {
+                stop = Some(debugger.guarded(|debugger| debugger.reverse(Some(1))));
+                Ok(json!({}))
+            },
+            "reverseContinue" => {
+                stop = Some(debugger.guarded(|debugger| debugger.reverse(None)));
+                Ok(json!({}))
+            },
+            "pause" => {
+                stop = Some(Ok(Stop::Done));
+                reason = Some("pause");
+                Ok(json!({}))
+            },
+            "disconnect" | "terminate" => {
+                connection.respond(&request, Ok(json!({})));
+                return;
+            },
+            command => Err(format!("Unsupported request {}.", command)),
+        };
+        connection.respond(&request, result);
+        if let Some(stop) = stop {
+            report(connection, &debugger, &output, stop, reason);
+        }
+    }
+}
+
+/* Handles the requests of the client until it disconnects. */
+fn serve_client(connection: &mut Connection) {
+    while let Some(request) = connection.receive() {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                let one_based = |name: &str| arguments[name].as_bool().unwrap_or(true);
+                connection.line_base = if one_based("linesStartAt1") { 1 } else { 0 };
+                connection.column_base = if one_based("columnsStartAt1") { 1 } else { 0 };
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsStepBack": true,
+                    "supportsTerminateRequest": true,
+                });
+                connection.respond(&request, Ok(capabilities));
+            },
+            "launch" => match Launch::parse(arguments) {
+                Ok(launch) => {
+                    connection.respond(&request, Ok(json!({})));
+                    session(connection, &launch);
+                    return;
+                },
+                Err(message) => connection.respond(&request, Err(message)),
+            },
+            "disconnect" | "terminate" => {
+                connection.respond(&request, Ok(json!({})));
+                return;
+            },
+            command => {
+                let message = format!("Unsupported request {} before launch.", command);
+                connection.respond(&request, Err(message));
+            },
+        }
+    }
+}
+
+/* Runs the server until the client disconnects. */
+pub fn run_dap() {
+    serve_client(&mut Connection {
+        input: Box::new(io::BufReader::new(io::stdin())),
+        output: Box::new(io::stdout()),
+        seq: 0,
+        line_base: 1,
+        column_base: 1,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(hue: Hue, lightness: Lightness) -> PietColor {
+        PietColor { hue, lightness }
+    }
+
+    /* Writes a program of a single row of codels to a temporary file.
+     * returns: The path of the file.
+     * */
+    fn write_program(name: &str, row: Vec<PietColor>) -> String {
+        let path = env::temp_dir().join(format!("pieti-dap-{}-{}.png", name, process::id()));
+        write_picture(fs::File::create(&path).unwrap(), &[row], 1).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /* Sends `requests` to the adapter the way a client does through its stdin.
+     * returns: The responses and events the adapter wrote to its stdout.
+     * */
+    fn exchange(requests: Vec<Value>) -> Vec<Value> {
+        let mut input = vec![];
+        for (seq, mut request) in requests.into_iter().enumerate() {
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let body = request.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        let output = SharedBuffer::new();
+        serve_client(&mut Connection {
+            input: Box::new(io::Cursor::new(input)),
+            output: Box::new(output.clone()),
+            seq: 0,
+            line_base: 1,
+            column_base: 1,
+        });
+        let mut replies = Connection {
+            input: Box::new(io::Cursor::new(output.take().into_bytes())),
+            output: Box::new(io::sink()),
+            seq: 0,
+            line_base: 1,
+            column_base: 1,
+        };
+        let mut messages = vec![];
+        while let Some(message) = replies.receive() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        let is_response = |m: &&Value| m["type"] == "response" && m["command"] == command;
+        messages.iter().rev().find(is_response).unwrap()
+    }
+
+    fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+        messages.iter().filter(|m| m["type"] == "event" && m["event"] == event).collect()
+    }
+
+    #[test]
+    fn steps_to_a_breakpoint() {
+        // Pushes 2 leaving the first block and 1 leaving the second one
+        let program = write_program(
+            "breakpoint",
+            vec![
+                color(Hue::Red, Lightness::Light),
+                color(Hue::Red, Lightness::Light),
+                color(Hue::Red, Lightness::Normal),
+                color(Hue::Red, Lightness::Dark),
+            ],
+        );
+        let messages = exchange(vec![
+            json!({ "command": "initialize", "arguments": { "adapterID": "pieti" } }),
+            json!({
+                "command": "launch",
+                "arguments": { "program": program, "stopOnEntry": true },
+            }),
+            json!({
+                "command": "setBreakpoints",
+                "arguments": { "breakpoints": [{ "line": 1, "column": 3 }, { "line": 2 }] },
+            }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&program).unwrap_or(());
+
+        assert_eq!(response(&messages, "initialize")["body"]["supportsStepBack"], true);
+        assert_eq!(response(&messages, "launch")["success"], true);
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+        let reasons: Vec<&Value> =
+            events(&messages, "stopped").iter().map(|e| &e["body"]["reason"]).collect();
+        assert_eq!(reasons, vec!["entry", "breakpoint", "step"]);
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[0]["value"], "2");
+        assert_eq!(variables[1]["value"], "1");
+        assert_eq!(response(&messages, "disconnect")["success"], true);
+    }
+
+    #[test]
+    fn a_crash_terminates_the_program() {
+        // Reads a number leaving the first codel
+        let program = write_program(
+            "crash",
+            vec![color(Hue::Red, Lightness::Light), color(Hue::Blue, Lightness::Dark)],
+        );
+        let messages = exchange(vec![
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": program, "input": "x\n" } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&program).unwrap_or(());
+
+        let output = events(&messages, "output");
+        assert_eq!(output[0]["body"]["category"], "stderr");
+        assert!(output[0]["body"]["output"].as_str().unwrap().starts_with("The program crashed"));
+        assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 1);
+        assert!(!events(&messages, "terminated").is_empty());
+        assert_eq!(response(&messages, "disconnect")["success"], true);
+    }
+
+    #[test]
+    fn launching_something_else_than_a_picture_fails() {
+        let path = env::temp_dir().join(format!("pieti-dap-text-{}.png", process::id()));
+        fs::write(&path, "not a picture").unwrap();
+        let program = path.to_string_lossy().into_owned();
+        let messages = exchange(vec![
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": program } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap_or(());
+
+        assert_eq!(response(&messages, "launch")["success"], false);
+        assert_eq!(response(&messages, "disconnect")["success"], true);
+    }
+}
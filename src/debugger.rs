@@ -0,0 +1,646 @@
+use codel::*;
+use history::*;
+use instruction::*;
+use interpreter::*;
+use machine::*;
+use picture::*;
+use std::*;
+
+use std::io::prelude::*;
+
+/* A value of the program state that can be watched. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watched {
+    StackDepth,
+    Top,
+}
+
+/* A condition on the program state, such as `stack-depth > 100`.
+ * orderings: The results of comparing the watched value to `value` that make the condition
+ * true.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub watched: Watched,
+    pub orderings: Vec<cmp::Ordering>,
+    pub value: i64,
+    pub expression: String,
+}
+
+impl Watch {
+    /* Parses a condition made of a watched value, a comparison operator and a number. */
+    pub fn parse(words: &[&str]) -> Result<Watch, String> {
+        use std::cmp::Ordering::*;
+        if words.len() != 3 {
+            return Err(String::from("Error: expected a condition such as stack-depth > 100."));
+        }
+        let watched = match words[0] {
+            "stack-depth" => Watched::StackDepth,
+            "top" => Watched::Top,
+            w => return Err(format!("Error: can't watch {}, use stack-depth or top.", w)),
+        };
+        let orderings = match words[1] {
+            "<" => vec![Less],
+            "<=" => vec![Less, Equal],
+            ">" => vec![Greater],
+            ">=" => vec![Greater, Equal],
+            "==" => vec![Equal],
+            "!=" => vec![Less, Greater],
+            op => return Err(format!("Error: unknown comparison {}.", op)),
+        };
+        let value = match words[2].parse::<i64>() {
+            Ok(value) => value,
+            Err(_) => return Err(format!("Error: {} isn't a number.", words[2])),
+        };
+        Ok(Watch { watched, orderings, value, expression: words.join(" ") })
+    }
+
+    /* Returns whether the condition holds, which it never does for the top of an empty
+     * stack.
+     * */
+    pub fn holds(&self, machine: &Machine) -> bool {
+        self.holds_for(machine.stack.len(), machine.stack.last().cloned())
+    }
+
+    /* Returns whether the condition holds for a stack of `depth` values whose top is `top`. */
+    pub fn holds_for(&self, depth: usize, top: Option<i64>) -> bool {
+        let current = match self.watched {
+            Watched::StackDepth => Some(depth as i64),
+            Watched::Top => top,
+        };
+        match current {
+            Some(current) => self.orderings.contains(&current.cmp(&self.value)),
+            None => false,
+        }
+    }
+}
+
+/* Something that stops the execution of the program.
+ * Codel: Stops before leaving the block containing the codel at (x, y).
+ * Instruction: Stops before executing the instruction.
+ * Watch: Stops after a step that made the condition true.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    Codel(usize, usize),
+    Instruction(Instruction),
+    Watch(Watch),
+}
+
+/* Why Debugger::resume returned.
+ * Done: The requested number of steps was performed.
+ * Breakpoint: The breakpoint with the given id was hit.
+ * Terminated: The program terminated.
+ * HistoryStart: There are no more steps to undo.
+ * */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    Done,
+    Breakpoint(usize),
+    Terminated,
+    HistoryStart,
+}
+
+/* An interpreter that can be paused at breakpoints and run backwards.
+ * breakpoints: Breakpoints indexed by id, None for the ones that were deleted.
+ * */
+pub struct Debugger<'a> {
+    pub interpreter: Interpreter<'a>,
+    pub breakpoints: Vec<Option<Breakpoint>>,
+    pub terminated: bool,
+    pub history: History,
+    blocks: Blocks,
+}
+
+impl<'a> Debugger<'a> {
+    /* history: How many steps can be undone. */
+    pub fn new(
+        picture: &'a [Vec<Codel>],
+        machine: Machine,
+        history: HistorySize,
+    ) -> Debugger<'a> {
+        Debugger {
+            interpreter: Interpreter::new(picture, machine),
+            breakpoints: vec![],
+            terminated: false,
+            history: History::new(history),
+            blocks: get_blocks(picture),
+        }
+    }
+
+    /* Returns the id of the first breakpoint that stops the program before the next step. */
+    fn breakpoint_before(&self) -> Option<usize> {
+        let current = self.blocks.ids[self.interpreter.codel.y][self.interpreter.codel.x];
+        let next = self.interpreter.peek().map(|(_, instruction)| instruction);
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match *breakpoint {
+                Some(Breakpoint::Codel(x, y)) => self.blocks.ids[y][x] == current,
+                Some(Breakpoint::Instruction(instruction)) => next == Some(instruction),
+                _ => false,
+            })
+    }
+
+    fn watches_holding(&self) -> Vec<bool> {
+        self.breakpoints
+            .iter()
+            .map(|breakpoint| match *breakpoint {
+                Some(Breakpoint::Watch(ref watch)) => watch.holds(&self.interpreter.machine),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /* Performs `count` steps, or steps until the program terminates if `count` is None,
+     * stopping early at breakpoints. Breakpoints are ignored before the first step, so that
+     * resuming from a breakpoint doesn't stop right away.
+     * returns: Why the execution stopped and the last step that was performed.
+     * */
+    pub fn resume(&mut self, count: Option<u64>) -> (Stop, Option<Step>) {
+        let mut last = None;
+        let mut performed = 0;
+        loop {
+            if self.terminated {
+                return (Stop::Terminated, last);
+            }
+            if let Some(count) = count {
+                if performed >= count {
+                    return (Stop::Done, last);
+                }
+            }
+            if performed > 0 {
+                if let Some(id) = self.breakpoint_before() {
+                    return (Stop::Breakpoint(id), last);
+                }
+            }
+            let before = self.watches_holding();
+            match self.history.step(&mut self.interpreter) {
+                Some(step) => last = Some(step),
+                None => self.terminated = true,
+            }
+            performed += 1;
+            let after = self.watches_holding();
+            if let Some(id) = (0..after.len()).find(|&id| after[id] && !before[id]) {
+                return (Stop::Breakpoint(id), last);
+            }
+        }
+    }
+
+    /* Undoes `count` steps, or steps until a breakpoint is hit if `count` is None. Going
+     * backwards, execution stops where it would have stopped going forward: before leaving a
+     * block with a breakpoint, or before the step that made a watched condition true.
+     * returns: Why the execution stopped and the last step that was undone.
+     * */
+    pub fn reverse(&mut self, count: Option<u64>) -> (Stop, Option<Step>) {
+        let mut last = None;
+        let mut undone = 0;
+        if self.terminated && count != Some(0) {
+            // Terminating isn't a step, going back from it doesn't undo anything
+            self.terminated = false;
+            undone += 1;
+        }
+        loop {
+            if let Some(count) = count {
+                if undone >= count {
+                    return (Stop::Done, last);
+                }
+            }
+            let before = self.watches_holding();
+            match self.history.step_back(&mut self.interpreter) {
+                Some(step) => last = Some(step),
+                None => return (Stop::HistoryStart, last),
+            }
+            undone += 1;
+            let after = self.watches_holding();
+            if let Some(id) = (0..after.len()).find(|&id| before[id] && !after[id]) {
+                return (Stop::Breakpoint(id), last);
+            }
+            if let Some(id) = self.breakpoint_before() {
+                return (Stop::Breakpoint(id), last);
+            }
+        }
+    }
+
+    /* Moves the program with `f`, a crash of the program terminating it rather than the
+     * debugging server.
+     * returns: Why the program stopped, the message of the crash if it crashed.
+     * */
+    pub fn guarded<F>(&mut self, f: F) -> Result<Stop, String>
+    where
+        F: FnOnce(&mut Debugger<'a>) -> (Stop, Option<Step>),
+    {
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| f(self).0)) {
+            Ok(stop) => Ok(stop),
+            Err(e) => {
+                self.terminated = true;
+                self.history.clear();
+                Err(format!("The program crashed: {}", crash_message(&e)))
+            },
+        }
+    }
+
+    /* Goes back to before the step that wrote the value at index `slot` of the stack, ignoring
+     * breakpoints on the way.
+     * returns: The step that wrote the value.
+     * */
+    pub fn last_write(&mut self, slot: usize) -> Result<Step, String> {
+        let len = self.interpreter.machine.stack.len();
+        if slot >= len {
+            return Err(format!("Error: the stack only holds {} values.", len));
+        }
+        self.terminated = false;
+        match self.history.last_write(slot, len) {
+            Some(back) => {
+                let mut last = None;
+                for _ in 0..back {
+                    last = self.history.step_back(&mut self.interpreter);
+                }
+                last.ok_or_else(|| String::from("Error: the history is inconsistent."))
+            },
+            None => {
+                let steps = self.history.len();
+                Err(format!("Error: slot {} wasn't written in the last {} steps.", slot, steps))
+            },
+        }
+    }
+
+    /* Returns the number of codels of the block the interpreter is on. */
+    pub fn block_size(&self) -> usize {
+        self.blocks.block_of(&self.interpreter.codel).codels.len()
+    }
+
+    /* Describes the block the interpreter is on and what it will do next. */
+    pub fn location(&self) -> String {
+        let codel = &self.interpreter.codel;
+        let size = self.block_size();
+        let next = match self.interpreter.peek() {
+            _ if self.terminated => String::from("program terminated"),
+            Some((_, instruction)) => format!("next: {}", instruction.name()),
+            None => String::from("next: terminate"),
+        };
+        format!(
+            "({}, {}) {:?} {:?}, block of {} codels, dp: {:?}, cc: {:?}, {}",
+            codel.x,
+            codel.y,
+            codel.color.lightness,
+            codel.color.hue,
+            size,
+            self.interpreter.dp,
+            self.interpreter.cc,
+            next
+        )
+    }
+}
+
+/* Parses a direction name, left or right only if `only_sides` is true, as these are the only
+ * values cc can take.
+ * */
+fn parse_direction(name: &str, only_sides: bool) -> Result<Direction, String> {
+    match name {
+        "left" => Ok(Direction::Left),
+        "right" => Ok(Direction::Right),
+        "up" if !only_sides => Ok(Direction::Up),
+        "down" if !only_sides => Ok(Direction::Down),
+        _ => Err(format!("Error: invalid direction {}.", name)),
+    }
+}
+
+/* Parses codel coordinates written as `x,y`, possibly with spaces around the comma. */
+fn parse_coordinates(words: &[&str], picture: &[Vec<Codel>]) -> Result<(usize, usize), String> {
+    let joined = words.join("");
+    let coordinates: Vec<Result<usize, _>> = joined.split(',').map(|c| c.parse()).collect();
+    match coordinates.as_slice() {
+        [Ok(x), Ok(y)] if *y < picture.len() && *x < picture[*y].len() => Ok((*x, *y)),
+        [Ok(x), Ok(y)] => Err(format!("Error: ({}, {}) is outside of the picture.", x, y)),
+        _ => Err(format!("Error: expected coordinates such as 3,4, got {}.", joined)),
+    }
+}
+
+/* Parses the optional number of steps of step and step-back. */
+fn parse_count(word: Option<&&str>) -> Result<u64, String> {
+    match word {
+        Some(count) => count
+            .parse()
+            .map_err(|_| format!("Error: invalid count {}.", count)),
+        None => Ok(1),
+    }
+}
+
+fn describe(breakpoint: &Breakpoint) -> String {
+    match *breakpoint {
+        Breakpoint::Codel(x, y) => format!("block of ({}, {})", x, y),
+        Breakpoint::Instruction(instruction) => format!("instruction {}", instruction.name()),
+        Breakpoint::Watch(ref watch) => format!("watch {}", watch.expression),
+    }
+}
+
+const HELP: &str = "Commands:
+  step [n], s [n]       Perform n steps, 1 by default.
+  continue, c           Run until a breakpoint is hit or the program terminates.
+  step-back [n], sb [n] Undo n steps, 1 by default.
+  reverse-continue, rc  Undo steps until a breakpoint is hit.
+  last-write N          Go back to before the step that wrote the value at index N of the
+                        stack, 0 being the bottom.
+  break x,y             Stop before leaving the block containing the codel (x, y).
+  break instr NAME      Stop before executing the instruction NAME (push, outc, ...).
+  watch VALUE OP N      Stop when the condition becomes true. VALUE is stack-depth or top,
+                        OP one of < <= > >= == !=.
+  break                 List breakpoints and watches.
+  delete N              Delete breakpoint or watch N.
+  print stack|dp|cc|steps|history, p ...
+                        Print part of the state. The stack is printed bottom first, history
+                        is the number of steps that can be undone.
+  set dp DIRECTION      Set dp to left, right, up or down.
+  set cc DIRECTION      Set cc to left or right.
+  set stack N...        Replace the stack, bottom first.
+                        Setting anything forgets the steps that could be undone.
+  where                 Show the current block.
+  help                  Show this help.
+  quit, q               Exit the debugger.
+An empty line repeats the previous command.";
+
+/* Executes a debugger command.
+ * returns: Whether the debugger should keep reading commands.
+ * */
+fn execute_command(debugger: &mut Debugger, words: &[&str]) -> Result<bool, String> {
+    let picture = debugger.interpreter.picture;
+    match words {
+        ["step"] | ["s"] | ["step", _] | ["s", _] => {
+            let count = parse_count(words.get(1))?;
+            let result = debugger.resume(Some(count));
+            report(debugger, result, false);
+        },
+        ["continue"] | ["c"] => {
+            let result = debugger.resume(None);
+            report(debugger, result, false);
+        },
+        ["step-back"] | ["sb"] | ["step-back", _] | ["sb", _] => {
+            let count = parse_count(words.get(1))?;
+            let result = debugger.reverse(Some(count));
+            report(debugger, result, true);
+        },
+        ["reverse-continue"] | ["rc"] => {
+            let result = debugger.reverse(None);
+            report(debugger, result, true);
+        },
+        ["last-write", slot] => {
+            let slot = slot
+                .parse()
+                .map_err(|_| format!("Error: invalid slot {}.", slot))?;
+            let step = debugger.last_write(slot)?;
+            println!(
+                "Slot {} was written by {} ({}) leaving ({}, {}).",
+                slot,
+                step.instruction.name(),
+                step.block_size,
+                step.from.x,
+                step.from.y
+            );
+            println!("{}", debugger.location());
+        },
+        ["break"] | ["b"] => {
+            for (id, breakpoint) in debugger.breakpoints.iter().enumerate() {
+                if let Some(ref breakpoint) = *breakpoint {
+                    println!("{}: {}", id, describe(breakpoint));
+                }
+            }
+        },
+        ["break", "instr", name] | ["b", "instr", name] => match Instruction::from_name(name) {
+            Some(instruction) => add_breakpoint(debugger, Breakpoint::Instruction(instruction)),
+            None => return Err(format!("Error: unknown instruction {}.", name)),
+        },
+        ["break", ..] | ["b", ..] => {
+            let (x, y) = parse_coordinates(&words[1..], picture)?;
+            add_breakpoint(debugger, Breakpoint::Codel(x, y));
+        },
+        ["watch", ..] => add_breakpoint(debugger, Breakpoint::Watch(Watch::parse(&words[1..])?)),
+        ["delete", id] => match id.parse::<usize>() {
+            Ok(id) if id < debugger.breakpoints.len() => debugger.breakpoints[id] = None,
+            _ => return Err(format!("Error: no breakpoint {}.", id)),
+        },
+        ["print", what] | ["p", what] => match *what {
+            "stack" => println!("{:?}", debugger.interpreter.machine.stack),
+            "dp" => println!("{:?}", debugger.interpreter.dp),
+            "cc" => println!("{:?}", debugger.interpreter.cc),
+            "steps" => println!("{}", debugger.interpreter.steps),
+            "history" => println!("{}", debugger.history.len()),
+            _ => return Err(format!("Error: can't print {}.", what)),
+        },
+        ["set", "dp", direction] => {
+            debugger.interpreter.dp = parse_direction(direction, false)?;
+            debugger.history.clear();
+        },
+        ["set", "cc", direction] => {
+            debugger.interpreter.cc = parse_direction(direction, true)?;
+            debugger.history.clear();
+        },
+        ["set", "stack", ..] => {
+            let mut stack = vec![];
+            for value in words[2..].iter() {
+                stack.push(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Error: {} isn't a number.", value))?,
+                );
+            }
+            debugger.interpreter.machine.stack = stack;
+            debugger.history.clear();
+        },
+        ["where"] => println!("{}", debugger.location()),
+        ["help"] => println!("{}", HELP),
+        ["quit"] | ["q"] => return Ok(false),
+        _ => return Err(format!("Error: unknown command {}, try help.", words.join(" "))),
+    }
+    Ok(true)
+}
+
+fn add_breakpoint(debugger: &mut Debugger, breakpoint: Breakpoint) {
+    println!("{}: {}", debugger.breakpoints.len(), describe(&breakpoint));
+    debugger.breakpoints.push(Some(breakpoint));
+}
+
+/* Prints why the execution stopped and where.
+ * last: The last step that was performed, or undone if `backwards` is true.
+ * */
+fn report(debugger: &Debugger, (stop, last): (Stop, Option<Step>), backwards: bool) {
+    // Output of the program doesn't necessarily end with a new line
+    io::stdout().flush().unwrap_or(());
+    if let Some(step) = last {
+        println!(
+            "{} {} ({}) leaving ({}, {}) for ({}, {}).",
+            if backwards { "Undid" } else { "Executed" },
+            step.instruction.name(),
+            step.block_size,
+            step.from.x,
+            step.from.y,
+            step.to.x,
+            step.to.y
+        );
+    }
+    match stop {
+        Stop::Done => {},
+        Stop::Breakpoint(id) => {
+            if let Some(ref breakpoint) = debugger.breakpoints[id] {
+                println!("Stopped at breakpoint {}: {}.", id, describe(breakpoint));
+            }
+        },
+        Stop::Terminated => {
+            println!("Program terminated after {} steps.", debugger.interpreter.steps);
+        },
+        Stop::HistoryStart => println!("No more steps to undo."),
+    }
+    println!("{}", debugger.location());
+}
+
+/* Runs the debugger, reading commands from stdin until it is closed or the user quits. The
+ * program reads its input from stdin as well.
+ * history: How many steps can be undone.
+ * */
+pub fn run_debugger(picture: &[Vec<Codel>], machine: Machine, history: HistorySize) {
+    let mut debugger = Debugger::new(picture, machine, history);
+    let mut previous = String::new();
+    println!("{}", debugger.location());
+    loop {
+        print!("(pieti) ");
+        io::stdout().flush().unwrap_or(());
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+        if line.trim().is_empty() {
+            line = previous.clone();
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        match execute_command(&mut debugger, &words) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(e) => println!("{}", e),
+        }
+        previous = line.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use optimizer::*;
+    use pietcolor::*;
+
+    fn picture(ops: &[Op]) -> Vec<Vec<Codel>> {
+        let colors = layout(ops);
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, false)
+    }
+
+    fn machine() -> Machine {
+        Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()))
+    }
+
+    fn op(instruction: Instruction, value: i64) -> Op {
+        Op { instruction, value }
+    }
+
+    #[test]
+    fn parses_watches() {
+        use std::cmp::Ordering::*;
+        let watch = Watch::parse(&["stack-depth", ">=", "3"]).unwrap();
+        assert_eq!(watch.watched, Watched::StackDepth);
+        assert_eq!(watch.orderings, vec![Greater, Equal]);
+        assert_eq!(watch.expression, "stack-depth >= 3");
+        assert!(watch.holds_for(3, None) && watch.holds_for(4, Some(0)));
+        assert!(!watch.holds_for(2, Some(5)));
+
+        let watch = Watch::parse(&["top", "!=", "-1"]).unwrap();
+        assert!(watch.holds_for(1, Some(0)) && !watch.holds_for(1, Some(-1)));
+        // The top of an empty stack never matches
+        assert!(!watch.holds_for(0, None));
+
+        assert!(Watch::parse(&["top", "<"]).is_err());
+        assert!(Watch::parse(&["bottom", "<", "1"]).is_err());
+        assert!(Watch::parse(&["top", "=", "1"]).is_err());
+        assert!(Watch::parse(&["top", "<", "one"]).is_err());
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let picture = picture(&[op(Instruction::Push, 5)]);
+        assert_eq!(parse_coordinates(&["2,0"], &picture), Ok((2, 0)));
+        assert_eq!(parse_coordinates(&["2", ",", "0"], &picture), Ok((2, 0)));
+        assert!(parse_coordinates(&["200,0"], &picture).is_err());
+        assert!(parse_coordinates(&["2"], &picture).is_err());
+        assert!(parse_coordinates(&["a,b"], &picture).is_err());
+        assert_eq!(parse_count(None), Ok(1));
+        assert_eq!(parse_count(Some(&"12")), Ok(12));
+        assert!(parse_count(Some(&"-1")).is_err());
+        assert_eq!(parse_direction("up", false), Ok(Direction::Up));
+        assert!(parse_direction("up", true).is_err());
+        assert!(parse_direction("north", false).is_err());
+    }
+
+    #[test]
+    fn executes_commands() {
+        use instruction::Instruction::*;
+        let ops = vec![op(Push, 3), op(Duplicate, 0), op(Mul, 0), op(Push, 1), op(Add, 0)];
+        let picture = picture(&ops);
+        let size = HistorySize { memory: 1 << 20, interval: 100 };
+        let mut debugger = Debugger::new(&picture, machine(), size);
+        let execute = |debugger: &mut Debugger, command: &str| {
+            let words: Vec<&str> = command.split_whitespace().collect();
+            execute_command(debugger, &words)
+        };
+
+        assert_eq!(execute(&mut debugger, "break instr mul"), Ok(true));
+        assert_eq!(execute(&mut debugger, "watch top == 10"), Ok(true));
+        assert_eq!(execute(&mut debugger, "continue"), Ok(true));
+        assert_eq!(debugger.interpreter.machine.stack, vec![3, 3]);
+        assert_eq!(execute(&mut debugger, "c"), Ok(true));
+        assert_eq!(debugger.interpreter.machine.stack, vec![10]);
+        // Going back stops before the step that made the watch true
+        assert_eq!(execute(&mut debugger, "step-back 2"), Ok(true));
+        assert_eq!(debugger.interpreter.machine.stack, vec![9, 1]);
+        assert_eq!(execute(&mut debugger, "sb"), Ok(true));
+        assert_eq!(debugger.interpreter.machine.stack, vec![9]);
+        assert_eq!(execute(&mut debugger, "delete 1"), Ok(true));
+        assert_eq!(debugger.breakpoints[1], None);
+        assert_eq!(execute(&mut debugger, "s 100"), Ok(true));
+        assert!(debugger.terminated);
+
+        assert_eq!(execute(&mut debugger, "set stack 1 -2 3"), Ok(true));
+        assert_eq!(debugger.interpreter.machine.stack, vec![1, -2, 3]);
+        assert_eq!(debugger.history.len(), 0);
+        assert_eq!(execute(&mut debugger, "set dp down"), Ok(true));
+        assert_eq!(debugger.interpreter.dp, Direction::Down);
+        assert!(execute(&mut debugger, "set cc up").is_err());
+        assert!(execute(&mut debugger, "set stack 1 x").is_err());
+        assert!(execute(&mut debugger, "delete 5").is_err());
+        assert!(execute(&mut debugger, "break instr jump").is_err());
+        assert!(execute(&mut debugger, "last-write 3").is_err());
+        assert!(execute(&mut debugger, "fly").is_err());
+        assert_eq!(execute(&mut debugger, "q"), Ok(false));
+    }
+
+    #[test]
+    fn last_write_ignores_watches() {
+        use instruction::Instruction::*;
+        let ops = vec![op(Push, 3), op(Duplicate, 0), op(Mul, 0), op(Push, 1)];
+        let picture = picture(&ops);
+        let size = HistorySize { memory: 1 << 20, interval: 100 };
+        let mut debugger = Debugger::new(&picture, machine(), size);
+        debugger.resume(None);
+        assert_eq!(debugger.interpreter.machine.stack, vec![9, 1]);
+        let watch = Watch::parse(&["stack-depth", ">", "1"]).unwrap();
+        debugger.breakpoints.push(Some(Breakpoint::Watch(watch)));
+
+        let step = debugger.last_write(0).unwrap();
+        assert_eq!(step.instruction, Mul);
+        assert_eq!(debugger.interpreter.machine.stack, vec![3, 3]);
+        assert!(!debugger.terminated);
+    }
+}
@@ -1,70 +1,1003 @@
 use codel::*;
+use debugger::*;
+use instruction::*;
+use interpreter::*;
+use machine::*;
+use observer::*;
+use picture::*;
 use pietcolor::*;
 use std::*;
 extern crate termion;
 
-fn print_pic(picture: &Vec<Vec<Codel>>, offset_x: usize, offset_y: usize) {
-    for row in picture.iter() {
-        for codel in row.iter() {
-            print!(
-                "{}{}",
-                termion::cursor::Goto(
-                    (codel.x * 2 + offset_x + 1) as u16,
-                    (codel.y + offset_y + 1) as u16,
-                ),
-                codel.color
-            );
+// Number of values of the top of the stack shown in the panel
+const STACK_SHOWN: usize = 8;
+
+/* A move of the interpreter from the codel `exit` to the codel `entry`, in the direction `dp`. */
+#[derive(Clone)]
+struct Transition {
+    exit: (usize, usize),
+    entry: (usize, usize),
+    dp: Direction,
+    instruction: Instruction,
+}
+
+/* What the interpreter sends the display before each step.
+ * running: false once the program has terminated.
+ * next: The move the next step makes.
+ * stack: The top of the stack, bottom first, and depth the number of values on the stack.
+ * output: What the program wrote since the previous state.
+ * */
+struct State {
+    running: bool,
+    codel: Codel,
+    dp: Direction,
+    cc: Direction,
+    next: Option<Transition>,
+    stack: Vec<i64>,
+    depth: usize,
+    output: String,
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Right => "→",
+        Direction::Down => "↓",
+        Direction::Left => "←",
+        Direction::Up => "↑",
+    }
+}
+
+// Bounds of the time a step is displayed for
+const FASTEST: time::Duration = time::Duration::from_millis(16);
+const SLOWEST: time::Duration = time::Duration::from_secs(8);
+
+/* Reads the keys pressed and the mouse clicks in the terminal. The terminal is used rather than
+ * stdin, which belongs to the program. The channel is closed if there is no terminal.
+ * */
+fn read_events() -> sync::mpsc::Receiver<termion::event::Event> {
+    use termion::input::TermRead;
+    let (sender, receiver) = sync::mpsc::channel();
+    thread::spawn(move || if let Ok(tty) = termion::get_tty() {
+        for event in tty.events() {
+            match event {
+                Ok(event) => {
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                },
+                Err(_) => return,
+            }
+        }
+    });
+    receiver
+}
+
+// Width and height of the panel on the right of the picture, and number of rows kept below the
+// picture for the keys and at least a few lines of output
+const PANEL_WIDTH: usize = 28;
+const MAX_WATCHES: usize = 3;
+const PANEL_ROWS: usize = STACK_SHOWN + 8 + MAX_WATCHES;
+const BOTTOM_ROWS: usize = 7;
+// How often the size of the terminal is checked while waiting for keys
+const RESIZE_POLL: time::Duration = time::Duration::from_millis(200);
+// How often the display is drawn while the program runs to a breakpoint
+const FAST_FRAME: time::Duration = time::Duration::from_millis(40);
+const MAX_ZOOM: u32 = 6;
+
+// The part of the picture on screen: the origin, zoom and size of the viewport
+type View = ((usize, usize), u32, (u16, u16));
+
+/* Returns the number of cells needed to show `length` codels, `per_cell` codels per cell. */
+fn cell_count(length: usize, per_cell: usize) -> usize {
+    length / per_cell + cmp::min(length % per_cell, 1)
+}
+
+/* Returns black or white, whichever is readable over `color`. */
+fn ink(color: PietColor) -> PietColor {
+    let [red, green, blue] = color.to_rgb();
+    let luminance = 0.299 * red as f64 + 0.587 * green as f64 + 0.114 * blue as f64;
+    let hue = if luminance < 128.0 { Hue::White } else { Hue::Black };
+    PietColor { hue, lightness: Lightness::Normal }
+}
+
+/* The state of the display between steps.
+ * blocks: The color blocks of the picture, as the block of the current codel is highlighted.
+ * delay: How long each step is displayed for when the display isn't paused.
+ * output: Everything the program wrote.
+ * origin: The codel shown in the top-left corner of the viewport.
+ * zoom: 0 to draw each codel with two characters, n > 0 to draw half blocks, each character
+ * showing one codel out of 2^(n-1) horizontally on top of another, sampling codels when the
+ * picture is zoomed out further.
+ * follow: Whether the viewport moves to keep the current codel visible, which panning stops.
+ * size: The size of the terminal the screen was laid out for.
+ * drawn: The viewport on screen (origin, zoom and size) and the block highlighted on it, so
+ * that only the characters whose highlight changes are drawn again.
+ * mode: How the colors of the picture are drawn.
+ * frame: What is drawn until it is written to the terminal, all at once.
+ * cursor: The codel breakpoints are toggled on from the keyboard, once it has been moved.
+ * breakpoints: The blocks that pause the display when the interpreter enters them.
+ * watches: The watched conditions, and whether they held in the previous state, as they pause
+ * the display when they become true.
+ * block: The block of the previous state, to tell when the interpreter enters a block.
+ * stop: Why the display paused by itself, shown until it is resumed.
+ * trail: The last moves of the interpreter, oldest first, drawn over the picture until there
+ * are more than trail_length of them. next is the move the interpreter announced, which joins
+ * the trail with the next state.
+ * trail_drawn: The codels the trail was drawn on, which have to be drawn again when it changes.
+ * drawn_at: When the display was last drawn, as it is only drawn every FAST_FRAME when running
+ * to a breakpoint, output_stale telling whether output came in meanwhile.
+ * */
+struct Viewer {
+    picture: Vec<Vec<Codel>>,
+    mode: ColorMode,
+    blocks: Blocks,
+    events: sync::mpsc::Receiver<termion::event::Event>,
+    keys_closed: bool,
+    paused: bool,
+    delay: time::Duration,
+    output: String,
+    origin: (usize, usize),
+    zoom: u32,
+    follow: bool,
+    size: (u16, u16),
+    drawn: Option<(View, Option<usize>)>,
+    frame: String,
+    cursor: Option<(usize, usize)>,
+    breakpoints: collections::BTreeSet<usize>,
+    watches: Vec<(Watch, bool)>,
+    block: Option<usize>,
+    stop: Option<String>,
+    trail: collections::VecDeque<Transition>,
+    trail_length: usize,
+    next: Option<Transition>,
+    trail_drawn: Vec<(usize, usize)>,
+    drawn_at: time::Instant,
+    output_stale: bool,
+}
+
+impl Viewer {
+    /* Waits at most `timeout`, forever if it is None, for a key to be pressed or the mouse to be
+     * used.
+     * returns: The event, None if nothing happened in time or if there is no terminal to read
+     * events from.
+     * */
+    fn next_event(&mut self, timeout: Option<time::Duration>) -> Option<termion::event::Event> {
+        use std::sync::mpsc::RecvTimeoutError;
+        let event = match timeout {
+            None => self.events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(timeout) => self.events.recv_timeout(timeout),
+        };
+        match event {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.keys_closed = true;
+                thread::sleep(timeout.unwrap_or(RESIZE_POLL));
+                None
+            },
+        }
+    }
+
+    /* Writes the frame to the terminal. */
+    fn present<W: io::Write>(&mut self, screen: &mut W) {
+        screen.write_all(self.frame.as_bytes()).unwrap();
+        screen.flush().unwrap();
+        self.frame.clear();
+    }
+
+    /* Returns the number of codels each character shows horizontally and vertically, and the
+     * width of a character in columns.
+     * */
+    fn scale(&self) -> (usize, usize, usize) {
+        match self.zoom {
+            0 => (1, 1, 2),
+            zoom => {
+                let codels = 1 << (zoom - 1);
+                (codels, codels * 2, 1)
+            },
+        }
+    }
+
+    /* Returns the number of codels of the viewport horizontally and vertically. */
+    fn viewport(&self) -> (usize, usize) {
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let (codels_x, codels_y, cell_width) = self.scale();
+        let columns = cmp::max(width.saturating_sub(PANEL_WIDTH + 2) / cell_width, 1);
+        let rows = cmp::max(height.saturating_sub(BOTTOM_ROWS), 1);
+        let (picture_width, picture_height) = (self.picture[0].len(), self.picture.len());
+        (
+            cmp::min(columns * codels_x, picture_width),
+            cmp::min(rows * codels_y, picture_height),
+        )
+    }
+
+    /* Returns the number of characters the viewport takes horizontally and vertically. */
+    fn viewport_cells(&self) -> (usize, usize) {
+        let (codels_x, codels_y, cell_width) = self.scale();
+        let (width, height) = self.viewport();
+        (cell_count(width, codels_x) * cell_width, cell_count(height, codels_y))
+    }
+
+    /* Keeps the viewport within the picture, and around `codel` when following it. */
+    fn move_viewport(&mut self, codel: &Codel) {
+        let (width, height) = self.viewport();
+        let (x, y) = self.origin;
+        if self.follow && (codel.x < x || codel.x >= x + width) {
+            self.origin.0 = codel.x.saturating_sub(width / 2);
+        }
+        if self.follow && (codel.y < y || codel.y >= y + height) {
+            self.origin.1 = codel.y.saturating_sub(height / 2);
+        }
+        self.origin.0 = cmp::min(self.origin.0, self.picture[0].len() - width);
+        self.origin.1 = cmp::min(self.origin.1, self.picture.len() - height);
+    }
+
+    /* Returns the block highlighted when the interpreter is on `codel`, None in white regions,
+     * which the interpreter doesn't treat as blocks.
+     * */
+    fn block_of(&self, codel: &Codel) -> Option<usize> {
+        if codel.color.hue == Hue::White {
+            None
+        } else {
+            Some(self.blocks.ids[codel.y][codel.x])
+        }
+    }
+
+    /* Returns the color of the codels from (x, y) to (x + width, y + height) excluded: white if
+     * one of them belongs to the highlighted block, the color of the first one otherwise, None
+     * if it is outside the picture.
+     * */
+    fn color(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        highlighted: Option<usize>,
+    ) -> Option<PietColor> {
+        let color = self.picture.get(y)?.get(x)?.color;
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        if let Some(id) = highlighted {
+            let right = cmp::min(x + width, self.picture[0].len());
+            let bottom = cmp::min(y + height, self.picture.len());
+            if (y..bottom).any(|y| (x..right).any(|x| self.blocks.ids[y][x] == id)) {
+                return Some(white);
+            }
+        }
+        Some(color)
+    }
+
+    /* Returns the marker drawn over the codels from (x, y) to (x + width, y + height) excluded,
+     * if the cursor is on one of them or one of them belongs to a block with a breakpoint.
+     * */
+    fn marker(&self, x: usize, y: usize, width: usize, height: usize) -> Option<&'static str> {
+        let (cursor, breakpoint) = if self.zoom == 0 { ("[]", "**") } else { ("+", "*") };
+        let right = cmp::min(x + width, self.picture[0].len());
+        let bottom = cmp::min(y + height, self.picture.len());
+        if let Some((cursor_x, cursor_y)) = self.cursor {
+            if cursor_x >= x && cursor_x < right && cursor_y >= y && cursor_y < bottom {
+                return Some(cursor);
+            }
+        }
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let ids = &self.blocks.ids;
+        if (y..bottom).any(|y| (x..right).any(|x| self.breakpoints.contains(&ids[y][x]))) {
+            return Some(breakpoint);
+        }
+        None
+    }
+
+    /* Returns what the trail draws over the codels from (x, y) to (x + width, y + height)
+     * excluded, along with its age, 0 for the newest move: the abbreviation of the instruction of
+     * the newest move entering one of them, or else the arrow of the newest move leaving one of
+     * them. Abbreviations don't fit once the picture is zoomed out.
+     * */
+    fn trail_label(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Option<(String, usize)> {
+        let inside = |(cx, cy): (usize, usize)| {
+            cx >= x && cx < x + width && cy >= y && cy < y + height
+        };
+        let newest = || self.trail.iter().rev().enumerate();
+        if self.zoom == 0 {
+            let entering =
+                newest().find(|&(_, t)| inside(t.entry) && t.instruction != Instruction::Nop);
+            if let Some((age, transition)) = entering {
+                return Some((String::from(transition.instruction.abbreviation()), age));
+            }
+        }
+        let (age, transition) = newest().find(|&(_, t)| inside(t.exit))?;
+        let arrow = arrow(transition.dp);
+        Some((if self.zoom == 0 { format!("{} ", arrow) } else { String::from(arrow) }, age))
+    }
+
+    /* Returns the style of what the trail draws: bold for the newest third of the moves, faint
+     * for the oldest third.
+     * */
+    fn trail_style(&self, age: usize) -> String {
+        let length = self.trail.len();
+        if age * 3 < length {
+            termion::style::Bold.to_string()
+        } else if age * 3 < length * 2 {
+            String::new()
+        } else {
+            termion::style::Faint.to_string()
+        }
+    }
+
+    /* Draws the character of the viewport at (column, row), which are counted in characters of
+     * the width given by `scale`.
+     * */
+    fn draw_cell(&mut self, column: usize, row: usize, highlighted: Option<usize>) {
+        let (codels_x, codels_y, cell_width) = self.scale();
+        let (x, y) = (self.origin.0 + column * codels_x, self.origin.1 + row * codels_y);
+        let goto = termion::cursor::Goto((column * cell_width + 1) as u16, (row + 1) as u16);
+        let reset = termion::style::Reset;
+        let trail = self.trail_label(x, y, codels_x, codels_y);
+        let marker = match (self.marker(x, y, codels_x, codels_y), trail) {
+            (Some(marker), _) => Some(String::from(marker)),
+            (None, Some((label, age))) => Some(format!("{}{}", self.trail_style(age), label)),
+            (None, None) => None,
+        };
+        let cell = if self.zoom == 0 {
+            match (self.color(x, y, 1, 1, highlighted), marker) {
+                (Some(color), Some(marker)) => {
+                    let (bg, fg) = (color.bg(self.mode), ink(color).fg(self.mode));
+                    format!("{}{}{}{}{}", goto, bg, fg, marker, reset)
+                },
+                (Some(color), None) => format!("{}{}{}", goto, color.cell(self.mode), reset),
+                (None, _) => return,
+            }
+        } else {
+            let half = codels_y / 2;
+            let top = self.color(x, y, codels_x, half, highlighted);
+            match (top, self.color(x, y + half, codels_x, half, highlighted)) {
+                (Some(top), _) if marker.is_some() => {
+                    let (bg, fg) = (top.bg(self.mode), ink(top).fg(self.mode));
+                    format!("{}{}{}{}{}", goto, bg, fg, marker.unwrap(), reset)
+                },
+                (Some(top), Some(bottom)) => {
+                    let (top, bottom) = (top.fg(self.mode), bottom.bg(self.mode));
+                    format!("{}{}{}▀{}", goto, top, bottom, reset)
+                },
+                (Some(top), None) => format!("{}{}▀{}", goto, top.fg(self.mode), reset),
+                _ => return,
+            }
+        };
+        self.frame.push_str(&cell);
+    }
+
+    /* Draws the viewport with `highlighted` in white. Only the characters showing the block that
+     * was highlighted and the one that is are drawn if the viewport didn't change.
+     * */
+    fn draw_picture(&mut self, highlighted: Option<usize>) {
+        let (codels_x, codels_y, _) = self.scale();
+        let (width, height) = self.viewport();
+        let view = (self.origin, self.zoom, self.size);
+        let mut cells = collections::BTreeSet::new();
+        match self.drawn {
+            Some((drawn_view, drawn_block)) if drawn_view == view => {
+                let (x, y) = self.origin;
+                let mut codels = self.trail_drawn.clone();
+                if drawn_block != highlighted {
+                    for &id in drawn_block.iter().chain(highlighted.iter()) {
+                        codels.extend_from_slice(&self.blocks.blocks[id].codels);
+                    }
+                }
+                for transition in self.trail.iter() {
+                    codels.push(transition.exit);
+                    codels.push(transition.entry);
+                }
+                for (codel_x, codel_y) in codels {
+                    if codel_x >= x && codel_x < x + width && codel_y >= y && codel_y < y + height {
+                        cells.insert(((codel_y - y) / codels_y, (codel_x - x) / codels_x));
+                    }
+                }
+            },
+            _ => {
+                for row in 0..cell_count(height, codels_y) {
+                    for column in 0..cell_count(width, codels_x) {
+                        cells.insert((row, column));
+                    }
+                }
+            },
+        }
+        for (row, column) in cells {
+            self.draw_cell(column, row, highlighted);
+        }
+        self.drawn = Some((view, highlighted));
+        self.trail_drawn = self.trail.iter().flat_map(|t| vec![t.exit, t.entry]).collect();
+    }
+
+    /* Draws the panel describing the state on the right of the picture. */
+    fn draw_panel(&mut self, state: &State, status: &str) {
+        let mut panel = vec![
+            format!("{}, {} ms per step", status, self.delay.as_millis()),
+            format!("dp: {} {}", arrow(state.dp), state.dp.name()),
+            format!("cc: {} {}", arrow(state.cc.relative_to(state.dp)), state.cc.name()),
+            match state.next {
+                Some(ref next) => format!("next: {}", next.instruction.name()),
+                None => String::from("next: none"),
+            },
+            format!("stack: {} values", state.depth),
+        ];
+        panel.extend(state.stack.iter().rev().map(|value| format!("  {}", value)));
+        if state.depth > state.stack.len() {
+            panel.push(String::from("  ..."));
+        }
+        let (x, y) = (state.codel.x, state.codel.y);
+        let following = if self.follow { "" } else { ", f: follow" };
+        panel.push(format!("({}, {}), zoom {}{}", x, y, self.zoom, following));
+        panel.push(format!("breakpoints: {}", self.breakpoints.len()));
+        for &(ref watch, holds) in self.watches.iter() {
+            panel.push(format!("watch {}: {}", watch.expression, holds));
+        }
+        let column = self.viewport_cells().0 as u16 + 3;
+        for row in 0..PANEL_ROWS {
+            let line = panel.get(row).map_or("", |line| line.as_str());
+            self.frame.push_str(&format!(
+                "{}{}{}",
+                termion::cursor::Goto(column, row as u16 + 1),
+                termion::clear::UntilNewline,
+                line
+            ));
+        }
+    }
+
+    /* Returns the row of the keys, the first one below the picture and the panel. */
+    fn bottom(&self) -> u16 {
+        cmp::max(self.viewport_cells().1, PANEL_ROWS) as u16 + 2
+    }
+
+    /* Draws the keys and the end of the output below the picture and the panel. */
+    fn draw_output(&mut self) {
+        let (width, height) = self.size;
+        let top = self.bottom() + 1;
+        self.frame.push_str(&format!(
+            "{}{}space: pause/resume, n: step, +/-: speed, arrows: pan, i/o: zoom in/out, \
+             r: restart, q: quit{}{}click, or hjkl and b: toggle a breakpoint, w/W: add/remove \
+             a watch{}{}output:",
+            termion::cursor::Goto(1, top - 1),
+            termion::clear::CurrentLine,
+            termion::cursor::Goto(1, top),
+            termion::clear::CurrentLine,
+            termion::cursor::Goto(1, top + 1),
+            termion::clear::CurrentLine
+        ));
+        let rows = height.saturating_sub(top + 1) as usize;
+        // Lines before the last `rows` ones can't be visible, even if they are short
+        let mut lines = vec![];
+        for line in self.output.rsplit('\n').take(rows) {
+            let chars: Vec<char> =
+                line.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+            let chunks = chars.chunks(cmp::max(width as usize, 1));
+            let mut wrapped: Vec<String> = chunks.map(|chunk| chunk.iter().collect()).collect();
+            if wrapped.is_empty() {
+                wrapped.push(String::new());
+            }
+            lines.extend(wrapped.into_iter().rev());
+        }
+        lines.truncate(rows);
+        lines.reverse();
+        for row in 0..rows {
+            let line = lines.get(row).map_or("", |line| line.as_str());
+            self.frame.push_str(&format!(
+                "{}{}{}",
+                termion::cursor::Goto(1, top + 2 + row as u16),
+                termion::clear::CurrentLine,
+                line
+            ));
+        }
+    }
+
+    /* Draws the state, clearing the screen first if the terminal was resized.
+     * output: Whether the output has to be drawn, because it changed.
+     * */
+    fn draw(&mut self, state: &State, highlighted: bool, status: &str, output: bool) {
+        let size = termion::terminal_size().unwrap_or((80, 24));
+        let resized = size != self.size;
+        if resized {
+            self.size = size;
+            self.frame.push_str(&format!("{}", termion::clear::All));
+        }
+        self.move_viewport(&state.codel);
+        let block = if highlighted { self.block_of(&state.codel) } else { None };
+        self.draw_picture(block);
+        self.draw_panel(state, status);
+        if output || resized || self.output_stale {
+            self.draw_output();
+            self.output_stale = false;
+        }
+        self.drawn_at = time::Instant::now();
+    }
+
+    /* Pans or zooms the viewport if `key` is one of the keys that do.
+     * returns: Whether the key did.
+     * */
+    fn view_key(&mut self, key: termion::event::Key) -> bool {
+        use termion::event::Key;
+        let (width, height) = self.viewport();
+        let (x, y) = self.origin;
+        match key {
+            Key::Left => self.origin.0 = x.saturating_sub(cmp::max(width / 4, 1)),
+            Key::Right => self.origin.0 = x + cmp::max(width / 4, 1),
+            Key::Up => self.origin.1 = y.saturating_sub(cmp::max(height / 4, 1)),
+            Key::Down => self.origin.1 = y + cmp::max(height / 4, 1),
+            Key::Char('f') => {
+                self.follow = true;
+                return true;
+            },
+            Key::Char('i') | Key::Char('o') => {
+                self.zoom = match key {
+                    Key::Char('i') => self.zoom.saturating_sub(1),
+                    _ => cmp::min(self.zoom + 1, MAX_ZOOM),
+                };
+                // The layout changes, so the screen is cleared by draw
+                self.size = (0, 0);
+                return true;
+            },
+            _ => return false,
+        }
+        self.follow = false;
+        true
+    }
+
+    /* Returns the codel at the position (column, row) of the terminal, counted from 1 like the
+     * positions of mouse events, None outside of the viewport.
+     * */
+    fn codel_at(&self, column: u16, row: u16) -> Option<(usize, usize)> {
+        let (codels_x, codels_y, cell_width) = self.scale();
+        let (width, height) = self.viewport();
+        let column = (column as usize).checked_sub(1)? / cell_width;
+        let row = (row as usize).checked_sub(1)?;
+        let (x, y) = (self.origin.0 + column * codels_x, self.origin.1 + row * codels_y);
+        if x < self.origin.0 + width && y < self.origin.1 + height {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    /* Moves the cursor by (dx, dy) characters, starting from the current codel, and pans the
+     * viewport to keep it visible.
+     * */
+    fn move_cursor(&mut self, dx: isize, dy: isize, state: &State) {
+        let (codels_x, codels_y, _) = self.scale();
+        let (x, y) = self.cursor.unwrap_or((state.codel.x, state.codel.y));
+        let (width, height) = (self.picture[0].len() as isize, self.picture.len() as isize);
+        let x = cmp::min(cmp::max(x as isize + dx * codels_x as isize, 0), width - 1) as usize;
+        let y = cmp::min(cmp::max(y as isize + dy * codels_y as isize, 0), height - 1) as usize;
+        self.cursor = Some((x, y));
+        let (width, height) = self.viewport();
+        if x < self.origin.0 || x >= self.origin.0 + width {
+            self.origin.0 = x.saturating_sub(width / 2);
+            self.follow = false;
+        }
+        if y < self.origin.1 || y >= self.origin.1 + height {
+            self.origin.1 = y.saturating_sub(height / 2);
+            self.follow = false;
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, (x, y): (usize, usize)) {
+        let id = self.blocks.ids[y][x];
+        if !self.breakpoints.remove(&id) {
+            self.breakpoints.insert(id);
+        }
+    }
+
+    /* Asks for a condition to watch on the row of the keys, and adds it once it is valid.
+     * Escape gives up.
+     * */
+    fn prompt_watch<W: io::Write>(&mut self, state: &State, screen: &mut W) {
+        use termion::event::{Event, Key};
+        let mut text = String::new();
+        let mut error = if self.watches.len() < MAX_WATCHES {
+            String::new()
+        } else {
+            format!("  Error: at most {} watches, W removes the last one.", MAX_WATCHES)
+        };
+        loop {
+            self.frame.push_str(&format!(
+                "{}{}watch (stack-depth or top, comparison, number): {}{}",
+                termion::cursor::Goto(1, self.bottom()),
+                termion::clear::CurrentLine,
+                text,
+                error
+            ));
+            self.present(screen);
+            match self.next_event(None) {
+                Some(Event::Key(Key::Char('\n'))) => {
+                    let words: Vec<&str> = text.split_whitespace().collect();
+                    match Watch::parse(&words) {
+                        Ok(_) if self.watches.len() >= MAX_WATCHES => {},
+                        Ok(watch) => {
+                            let holds = watch.holds_for(state.depth, state.stack.last().cloned());
+                            self.watches.push((watch, holds));
+                            break;
+                        },
+                        Err(e) => error = format!("  {}", e),
+                    }
+                },
+                Some(Event::Key(Key::Char(c))) => text.push(c),
+                Some(Event::Key(Key::Backspace)) => {
+                    text.pop();
+                },
+                Some(Event::Key(Key::Esc)) | Some(Event::Key(Key::Ctrl('c'))) => break,
+                None if self.keys_closed => break,
+                _ => {},
+            }
+        }
+        self.output_stale = true;
+    }
+
+    /* Toggles breakpoints and edits watches if `event` is one of the keys or clicks that do.
+     * returns: Whether the event did.
+     * */
+    fn edit_event<W: io::Write>(
+        &mut self,
+        event: &termion::event::Event,
+        state: &State,
+        screen: &mut W,
+    ) -> bool {
+        use termion::event::{Event, Key, MouseButton, MouseEvent};
+        match *event {
+            Event::Mouse(MouseEvent::Press(MouseButton::Left, column, row)) => {
+                match self.codel_at(column, row) {
+                    Some(codel) => {
+                        self.cursor = Some(codel);
+                        self.toggle_breakpoint(codel);
+                    },
+                    None => return false,
+                }
+            },
+            Event::Key(Key::Char('h')) => self.move_cursor(-1, 0, state),
+            Event::Key(Key::Char('j')) => self.move_cursor(0, 1, state),
+            Event::Key(Key::Char('k')) => self.move_cursor(0, -1, state),
+            Event::Key(Key::Char('l')) => self.move_cursor(1, 0, state),
+            Event::Key(Key::Char('b')) => {
+                let codel = self.cursor.unwrap_or((state.codel.x, state.codel.y));
+                self.cursor = Some(codel);
+                self.toggle_breakpoint(codel);
+            },
+            Event::Key(Key::Char('w')) => self.prompt_watch(state, screen),
+            Event::Key(Key::Char('W')) => {
+                self.watches.pop();
+            },
+            _ => return false,
+        }
+        // Markers can change anywhere on the viewport, so all of it is drawn again
+        self.drawn = None;
+        true
+    }
+
+    /* Forgets what the display knows about the run of the program, as it starts again. */
+    fn restart(&mut self) -> Control {
+        self.block = None;
+        self.trail.clear();
+        self.next = None;
+        Control::Restart
+    }
+
+    /* Pauses the display if the interpreter entered a block with a breakpoint or made a watched
+     * condition true.
+     * */
+    fn check_breakpoints(&mut self, state: &State) {
+        let id = self.blocks.ids[state.codel.y][state.codel.x];
+        let entered = self.block != Some(id);
+        self.block = Some(id);
+        let mut stop = None;
+        let top = state.stack.last().cloned();
+        for &mut (ref watch, ref mut held) in self.watches.iter_mut() {
+            let holds = watch.holds_for(state.depth, top);
+            if holds && !*held {
+                stop = Some(format!("paused, watch {}", watch.expression));
+            }
+            *held = holds;
+        }
+        if entered && self.breakpoints.contains(&id) {
+            stop = Some(String::from("paused at a breakpoint"));
+        }
+        if stop.is_some() {
+            self.paused = true;
+            self.stop = stop;
+        }
+    }
+
+    /* Returns whether the program runs at full speed, which it does when it isn't paused and
+     * there is something to stop it.
+     * */
+    fn full_speed(&self) -> bool {
+        !self.paused && (!self.breakpoints.is_empty() || !self.watches.is_empty())
+    }
+
+    fn status(&self) -> String {
+        if self.paused {
+            self.stop.clone().unwrap_or(String::from("paused"))
+        } else if self.full_speed() {
+            String::from("running to a breakpoint")
+        } else {
+            String::from("running")
+        }
+    }
+
+    /* Shows the interpreter in `state` until the user lets it perform the next step, or until
+     * `delay` has passed if the display isn't paused. The block blinks while the program runs.
+     * When it runs to a breakpoint, it isn't waited for, and only drawn every FAST_FRAME.
+     * output: Whether the state comes with new output.
+     * */
+    fn wait_for_step<W: io::Write>(
+        &mut self,
+        state: &State,
+        output: bool,
+        screen: &mut W,
+    ) -> Control {
+        use termion::event::{Event, Key};
+        self.check_breakpoints(state);
+        let mut pending = None;
+        if self.full_speed() {
+            self.output_stale |= output;
+            if self.drawn_at.elapsed() >= FAST_FRAME {
+                self.draw(state, true, "running to a breakpoint", false);
+                self.present(screen);
+            }
+            match self.next_event(Some(time::Duration::new(0, 0))) {
+                Some(event) => pending = Some(event),
+                None => return Control::Step,
+            }
+        }
+        let mut highlighted = true;
+        let mut blink = time::Instant::now() + self.delay / 2;
+        let status = self.status();
+        self.draw(state, true, &status, output);
+        loop {
+            self.present(screen);
+            let event = if let Some(event) = pending.take() {
+                event
+            } else if self.paused {
+                match self.next_event(Some(RESIZE_POLL)) {
+                    Some(event) => event,
+                    // Without a terminal, there is no way to resume
+                    None if self.keys_closed => {
+                        self.paused = false;
+                        continue;
+                    },
+                    None => {
+                        if termion::terminal_size().ok() != Some(self.size) {
+                            let status = self.status();
+                            self.draw(state, true, &status, false);
+                        }
+                        continue;
+                    },
+                }
+            } else {
+                let now = time::Instant::now();
+                let left = if blink > now { blink - now } else { time::Duration::new(0, 0) };
+                match self.next_event(Some(left)) {
+                    Some(event) => event,
+                    None if highlighted => {
+                        highlighted = false;
+                        blink += self.delay / 2;
+                        self.draw(state, false, "running", false);
+                        continue;
+                    },
+                    None => return Control::Step,
+                }
+            };
+            match event {
+                Event::Key(Key::Char(' ')) => {
+                    self.paused = !self.paused;
+                    self.stop = None;
+                    highlighted = true;
+                    blink = time::Instant::now() + self.delay / 2;
+                },
+                Event::Key(Key::Char('n')) => {
+                    self.paused = true;
+                    self.stop = None;
+                    return Control::Step;
+                },
+                Event::Key(Key::Char('+')) => self.delay = cmp::max(self.delay / 2, FASTEST),
+                Event::Key(Key::Char('-')) => self.delay = cmp::min(self.delay * 2, SLOWEST),
+                Event::Key(Key::Char('r')) => {
+                    return self.restart();
+                },
+                Event::Key(Key::Char('q')) | Event::Key(Key::Ctrl('c')) => return Control::Quit,
+                Event::Key(key) if self.view_key(key) => {},
+                event => {
+                    if !self.edit_event(&event, state, screen) && !self.full_speed() {
+                        continue;
+                    }
+                },
+            }
+            if self.full_speed() {
+                return Control::Step;
+            }
+            let status = self.status();
+            self.draw(state, highlighted || self.paused, &status, false);
+        }
+    }
+
+    /* Shows the program once it has terminated, until a key other than the ones moving the
+     * viewport and editing breakpoints is pressed, r restarting the program.
+     * */
+    fn wait_for_restart<W: io::Write>(
+        &mut self,
+        state: &State,
+        output: bool,
+        screen: &mut W,
+    ) -> Control {
+        use termion::event::{Event, Key};
+        self.draw(state, true, "terminated", output);
+        loop {
+            self.present(screen);
+            match self.next_event(Some(RESIZE_POLL)) {
+                Some(Event::Key(Key::Char('r'))) => {
+                    return self.restart();
+                },
+                Some(Event::Key(key)) if self.view_key(key) => {},
+                Some(event) => {
+                    if !self.edit_event(&event, state, screen) {
+                        match event {
+                            Event::Key(_) => return Control::Quit,
+                            _ => continue,
+                        }
+                    }
+                },
+                None if self.keys_closed => return Control::Quit,
+                None => {
+                    if termion::terminal_size().ok() == Some(self.size) {
+                        continue;
+                    }
+                },
+            }
+            self.draw(state, true, "terminated", false);
         }
-        println!("{}\r", termion::style::Reset);
     }
 }
 
+/* Displays the states the interpreter sends and answers each of them with what the interpreter
+ * should do next. The output of the program is shown below the picture, and printed once the
+ * display is closed.
+ * */
 fn display_pic(
     picture: Vec<Vec<Codel>>,
-    receiving_end: sync::mpsc::Receiver<(bool, Codel, Direction, Direction)>,
+    mode: ColorMode,
+    trail_length: usize,
+    states: sync::mpsc::Receiver<State>,
+    controls: sync::mpsc::Sender<Control>,
 ) {
     use termion::raw::IntoRawMode;
     use std::io::Write;
-    use std::io::Read;
-    let _stdout = termion::input::MouseTerminal::from(io::stdout().into_raw_mode().unwrap());
+    let stdout = termion::input::MouseTerminal::from(io::stdout().into_raw_mode().unwrap());
     let mut screen = termion::screen::AlternateScreen::from(io::stdout());
     print!("{}", termion::cursor::Hide);
 
-    let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
-    while let Ok((true, codel, _, _)) = receiving_end.recv() {
-        print_pic(&picture, 0, 0);
-
-        let (x, y) = (codel.x as u16 * 2 + 1, codel.y as u16 + 2);
-        print!("{}{}", termion::cursor::Goto(x, y), white);
-        screen.flush().unwrap();
-
-        thread::sleep(time::Duration::from_millis(250));
-        print!("{}{}{}", termion::cursor::Goto(x, y), codel.color, termion::style::Reset);
-        screen.flush().unwrap();
-
-        thread::sleep(time::Duration::from_millis(250));
+    let mut viewer = Viewer {
+        blocks: get_blocks(&picture),
+        picture,
+        mode,
+        events: read_events(),
+        keys_closed: false,
+        paused: false,
+        delay: time::Duration::from_millis(500),
+        output: String::new(),
+        origin: (0, 0),
+        zoom: 0,
+        follow: true,
+        size: (0, 0),
+        drawn: None,
+        frame: String::new(),
+        cursor: None,
+        breakpoints: collections::BTreeSet::new(),
+        watches: vec![],
+        block: None,
+        stop: None,
+        trail: collections::VecDeque::new(),
+        trail_length,
+        next: None,
+        trail_drawn: vec![],
+        drawn_at: time::Instant::now(),
+        output_stale: false,
+    };
+    while let Ok(state) = states.recv() {
+        if let Some(transition) = viewer.next.take() {
+            viewer.trail.push_back(transition);
+            if viewer.trail.len() > viewer.trail_length {
+                viewer.trail.pop_front();
+            }
+        }
+        viewer.next = state.next.clone();
+        let output = !state.output.is_empty();
+        viewer.output.push_str(&state.output);
+        let control = if state.running {
+            viewer.wait_for_step(&state, output, &mut screen)
+        } else {
+            viewer.wait_for_restart(&state, output, &mut screen)
+        };
+        if controls.send(control).is_err() || control == Control::Quit {
+            break;
+        }
     }
 
     print!("{}", termion::cursor::Show);
-    io::stdin().bytes().next();
     screen.flush().unwrap();
+    drop(screen);
+    drop(stdout);
+    print!("{}", viewer.output);
+    io::stdout().flush().unwrap();
 }
 
-pub fn setup_display(
-    picture: Vec<Vec<Codel>>,
-) -> Option<(thread::JoinHandle<()>, sync::mpsc::Sender<(bool, Codel, Direction, Direction)>)> {
-    let (width, height) = termion::terminal_size().unwrap();
-    if (height as usize) <= picture.len() || (width as usize) < picture[0].len() {
-        println!("Picture is larger than terminal size. View anyway [y/n]?");
-        let mut input = String::new();
-        if let Err(error) = io::stdin().read_line(&mut input) {
-            panic!("{}", error);
-        }
-        if input.chars().next().unwrap() != 'y' {
-            return None;
+/* The terminal display of a program run by --view, which runs in its own thread. The
+ * interpreter sends it each state and waits for its answer, so that it doesn't run ahead of what
+ * is displayed.
+ * output: Where the program writes, as the display covers the terminal.
+ * */
+pub struct Display {
+    handle: thread::JoinHandle<()>,
+    states: sync::mpsc::SyncSender<State>,
+    controls: sync::mpsc::Receiver<Control>,
+    output: SharedBuffer,
+}
+
+impl Observer for Display {
+    /* The display covers the terminal, so it shows the output of the program itself. */
+    fn output(&self) -> Option<Box<dyn io::Write>> {
+        Some(Box::new(self.output.clone()))
+    }
+
+    /* Shows the state of the interpreter before its next step.
+     * returns: What the interpreter should do next, Quit if the display is gone. Step is only
+     * returned while the program is running.
+     * */
+    fn show(&mut self, interpreter: &Interpreter) -> Control {
+        let stack = &interpreter.machine.stack;
+        let state = State {
+            running: interpreter.peek().is_some(),
+            codel: interpreter.codel,
+            dp: interpreter.dp,
+            cc: interpreter.cc,
+            next: interpreter.peek().map(|(step, instruction)| {
+                Transition {
+                    exit: (step.exit.x, step.exit.y),
+                    entry: (step.to.x, step.to.y),
+                    dp: step.dp,
+                    instruction,
+                }
+            }),
+            stack: stack[stack.len() - cmp::min(stack.len(), STACK_SHOWN)..].to_vec(),
+            depth: stack.len(),
+            output: self.output.take(),
+        };
+        if self.states.send(state).is_err() {
+            return Control::Quit;
+        }
+        self.controls.recv().unwrap_or(Control::Quit)
+    }
+
+    /* Waits for the display to be closed. */
+    fn close(self: Box<Self>) {
+        drop(self.states);
+        if let Err(e) = self.handle.join() {
+            println!("{:?}", e);
         }
     }
-    let (se, re) = sync::mpsc::channel();
-    let handle = thread::spawn(move || { display_pic(picture, re); });
-    return Some((handle, se));
+}
+
+/* Starts displaying a program.
+ * mode: How colors are drawn.
+ * trail_length: The number of moves of the interpreter drawn over the picture.
+ * */
+pub fn setup_display(picture: Vec<Vec<Codel>>, mode: ColorMode, trail_length: usize) -> Display {
+    let (states, received_states) = sync::mpsc::sync_channel(0);
+    let (sent_controls, controls) = sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        display_pic(picture, mode, trail_length, received_states, sent_controls);
+    });
+    Display { handle, states, controls, output: SharedBuffer::new() }
 }
@@ -0,0 +1,526 @@
+use codel::*;
+use debugger::*;
+use history::*;
+use machine::*;
+use std::*;
+
+use std::io::prelude::*;
+
+/* A stub for the gdb remote serial protocol, serving a single debugging session over TCP or over
+ * stdin and stdout.
+ *
+ * Piet programs have no addresses, so the program counter encodes the position of the
+ * interpreter as 0xXXXXXXYYYYYYDDCC: the x and y coordinates of the codel on 24 bits each, then
+ * dp and cc on 8 bits each. dp counts clockwise from right (0 right, 1 down, 2 left, 3 up) and cc
+ * is 0 for left and 1 for right. Breakpoints are set on codels by giving the address of the codel
+ * with dp and cc left to 0, and stop the program whenever it is on the block of that codel.
+ *
+ * The registers are pc, depth (the number of values on the stack), dp, cc and steps, all of them
+ * 64 bits wide, which the target description sent to gdb tells. The Piet stack is mapped to
+ * memory from address 0, 8 bytes per value in little endian, the bottom of the stack first.
+ *
+ * Over TCP the program reads stdin and writes to stdout as usual. Over stdin and stdout, which
+ * carry the protocol, the program reads no input and its output is sent to gdb, which prints it.
+ * A crash of the program, such as in(number) reading no number, is sent to gdb as a message and
+ * reported as the program being killed by SIGABRT.
+ * Packets are handled one at a time, so a program that never hits a breakpoint can't be
+ * interrupted.
+ * */
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.piet.core">
+    <reg name="pc" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="depth" bitsize="64" type="uint64"/>
+    <reg name="dp" bitsize="64" type="uint64"/>
+    <reg name="cc" bitsize="64" type="uint64"/>
+    <reg name="steps" bitsize="64" type="uint64"/>
+  </feature>
+</target>
+"#;
+
+const REGISTERS: u64 = 5;
+
+fn direction_number(direction: Direction) -> u64 {
+    match direction {
+        Direction::Right => 0,
+        Direction::Down => 1,
+        Direction::Left => 2,
+        Direction::Up => 3,
+    }
+}
+
+/* Returns the address of a codel, 0 being the top-left codel. */
+fn address_of(x: usize, y: usize) -> u64 {
+    ((x as u64) << 40) | ((y as u64) << 16)
+}
+
+/* Returns the coordinates of the codel at an address, ignoring dp and cc. */
+fn codel_at(address: u64) -> (usize, usize) {
+    ((address >> 40) as usize, ((address >> 16) & 0xff_ffff) as usize)
+}
+
+fn register(debugger: &Debugger, number: u64) -> Option<u64> {
+    let interpreter = &debugger.interpreter;
+    match number {
+        0 => {
+            let codel = address_of(interpreter.codel.x, interpreter.codel.y);
+            let cc = if interpreter.cc == Direction::Right { 1 } else { 0 };
+            Some(codel | (direction_number(interpreter.dp) << 8) | cc)
+        },
+        1 => Some(interpreter.machine.stack.len() as u64),
+        2 => Some(direction_number(interpreter.dp)),
+        3 => Some(if interpreter.cc == Direction::Right { 1 } else { 0 }),
+        4 => Some(interpreter.steps),
+        _ => None,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let digit = |byte: u8| char::from(byte).to_digit(16);
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => Some((digit(high)? * 16 + digit(low)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/* Parses the numbers of a packet, written in hexadecimal and separated by `separators`. */
+fn numbers(text: &str, separators: &[char]) -> Option<Vec<u64>> {
+    text.split(separators).map(|number| u64::from_str_radix(number, 16).ok()).collect()
+}
+
+/* Reads and writes packets, acknowledging the received ones. */
+struct Connection {
+    input: Box<dyn io::BufRead>,
+    output: Box<dyn io::Write>,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Err(e) = self.output.write_all(bytes).and_then(|_| self.output.flush()) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    /* Reads the next packet, asking gdb to send it again if its checksum is wrong. Interrupts
+     * and acknowledgments are skipped.
+     * returns: None when gdb closed the connection.
+     * */
+    fn receive(&mut self) -> Option<String> {
+        loop {
+            while self.read_byte()? != b'$' {}
+            let mut packet = vec![];
+            let mut sum: u8 = 0;
+            loop {
+                let byte = self.read_byte()?;
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                packet.push(byte);
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = str::from_utf8(&checksum).ok();
+            let expected = expected.and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected != Some(sum) {
+                self.write(b"-");
+                continue;
+            }
+            self.write(b"+");
+            // Escaped bytes are followed by their value xor 0x20
+            let mut unescaped = vec![];
+            let mut bytes = packet.into_iter();
+            while let Some(byte) = bytes.next() {
+                match byte {
+                    b'}' => unescaped.push(bytes.next()? ^ 0x20),
+                    byte => unescaped.push(byte),
+                }
+            }
+            return Some(String::from_utf8_lossy(&unescaped).into_owned());
+        }
+    }
+
+    /* Sends a packet until gdb acknowledges it. */
+    fn send(&mut self, packet: &str) {
+        let mut escaped = vec![];
+        for &byte in packet.as_bytes() {
+            match byte {
+                b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+                byte => escaped.push(byte),
+            }
+        }
+        let sum = escaped.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut message = vec![b'$'];
+        message.extend(escaped);
+        message.extend(format!("#{:02x}", sum).into_bytes());
+        loop {
+            self.write(&message);
+            match self.read_byte() {
+                Some(b'-') => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+/* Returns the stop reply telling gdb why the program stopped. A crash of the program is reported
+ * as its termination by SIGABRT.
+ * stop: Why the program stopped, the message of the crash if it crashed.
+ * */
+fn stop_reply(stop: &Result<Stop, String>) -> String {
+    match *stop {
+        Ok(Stop::Terminated) => String::from("W00"),
+        Ok(Stop::HistoryStart) => String::from("T05replaylog:begin;"),
+        Ok(Stop::Done) | Ok(Stop::Breakpoint(..)) => String::from("S05"),
+        Err(_) => String::from("X06"),
+    }
+}
+
+/* Answers qXfer:features:read:target.xml:offset,length. */
+fn target_description(arguments: &str) -> String {
+    let range = match numbers(arguments, &[',']) {
+        Some(ref range) if range.len() == 2 => (range[0] as usize, range[1] as usize),
+        _ => return String::from("E00"),
+    };
+    let start = cmp::min(range.0, TARGET_XML.len());
+    let end = cmp::min(start.saturating_add(range.1), TARGET_XML.len());
+    let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+    format!("{}{}", prefix, &TARGET_XML[start..end])
+}
+
+/* Answers m addr,length with the bytes of the Piet stack. Reads past the top of the stack are
+ * cut short.
+ * */
+fn read_memory(debugger: &Debugger, arguments: &str) -> String {
+    let (address, length) = match numbers(arguments, &[',']) {
+        Some(ref range) if range.len() == 2 => (range[0], range[1]),
+        _ => return String::from("E00"),
+    };
+    let stack = &debugger.interpreter.machine.stack;
+    let mut bytes = vec![];
+    for offset in address..address.saturating_add(length) {
+        match stack.get((offset / 8) as usize) {
+            Some(value) => bytes.push(value.to_le_bytes()[(offset % 8) as usize]),
+            None => break,
+        }
+    }
+    if bytes.is_empty() && length > 0 {
+        String::from("E14")
+    } else {
+        to_hex(&bytes)
+    }
+}
+
+/* Answers M addr,length:XX... by changing values of the Piet stack. */
+fn write_memory(debugger: &mut Debugger, arguments: &str) -> String {
+    let mut parts = arguments.splitn(2, ':');
+    let range = parts.next().and_then(|range| numbers(range, &[',']));
+    let bytes = parts.next().and_then(from_hex);
+    let (address, bytes) = match (range, bytes) {
+        (Some(ref range), Some(bytes)) if range.len() == 2 && range[1] == bytes.len() as u64 => {
+            (range[0], bytes)
+        },
+        _ => return String::from("E00"),
+    };
+    let stack = &mut debugger.interpreter.machine.stack;
+    let end = address.saturating_add(bytes.len() as u64);
+    if end > stack.len() as u64 * 8 {
+        return String::from("E14");
+    }
+    for (offset, byte) in (address..end).zip(bytes) {
+        let slot = (offset / 8) as usize;
+        let mut value = stack[slot].to_le_bytes();
+        value[(offset % 8) as usize] = byte;
+        stack[slot] = i64::from_le_bytes(value);
+    }
+    debugger.history.clear();
+    String::from("OK")
+}
+
+/* Answers Z0/Z1 and z0/z1, which insert and remove breakpoints on codels. */
+fn set_breakpoint(debugger: &mut Debugger, arguments: &str, insert: bool) -> String {
+    let address = match numbers(arguments, &[',']) {
+        Some(ref arguments) if arguments.len() == 3 && arguments[0] <= 1 => arguments[1],
+        Some(_) => return String::new(),
+        None => return String::from("E00"),
+    };
+    let (x, y) = codel_at(address);
+    let picture = debugger.interpreter.picture;
+    if y >= picture.len() || x >= picture[y].len() {
+        return String::from("E22");
+    }
+    let breakpoint = Some(Breakpoint::Codel(x, y));
+    if insert {
+        debugger.breakpoints.push(breakpoint);
+    } else if let Some(id) = debugger.breakpoints.iter().position(|b| *b == breakpoint) {
+        debugger.breakpoints[id] = None;
+    }
+    String::from("OK")
+}
+
+/* Answers the packets of gdb until it detaches or kills the program.
+ * output: Where the program writes, when its output has to be sent to gdb.
+ * */
+fn session(connection: &mut Connection, debugger: &mut Debugger, output: Option<SharedBuffer>) {
+    let mut last_stop = Ok(Stop::Done);
+    while let Some(packet) = connection.receive() {
+        let mut stop = None;
+        let reply = match packet.as_str() {
+            "?" => stop_reply(&last_stop),
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "g" => {
+                let values = (0..REGISTERS).filter_map(|number| register(debugger, number));
+                values.map(|value| to_hex(&value.to_le_bytes())).collect()
+            },
+            "D" => {
+                connection.send("OK");
+                return;
+            },
+            "k" => return,
+            "bc" => {
+                stop = Some(debugger.guarded(|debugger| debugger.reverse(None)));
+                String::new()
+            },
+            "bs" => {
+                stop = Some(debugger.guarded(|debugger| debugger.reverse(Some(1))));
+                String::new()
+            },
+            _ if packet.starts_with("qSupported") => {
+                String::from("PacketSize=4000;qXfer:features:read+;ReverseStep+;ReverseContinue+")
+            },
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                target_description(&packet["qXfer:features:read:target.xml:".len()..])
+            },
+            _ if packet.starts_with('H') || packet.starts_with('T') => String::from("OK"),
+            // Continuing or stepping from another address isn't supported, it is ignored
+            _ if packet.starts_with('c') => {
+                stop = Some(debugger.guarded(|debugger| debugger.resume(None)));
+                String::new()
+            },
+            _ if packet.starts_with('s') => {
+                stop = Some(debugger.guarded(|debugger| debugger.resume(Some(1))));
+                String::new()
+            },
+            _ if packet.starts_with('p') => {
+                let number = u64::from_str_radix(&packet[1..], 16).ok();
+                match number.and_then(|number| register(debugger, number)) {
+                    Some(value) => to_hex(&value.to_le_bytes()),
+                    None => String::from("E00"),
+                }
+            },
+            _ if packet.starts_with('m') => read_memory(debugger, &packet[1..]),
+            _ if packet.starts_with('M') => write_memory(debugger, &packet[1..]),
+            _ if packet.starts_with('Z') => set_breakpoint(debugger, &packet[1..], true),
+            _ if packet.starts_with('z') => set_breakpoint(debugger, &packet[1..], false),
+            // Empty replies tell gdb that the packet isn't supported
+            _ => String::new(),
+        };
+        match stop {
+            Some(stop) => {
+                let mut text = output.as_ref().map(|output| output.take()).unwrap_or_default();
+                if let Err(ref message) = stop {
+                    text.push_str(&format!("{}\n", message));
+                }
+                if !text.is_empty() {
+                    connection.send(&format!("O{}", to_hex(text.as_bytes())));
+                }
+                connection.send(&stop_reply(&stop));
+                last_stop = stop;
+            },
+            None => connection.send(&reply),
+        }
+    }
+}
+
+/* Runs the stub until gdb detaches.
+ * address: "-" to talk to gdb over stdin and stdout, [host]:port to wait for gdb on a TCP port,
+ * localhost being the default host.
+ * history: How many steps can be undone.
+ * */
+pub fn run_gdbserver(picture: &[Vec<Codel>], address: &str, history: HistorySize) {
+    if address == "-" {
+        let output = SharedBuffer::new();
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(output.clone()));
+        let mut connection = Connection {
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+        };
+        session(&mut connection, &mut Debugger::new(picture, machine, history), Some(output));
+        return;
+    }
+
+    let address = if address.starts_with(':') {
+        format!("localhost{}", address)
+    } else {
+        address.to_string()
+    };
+    let stream = net::TcpListener::bind(&address).and_then(|listener| {
+        eprintln!("Listening on {}", listener.local_addr()?);
+        Ok(listener.accept()?.0)
+    });
+    let connection = stream.and_then(|stream| {
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            input: Box::new(io::BufReader::new(stream.try_clone()?)),
+            output: Box::new(stream),
+        })
+    });
+    match connection {
+        Ok(mut connection) => {
+            let mut debugger = Debugger::new(picture, Machine::new(false), history);
+            session(&mut connection, &mut debugger, None);
+        },
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use instruction::*;
+    use optimizer::*;
+    use picture::*;
+    use pietcolor::*;
+
+    fn connection(input: &[u8], output: &SharedBuffer) -> Connection {
+        Connection {
+            input: Box::new(io::Cursor::new(input.to_vec())),
+            output: Box::new(output.clone()),
+        }
+    }
+
+    /* Frames packets the way gdb sends them, each one followed by enough acknowledgments for the
+     * replies to it.
+     * */
+    fn packets(packets: &[&str]) -> Vec<u8> {
+        let mut input = vec![];
+        for packet in packets.iter() {
+            let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            input.extend(format!("${}#{:02x}+++", packet, sum).into_bytes());
+        }
+        input
+    }
+
+    /* Returns the packets written by the stub, without the acknowledgments. */
+    fn replies(output: &SharedBuffer) -> Vec<String> {
+        let bytes = output.take().into_bytes();
+        let mut reader = connection(&bytes, &SharedBuffer::new());
+        let mut replies = vec![];
+        while let Some(reply) = reader.receive() {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    fn picture(instructions: &[Instruction]) -> Vec<Vec<Codel>> {
+        let ops: Vec<Op> =
+            instructions.iter().map(|&instruction| Op { instruction, value: 1 }).collect();
+        let colors = layout(&ops);
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, false)
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(numbers("1f,a", &[',']), Some(vec![0x1f, 0xa]));
+        assert_eq!(numbers("1,x", &[',']), None);
+        assert_eq!(from_hex("00ff"), Some(vec![0, 0xff]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(to_hex(&[0xab, 1]), "ab01");
+        assert_eq!(codel_at(address_of(3, 4) | 0x201), (3, 4));
+    }
+
+    #[test]
+    fn checks_checksums() {
+        let output = SharedBuffer::new();
+        let mut stub = connection(b"+$g#00$g#67", &output);
+        assert_eq!(stub.receive(), Some(String::from("g")));
+        assert_eq!(output.take(), "-+");
+        assert_eq!(stub.receive(), None);
+
+        let mut stub = connection(b"$qC#b4", &output);
+        assert_eq!(stub.receive(), Some(String::from("qC")));
+        assert_eq!(stub.receive(), None);
+    }
+
+    #[test]
+    fn escapes_packets() {
+        let output = SharedBuffer::new();
+        // The first attempt is refused
+        connection(b"-+", &output).send("a$b#c}d*");
+        let sent = output.take();
+        assert_eq!(sent.matches("$a}").count(), 2);
+        let mut reader = connection(sent.as_bytes(), &SharedBuffer::new());
+        assert_eq!(reader.receive(), Some(String::from("a$b#c}d*")));
+        assert_eq!(reader.receive(), Some(String::from("a$b#c}d*")));
+    }
+
+    #[test]
+    fn steps_and_continues() {
+        let picture = picture(&[Instruction::Push, Instruction::OutNumber]);
+        let output = SharedBuffer::new();
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(output.clone()));
+        let size = HistorySize { memory: 1 << 20, interval: 100 };
+        let mut debugger = Debugger::new(&picture, machine, size);
+        let sent = SharedBuffer::new();
+        let input = packets(&["?", "s", "p1", "m0,8", "bs", "p4", "c", "D"]);
+        session(&mut connection(&input, &sent), &mut debugger, Some(output));
+
+        let replies = replies(&sent);
+        let one = to_hex(&1u64.to_le_bytes());
+        assert_eq!(replies[0], "S05");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], one);
+        assert_eq!(replies[3], to_hex(&1i64.to_le_bytes()));
+        assert_eq!(replies[4], "S05");
+        assert_eq!(replies[5], to_hex(&0u64.to_le_bytes()));
+        assert_eq!(replies[6], format!("O{}", to_hex(b"1")));
+        assert_eq!(replies[7], "W00");
+        assert_eq!(replies[8], "OK");
+    }
+
+    #[test]
+    fn reports_crashes() {
+        // Reads a number, which there isn't
+        let picture = picture(&[Instruction::InNumber]);
+        let output = SharedBuffer::new();
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(output.clone()));
+        let size = HistorySize { memory: 1 << 20, interval: 100 };
+        let mut debugger = Debugger::new(&picture, machine, size);
+        let sent = SharedBuffer::new();
+        session(&mut connection(&packets(&["c", "?", "k"]), &sent), &mut debugger, Some(output));
+
+        let replies = replies(&sent);
+        let message = String::from_utf8(from_hex(&replies[0][1..]).unwrap()).unwrap();
+        assert!(message.starts_with("The program crashed"));
+        assert_eq!(replies[1], "X06");
+        assert_eq!(replies[2], "X06");
+        assert_eq!(replies.len(), 3);
+    }
+}
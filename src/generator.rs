@@ -0,0 +1,190 @@
+use instruction::*;
+use numbers::*;
+use optimizer::*;
+use pietcolor::*;
+use std::*;
+
+/* Generation of Piet pictures from sequences of instructions.
+ *
+ * Instructions are laid out as a snake of horizontal lanes, each lane being one codel high and
+ * separated from the next one by a row of black codels. Every block is a horizontal line of
+ * codels: its length is the number it pushes, or 1 for the other instructions. Lanes are
+ * traversed left to right and right to left alternately:
+ * - At the end of a left to right lane, the block that was entered last is followed by white
+ *   codels up to the edge of the picture. The interpreter rotates dp down when hitting the edge,
+ *   slides through a white codel of the separator and enters the next lane. Its first block is
+ *   blocked below, so dp rotates left.
+ * - At the end of a right to left lane, the same rotations would lead up. The white codels lead
+ *   to a `push 3, pointer` sequence at the left of the lane instead, which points dp down. The
+ *   interpreter goes down through a white codel of the separator into a block of 3 codels whose
+ *   middle codel is the only one open to the top, so that every attempt fails until dp points
+ *   right. That block is followed by a white codel and the rest of the lane.
+ * The program ends in a block of 3 codels shaped like an L that is entered from a codel that is
+ * never the farthest one, so that the 8 attempts to leave it fail.
+ * */
+
+// Number of codels at the left of a right to left lane that are used to turn down
+const LEFT_TURN_WIDTH: usize = 7;
+
+fn op(instruction: Instruction) -> Op {
+    Op { instruction, value: 0 }
+}
+
+/* Returns a sequence of instructions printing `text`. Each character is computed from the
+ * previous one when that is cheaper than computing it from scratch.
+ * */
+pub fn print_program(text: &str, synthesizer: &mut Synthesizer) -> Vec<Op> {
+    let mut ops = vec![];
+    let mut previous: Option<i64> = None;
+    let chars: Vec<i64> = text.chars().map(|c| c as i64).collect();
+    for (i, &c) in chars.iter().enumerate() {
+        let mut best = synthesizer.number(c);
+        if let Some(previous) = previous {
+            let mut relative = vec![];
+            if c > previous {
+                relative = synthesizer.number(c - previous);
+                relative.push(op(Instruction::Add));
+            } else if c < previous {
+                relative = synthesizer.number(previous - c);
+                relative.push(op(Instruction::Sub));
+            }
+            let model = synthesizer.model();
+            if model.cost(&relative) <= model.cost(&best) {
+                best = relative;
+            }
+        }
+        ops.extend(best);
+        if i + 1 < chars.len() {
+            ops.push(op(Instruction::Duplicate));
+        }
+        ops.push(op(Instruction::OutChar));
+        previous = Some(c);
+    }
+    ops
+}
+
+/* Keeps track of where the next block goes while laying out a program. */
+struct Layout {
+    colors: Vec<Vec<PietColor>>,
+    width: usize,
+    // Index of the row of the current lane
+    y: usize,
+    left_to_right: bool,
+    // Next free column of the current lane
+    x: usize,
+}
+
+impl Layout {
+    fn new_lane(&mut self) {
+        let black = PietColor { hue: Hue::Black, lightness: Lightness::Normal };
+        self.colors.push(vec![black; self.width]);
+        self.colors.push(vec![black; self.width]);
+    }
+
+    /* Returns whether a block of `size` codels fits in the current lane, keeping room for the
+     * block that follows it.
+     * */
+    fn fits(&self, size: usize) -> bool {
+        if self.left_to_right {
+            self.x + size < self.width
+        } else {
+            self.x + 1 >= LEFT_TURN_WIDTH + 1 + size
+        }
+    }
+
+    /* Draws a block of `size` codels at the current position of the lane. */
+    fn place(&mut self, size: usize, color: PietColor) {
+        for _ in 0..size {
+            self.colors[self.y][self.x] = color;
+            if self.left_to_right {
+                self.x += 1;
+            } else {
+                self.x -= 1;
+            }
+        }
+    }
+
+    /* Fills the rest of the current lane with white and leads the interpreter to the start of
+     * the next lane.
+     * color: Any color of the hue cycle, used for the blocks of the turn.
+     * */
+    fn turn(&mut self, color: PietColor) {
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        let y = self.y;
+        self.new_lane();
+        if self.left_to_right {
+            for x in self.x..self.width {
+                self.colors[y][x] = white;
+            }
+            self.colors[y + 1][self.width - 1] = white;
+            self.x = self.width - 1;
+        } else {
+            for x in LEFT_TURN_WIDTH - 1..self.x + 1 {
+                self.colors[y][x] = white;
+            }
+            // push 3, pointer
+            let pushed = color.shifted(Instruction::Push.to_diff().unwrap());
+            let pointed = pushed.shifted(Instruction::Pointer.to_diff().unwrap());
+            for x in 3..6 {
+                self.colors[y][x] = color;
+            }
+            self.colors[y][2] = pushed;
+            self.colors[y][1] = pointed;
+            self.colors[y + 1][1] = white;
+            for x in 0..3 {
+                self.colors[y + 2][x] = color;
+            }
+            self.colors[y + 2][3] = white;
+            self.x = 4;
+        }
+        self.y += 2;
+        self.left_to_right = !self.left_to_right;
+    }
+
+    /* Draws the block ending the program at the current position of the lane. */
+    fn end(&mut self, color: PietColor) {
+        let (x, y) = (self.x, self.y);
+        self.colors[y][x] = color;
+        self.colors[y + 1][x] = color;
+        if self.left_to_right {
+            self.colors[y + 1][x - 1] = color;
+        } else {
+            self.colors[y + 1][x + 1] = color;
+        }
+    }
+}
+
+/* Lays out a sequence of instructions as a picture executing them in order and then
+ * terminating. There can't be any Pointer or Switch, as these would derail the interpreter.
+ * returns: The colors of the codels of the picture.
+ * */
+pub fn layout(ops: &[Op]) -> Vec<Vec<PietColor>> {
+    let mut color = PietColor { hue: Hue::Red, lightness: Lightness::Light };
+    if ops.is_empty() {
+        return vec![vec![color]];
+    }
+
+    let total: i64 = ops.iter().map(CostModel::codels).sum();
+    let largest = ops.iter().map(CostModel::codels).max().unwrap() as usize;
+    let width = (4.0 + (16.0 + 2.0 * total as f64).sqrt()).ceil() as usize;
+    let mut layout = Layout {
+        colors: vec![],
+        width: cmp::max(width, LEFT_TURN_WIDTH + 1 + largest),
+        y: 0,
+        left_to_right: true,
+        x: 0,
+    };
+    layout.new_lane();
+    for op in ops.iter() {
+        let size = CostModel::codels(op) as usize;
+        if !layout.fits(size) {
+            // The block that was entered last can't be used for the next instruction
+            layout.place(1, color);
+            layout.turn(color);
+        }
+        layout.place(size, color);
+        color = color.shifted(op.instruction.to_diff().unwrap());
+    }
+    layout.end(color);
+    layout.colors
+}
@@ -0,0 +1,118 @@
+use codel::*;
+use instruction::*;
+use picture::*;
+use pietcolor::*;
+use std::*;
+
+/* What happens when the interpreter leaves a node.
+ * Halt: All 8 attempts to leave the block failed, the program terminates.
+ * Step: The interpreter executes `instruction`, `value` being the size of the block that is left
+ * (only meaningful for Push). `next` holds the node that is entered afterwards. Pointer and
+ * Switch change dp or cc at run time, so for them `next[n]` is the node entered when dp has been
+ * rotated n times (Pointer, 4 entries) or cc has been toggled n times (Switch, 2 entries).
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exit {
+    Halt,
+    Step { instruction: Instruction, value: i64, next: Vec<usize> },
+}
+
+/* A state the interpreter can be in when it starts looking for the next block.
+ * codel: For colored blocks, the first codel of the block in reading order, as every codel of a
+ * block behaves the same. For white codels, the codel itself, as the path taken through white
+ * depends on where the white region is entered.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub codel: Codel,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub exit: Exit,
+}
+
+/* Every state reachable from the start of the program, along with the transitions between them.
+ * Everything that does not depend on the content of the Piet stack is computed here, which
+ * makes the graph suitable for compiling programs or for running them without walking the
+ * picture.
+ * */
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub start: usize,
+}
+
+fn canonical_codel(picture: &[Vec<Codel>], blocks: &Blocks, codel: &Codel) -> Codel {
+    if codel.color.hue == Hue::White {
+        return *codel;
+    }
+    let (x, y) = blocks.block_of(codel).codels[0];
+    picture[y][x]
+}
+
+struct GraphBuilder<'a> {
+    picture: &'a [Vec<Codel>],
+    blocks: Blocks,
+    nodes: Vec<Node>,
+    ids: collections::HashMap<(usize, usize, Direction, Direction), usize>,
+}
+
+impl<'a> GraphBuilder<'a> {
+    /* Returns the id of the node corresponding to the given state, creating it if needed. New
+     * nodes are created with a Halt exit, which is fixed up by build_graph.
+     * */
+    fn node_id(&mut self, codel: &Codel, dp: Direction, cc: Direction) -> usize {
+        let codel = canonical_codel(self.picture, &self.blocks, codel);
+        let key = (codel.x, codel.y, dp, cc);
+        if let Some(id) = self.ids.get(&key) {
+            return *id;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(Node { codel, dp, cc, exit: Exit::Halt });
+        self.ids.insert(key, id);
+        id
+    }
+}
+
+/* Builds the graph of the states reachable from the top-left codel of the picture, using the
+ * same rules as the interpreter to choose the next codel.
+ * */
+pub fn build_graph(picture: &[Vec<Codel>]) -> Graph {
+    let mut builder = GraphBuilder {
+        picture,
+        blocks: get_blocks(picture),
+        nodes: vec![],
+        ids: collections::HashMap::new(),
+    };
+    let start = builder.node_id(&picture[0][0], Direction::Right, Direction::Left);
+    let mut visited = 0;
+    while visited < builder.nodes.len() {
+        let (codel, dp, cc) = {
+            let node = &builder.nodes[visited];
+            (node.codel, node.dp, node.cc)
+        };
+        if let Some(step) = next_move(picture, &codel, dp, cc) {
+            let instruction = Instruction::from_codels(&step.from, step.to);
+            let next = match instruction {
+                Instruction::Pointer => {
+                    let mut dp = step.dp;
+                    let mut next = vec![];
+                    for _ in 0..4 {
+                        next.push(builder.node_id(step.to, dp, step.cc));
+                        dp = dp.rotate();
+                    }
+                    next
+                },
+                Instruction::Switch => {
+                    vec![
+                        builder.node_id(step.to, step.dp, step.cc),
+                        builder.node_id(step.to, step.dp, step.cc.opposite()),
+                    ]
+                },
+                _ => vec![builder.node_id(step.to, step.dp, step.cc)],
+            };
+            builder.nodes[visited].exit =
+                Exit::Step { instruction, value: step.block_size as i64, next };
+        }
+        visited += 1;
+    }
+    Graph { nodes: builder.nodes, start }
+}
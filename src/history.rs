@@ -0,0 +1,453 @@
+use codel::*;
+use instruction::*;
+use interpreter::*;
+use machine::*;
+use std::*;
+
+// Largest number of values a step can change for the change to be stored in its record
+const MAX_CHANGE: usize = 64;
+
+/* How a step changed the stack.
+ * Values: The step replaced `removed`, the values at the top of the stack, with `added`.
+ * Rebuilt: The step can change too much of the stack for the change to be stored. The stack
+ * before it is rebuilt from a checkpoint, the stack after it by performing it again, or from
+ * the checkpoint taken right after it for syscalls, which can't be performed again.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Values { removed: Vec<i64>, added: Vec<i64> },
+    Rebuilt,
+}
+
+/* What is needed to undo a step and to perform it again.
+ * codel, dp, cc: The state of the interpreter before the step.
+ * next_dp, next_cc: dp and cc after the step, the codel being step.to.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub step: Step,
+    pub codel: Codel,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub next_dp: Direction,
+    pub next_cc: Direction,
+    pub change: Change,
+}
+
+impl Record {
+    /* Returns roughly how many bytes the record uses. */
+    fn size(&self) -> usize {
+        let values = match self.change {
+            Change::Values { ref removed, ref added } => removed.len() + added.len(),
+            Change::Rebuilt => 0,
+        };
+        mem::size_of::<Record>() + values * mem::size_of::<i64>()
+    }
+}
+
+/* A copy of the stack before the step `index`, counted from the creation of the history. */
+struct Checkpoint {
+    index: usize,
+    stack: Vec<i64>,
+}
+
+impl Checkpoint {
+    fn size(&self) -> usize {
+        mem::size_of::<Checkpoint>() + self.stack.len() * mem::size_of::<i64>()
+    }
+}
+
+/* How much a history keeps.
+ * memory: The number of bytes the history can use, 0 not keeping any step.
+ * interval: The number of steps between two checkpoints. Undoing a step whose change isn't
+ * stored performs again up to that many steps.
+ * */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistorySize {
+    pub memory: usize,
+    pub interval: usize,
+}
+
+/* Returns how many values at the top of the stack an instruction can change, None if that is
+ * too many for the change to be stored.
+ * */
+fn touched(instruction: Instruction, stack: &[i64]) -> Option<usize> {
+    use instruction::Instruction::*;
+    let depth = match instruction {
+        Push | InNumber | InChar | Nop => 0,
+        Pop | Not | Pointer | Switch | Duplicate | OutNumber | OutChar => 1,
+        Add | Sub | Mul | Div | Mod | Greater => 2,
+        // Roll moves values from as deep as its first argument
+        Roll if stack.len() >= 2 => 2 + cmp::max(stack[stack.len() - 2], 0) as usize,
+        Roll => 2,
+        // Syscalls can write anywhere in the stack
+        Syscall => return None,
+    };
+    if depth > MAX_CHANGE {
+        return None;
+    }
+    Some(cmp::min(depth, stack.len()))
+}
+
+/* Performs the step of `record` again on `stack`, which has to be the stack before it. */
+fn redo(record: &Record, stack: &mut Vec<i64>) {
+    match record.change {
+        Change::Values { ref removed, ref added } => {
+            let kept = stack.len() - removed.len();
+            stack.truncate(kept);
+            stack.extend(added.iter().cloned());
+        },
+        Change::Rebuilt => {
+            // Only rolls get here, which don't read input nor write output
+            let mut machine = Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()));
+            machine.stack = mem::take(stack);
+            machine.execute(record.step.instruction, record.step.block_size as i64);
+            *stack = machine.stack;
+        },
+    }
+}
+
+/* The steps performed by an interpreter, which makes it possible to go back in time. Steps that
+ * were undone are performed again from their record rather than executed, so that they don't
+ * read input or write output twice.
+ * Records only hold the few values a step changed. Every `size.interval` steps, and after each
+ * syscall, a checkpoint copies the whole stack, from which the steps whose change wasn't stored
+ * are undone. Once the history uses more than `size.memory` bytes, the oldest checkpoint and
+ * the records following it are forgotten. It only uses more than that when a single copy of the
+ * stack is larger.
+ * start, now: The indexes of the oldest record and of the next step, records after `now` being
+ * the steps that were undone.
+ * used: The number of bytes used by the records and checkpoints.
+ * */
+pub struct History {
+    pub size: HistorySize,
+    records: collections::VecDeque<Record>,
+    checkpoints: collections::VecDeque<Checkpoint>,
+    start: usize,
+    now: usize,
+    used: usize,
+}
+
+impl History {
+    pub fn new(size: HistorySize) -> History {
+        History {
+            size: HistorySize { memory: size.memory, interval: cmp::max(size.interval, 1) },
+            records: collections::VecDeque::new(),
+            checkpoints: collections::VecDeque::new(),
+            start: 0,
+            now: 0,
+            used: 0,
+        }
+    }
+
+    /* Returns the number of steps that can be undone. */
+    pub fn len(&self) -> usize {
+        self.now - self.start
+    }
+
+    /* Forgets every step, which has to be done when the state of the interpreter is changed by
+     * other means than steps.
+     * */
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.checkpoints.clear();
+        self.start = 0;
+        self.now = 0;
+        self.used = 0;
+    }
+
+    fn record(&self, index: usize) -> &Record {
+        &self.records[index - self.start]
+    }
+
+    fn checkpoint(&mut self, stack: &[i64]) {
+        let checkpoint = Checkpoint { index: self.now, stack: stack.to_vec() };
+        self.used += checkpoint.size();
+        self.checkpoints.push_back(checkpoint);
+    }
+
+    /* Returns the stack before the step `index`, rebuilt from the last checkpoint before it. As
+     * there is a checkpoint after each syscall, no syscall has to be performed again.
+     * */
+    fn stack_before(&self, index: usize) -> Vec<i64> {
+        let checkpoint = self.checkpoints.iter().rev().find(|c| c.index <= index).unwrap();
+        let mut stack = checkpoint.stack.clone();
+        for index in checkpoint.index..index {
+            redo(self.record(index), &mut stack);
+        }
+        stack
+    }
+
+    /* Forgets the oldest steps until the history fits in its memory. Whole checkpoints are
+     * forgotten, so that every step left can be undone.
+     * stack: The current stack, from which a checkpoint is taken when the last one has to go.
+     * */
+    fn forget(&mut self, stack: &[i64]) {
+        while self.used > self.size.memory {
+            if self.checkpoints.len() == 1 && self.checkpoints[0].index < self.now {
+                self.checkpoint(stack);
+            }
+            let end = match self.checkpoints.get(1) {
+                Some(checkpoint) => checkpoint.index,
+                None => break,
+            };
+            while self.start < end {
+                let record = self.records.pop_front().unwrap();
+                self.used -= record.size();
+                self.start += 1;
+            }
+            let checkpoint = self.checkpoints.pop_front().unwrap();
+            self.used -= checkpoint.size();
+        }
+    }
+
+    /* Performs a step, or the step that was undone last if there is one.
+     * returns: None if the program terminated, what was done otherwise.
+     * */
+    pub fn step(&mut self, interpreter: &mut Interpreter) -> Option<Step> {
+        if self.now < self.start + self.records.len() {
+            let after = self.checkpoints.iter().find(|c| c.index == self.now + 1);
+            let record = self.record(self.now);
+            match after {
+                Some(checkpoint) => interpreter.machine.stack = checkpoint.stack.clone(),
+                None => redo(record, &mut interpreter.machine.stack),
+            }
+            interpreter.codel = record.step.to;
+            interpreter.dp = record.next_dp;
+            interpreter.cc = record.next_cc;
+            interpreter.steps += 1;
+            let step = record.step.clone();
+            self.now += 1;
+            return Some(step);
+        }
+        if self.size.memory == 0 {
+            return interpreter.step();
+        }
+
+        let (codel, dp, cc) = (interpreter.codel, interpreter.dp, interpreter.cc);
+        let instruction = match interpreter.peek() {
+            Some((_, instruction)) => instruction,
+            None => return None,
+        };
+        let due = match self.checkpoints.back() {
+            Some(checkpoint) => self.now - checkpoint.index >= self.size.interval,
+            None => true,
+        };
+        if self.records.is_empty() || due {
+            if self.records.is_empty() {
+                self.clear();
+            }
+            self.checkpoint(&interpreter.machine.stack);
+        }
+        let stack = &interpreter.machine.stack;
+        let touched = touched(instruction, stack);
+        let kept = stack.len() - touched.unwrap_or(0);
+        let removed = touched.map(|_| stack[kept..].to_vec());
+        let step = interpreter.step()?;
+        let change = match removed {
+            Some(removed) => {
+                Change::Values { removed, added: interpreter.machine.stack[kept..].to_vec() }
+            },
+            None => Change::Rebuilt,
+        };
+        let record = Record {
+            step: step.clone(),
+            codel,
+            dp,
+            cc,
+            next_dp: interpreter.dp,
+            next_cc: interpreter.cc,
+            change,
+        };
+        self.used += record.size();
+        self.records.push_back(record);
+        self.now += 1;
+        if instruction == Instruction::Syscall {
+            self.checkpoint(&interpreter.machine.stack);
+        }
+        self.forget(&interpreter.machine.stack);
+        Some(step)
+    }
+
+    /* Undoes the last step.
+     * returns: The step that was undone, None if there is no step left to undo.
+     * */
+    pub fn step_back(&mut self, interpreter: &mut Interpreter) -> Option<Step> {
+        if self.now == self.start {
+            return None;
+        }
+        let index = self.now - 1;
+        let record = self.record(index);
+        match record.change {
+            Change::Values { ref removed, ref added } => {
+                let stack = &mut interpreter.machine.stack;
+                let kept = stack.len() - added.len();
+                stack.truncate(kept);
+                stack.extend(removed.iter().cloned());
+            },
+            Change::Rebuilt => interpreter.machine.stack = self.stack_before(index),
+        }
+        interpreter.codel = record.codel;
+        interpreter.dp = record.dp;
+        interpreter.cc = record.cc;
+        interpreter.steps -= 1;
+        let step = record.step.clone();
+        self.now -= 1;
+        Some(step)
+    }
+
+    /* Looks for the last step that changed the value currently at index `slot` of the stack, 0
+     * being the bottom of the stack.
+     * stack_len: The current size of the stack.
+     * returns: The number of steps to undo in order to get back to before that step, None if it
+     * isn't in the history.
+     * */
+    pub fn last_write(&self, slot: usize, stack_len: usize) -> Option<usize> {
+        let mut len = stack_len;
+        for index in (self.start..self.now).rev() {
+            match self.record(index).change {
+                Change::Values { ref removed, ref added } => {
+                    let base = len - added.len();
+                    if base <= slot && slot < len {
+                        // Instructions such as Duplicate or Roll pop values and push them back
+                        // unchanged
+                        let offset = slot - base;
+                        if removed.get(offset) != Some(&added[offset]) {
+                            return Some(self.now - index);
+                        }
+                    }
+                    len = base + removed.len();
+                },
+                Change::Rebuilt => {
+                    let (before, after) = (self.stack_before(index), self.stack_before(index + 1));
+                    if slot < len && before.get(slot) != Some(&after[slot]) {
+                        return Some(self.now - index);
+                    }
+                    len = before.len();
+                },
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use numbers::*;
+    use optimizer::*;
+    use picture::*;
+    use pietcolor::*;
+
+    fn color(hue: Hue, lightness: Lightness) -> PietColor {
+        PietColor { hue, lightness }
+    }
+
+    fn picture(colors: &[Vec<PietColor>], syscalls: bool) -> Vec<Vec<Codel>> {
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        to_codels(&pixels, 1, color(Hue::White, Lightness::Normal), syscalls)
+    }
+
+    fn machine() -> Machine {
+        Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()))
+    }
+
+    /* Performs up to `count` steps, then undoes them all and performs them again, checking that
+     * the interpreter goes through the same states every time.
+     * returns: The history.
+     * */
+    fn check(picture: &[Vec<Codel>], size: HistorySize, count: usize) -> History {
+        let mut interpreter = Interpreter::new(picture, machine());
+        let mut history = History::new(size);
+        let state = |interpreter: &Interpreter| {
+            let stack = interpreter.machine.stack.clone();
+            (interpreter.codel, interpreter.dp, interpreter.cc, interpreter.steps, stack)
+        };
+        let mut states = vec![state(&interpreter)];
+        while states.len() <= count && history.step(&mut interpreter).is_some() {
+            states.push(state(&interpreter));
+        }
+        let kept = history.len();
+        for expected in states.iter().rev().skip(1).take(kept) {
+            assert!(history.step_back(&mut interpreter).is_some());
+            assert_eq!(&state(&interpreter), expected);
+        }
+        assert_eq!(history.step_back(&mut interpreter), None);
+        for expected in states[states.len() - kept..].iter() {
+            assert!(history.step(&mut interpreter).is_some());
+            assert_eq!(&state(&interpreter), expected);
+        }
+        history
+    }
+
+    fn op(instruction: Instruction) -> Op {
+        Op { instruction, value: 0 }
+    }
+
+    #[test]
+    fn undoes_past_checkpoints() {
+        use instruction::Instruction::*;
+        // A roll deeper than MAX_CHANGE values, which can only be undone from a checkpoint
+        let mut synthesizer =
+            Synthesizer::new(CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 });
+        let mut ops = vec![];
+        for value in 0..MAX_CHANGE as i64 + 10 {
+            ops.extend(synthesizer.number(value % 5 + 1));
+        }
+        ops.extend(synthesizer.number(MAX_CHANGE as i64 + 5));
+        ops.extend(synthesizer.number(3));
+        ops.push(op(Roll));
+        ops.extend(vec![op(Add), op(Duplicate), op(Mul), op(OutNumber), op(Pop)]);
+        let picture = picture(&layout(&ops), false);
+
+        for &interval in [1, 7, 1000].iter() {
+            let history = check(&picture, HistorySize { memory: 1 << 20, interval }, 100_000);
+            let rebuilt = history.records.iter().filter(|r| r.change == Change::Rebuilt).count();
+            assert_eq!(rebuilt, 1);
+            let checkpoints = history.checkpoints.len();
+            assert!(checkpoints >= history.len() / interval, "{} checkpoints", checkpoints);
+        }
+    }
+
+    #[test]
+    fn undoes_past_a_syscall() {
+        use pietcolor::Hue::*;
+        use pietcolor::Lightness::*;
+        // Pushes 7, 1, turns the 1 into 0, then pushes 39 and calls getpid with no argument
+        let mut row = vec![color(Red, Light); 7];
+        row.extend(vec![color(Red, Normal), color(Red, Dark)]);
+        row.extend(vec![color(Green, Normal); 39]);
+        row.extend(vec![color(Green, Dark), color(Smoke, Normal), color(Blue, Light)]);
+        let picture = picture(&[row], true);
+
+        let size = HistorySize { memory: 1 << 20, interval: 1000 };
+        let history = check(&picture, size, 6);
+        assert_eq!(history.len(), 6);
+        assert_eq!(history.records[5].step.instruction, Instruction::Syscall);
+        assert_eq!(history.records[5].change, Change::Rebuilt);
+        // One checkpoint at the start, one after the syscall
+        let indexes: Vec<usize> = history.checkpoints.iter().map(|c| c.index).collect();
+        assert_eq!(indexes, vec![0, 6]);
+    }
+
+    #[test]
+    fn forgets_steps_beyond_its_memory() {
+        let mut synthesizer =
+            Synthesizer::new(CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 });
+        let ops = print_program("A longer text to make a lot of steps", &mut synthesizer);
+        let picture = picture(&layout(&ops), false);
+
+        let size = HistorySize { memory: 4000, interval: 10 };
+        let history = check(&picture, size, 1000);
+        assert!(history.used <= size.memory, "{} bytes used", history.used);
+        assert!(history.len() > 0 && history.len() < 1000);
+        assert!(history.start > 0);
+        assert_eq!(history.checkpoints[0].index, history.start);
+
+        // Keeping nothing leaves nothing to undo
+        let history = check(&picture, HistorySize { memory: 0, interval: 10 }, 100);
+        assert_eq!(history.len(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use codel::*;
+use pietcolor::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Push,
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Not,
+    Greater,
+    Pointer,
+    Switch,
+    Duplicate,
+    Roll,
+    InNumber,
+    InChar,
+    OutNumber,
+    OutChar,
+    Syscall,
+    Nop,
+}
+
+impl Instruction {
+    /* Returns the instruction executed when going from the codel `from` to the codel `to`.
+     * Leaving a Smoke codel is a Syscall, entering or leaving any other non-colored codel is a
+     * Nop.
+     * */
+    pub fn from_codels(from: &Codel, to: &Codel) -> Instruction {
+        if from.color.hue == Hue::Smoke {
+            return Instruction::Syscall;
+        }
+        Instruction::from_diff(from.diff_to(to))
+    }
+
+    /* Returns the instruction corresponding to a (hue, lightness) difference as returned by
+     * PietColor::diff_to.
+     * */
+    pub fn from_diff(diff: (usize, usize)) -> Instruction {
+        use self::Instruction::*;
+        match diff {
+            (0, 1) => Push,
+            (0, 2) => Pop,
+            (1, 0) => Add,
+            (1, 1) => Sub,
+            (1, 2) => Mul,
+            (2, 0) => Div,
+            (2, 1) => Mod,
+            (2, 2) => Not,
+            (3, 0) => Greater,
+            (3, 1) => Pointer,
+            (3, 2) => Switch,
+            (4, 0) => Duplicate,
+            (4, 1) => Roll,
+            (4, 2) => InNumber,
+            (5, 0) => InChar,
+            (5, 1) => OutNumber,
+            (5, 2) => OutChar,
+            (6, _) => Nop,
+            (a, b) => panic!("Error: differences are ({},{}))", a, b),
+        }
+    }
+
+    /* Returns the (hue, lightness) difference that has to separate two colors for this
+     * instruction to be executed, None for the instructions that aren't encoded by a color
+     * change.
+     * */
+    pub fn to_diff(self) -> Option<(usize, usize)> {
+        use self::Instruction::*;
+        match self {
+            Push => Some((0, 1)),
+            Pop => Some((0, 2)),
+            Add => Some((1, 0)),
+            Sub => Some((1, 1)),
+            Mul => Some((1, 2)),
+            Div => Some((2, 0)),
+            Mod => Some((2, 1)),
+            Not => Some((2, 2)),
+            Greater => Some((3, 0)),
+            Pointer => Some((3, 1)),
+            Switch => Some((3, 2)),
+            Duplicate => Some((4, 0)),
+            Roll => Some((4, 1)),
+            InNumber => Some((4, 2)),
+            InChar => Some((5, 0)),
+            OutNumber => Some((5, 1)),
+            OutChar => Some((5, 2)),
+            Syscall | Nop => None,
+        }
+    }
+
+    /* Returns the short name of the instruction used by the debugger. */
+    pub fn name(self) -> &'static str {
+        use self::Instruction::*;
+        match self {
+            Push => "push",
+            Pop => "pop",
+            Add => "add",
+            Sub => "sub",
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+            Not => "not",
+            Greater => "greater",
+            Pointer => "pointer",
+            Switch => "switch",
+            Duplicate => "dup",
+            Roll => "roll",
+            InNumber => "inn",
+            InChar => "inc",
+            OutNumber => "outn",
+            OutChar => "outc",
+            Syscall => "syscall",
+            Nop => "nop",
+        }
+    }
+
+    /* Returns a name of two characters for the instruction, for where its name doesn't fit. */
+    #[cfg(feature = "tui")]
+    pub fn abbreviation(self) -> &'static str {
+        use self::Instruction::*;
+        match self {
+            Push => "pu",
+            Pop => "po",
+            Add => "ad",
+            Sub => "su",
+            Mul => "mu",
+            Div => "dv",
+            Mod => "mo",
+            Not => "no",
+            Greater => "gt",
+            Pointer => "dp",
+            Switch => "cc",
+            Duplicate => "du",
+            Roll => "ro",
+            InNumber => "in",
+            InChar => "ic",
+            OutNumber => "on",
+            OutChar => "oc",
+            Syscall => "sy",
+            Nop => "  ",
+        }
+    }
+
+    /* Returns the instruction whose name is `name`, as returned by Instruction::name. */
+    pub fn from_name(name: &str) -> Option<Instruction> {
+        use self::Instruction::*;
+        let all = [
+            Push, Pop, Add, Sub, Mul, Div, Mod, Not, Greater, Pointer, Switch, Duplicate, Roll,
+            InNumber, InChar, OutNumber, OutChar, Syscall, Nop,
+        ];
+        all.iter().cloned().find(|instruction| instruction.name() == name)
+    }
+}
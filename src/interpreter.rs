@@ -0,0 +1,159 @@
+use codel::*;
+use instruction::*;
+use machine::*;
+use picture::*;
+use std::*;
+
+/* What the interpreter did during a step.
+ * from: The codel the interpreter left from.
+ * to: The codel that was entered.
+ * block_size: The size of the block that was left, 0 for white blocks.
+ * dp, cc: The direction pointer and codel chooser the block was left with, before the instruction
+ * was executed.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub from: Codel,
+    pub to: Codel,
+    pub block_size: usize,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub instruction: Instruction,
+}
+
+// Exit status of runs stopped by a limit, like timeout(1)
+pub const LIMIT_REACHED: i32 = 124;
+
+/* Bounds on a run, which stops once one of them is reached. None means no bound.
+ * timeout: The longest time the run can take.
+ * */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<time::Duration>,
+}
+
+impl Limits {
+    /* Returns whether the run has to stop before its next step, describing the limit if it does.
+     * started: When the run started.
+     * */
+    pub fn reached(&self, steps: u64, started: time::Instant) -> Option<String> {
+        match (self.max_steps, self.timeout) {
+            (Some(max_steps), _) if steps >= max_steps => {
+                Some(format!("the limit of {} steps was reached", max_steps))
+            },
+            (_, Some(timeout)) if started.elapsed() >= timeout => {
+                Some(format!("the timeout of {:?} was reached after {} steps", timeout, steps))
+            },
+            _ => None,
+        }
+    }
+}
+
+/* A Piet program run codel by codel by walking its picture, the way the Piet specification
+ * describes it. Everything that needs to follow the program block by block (debug mode, the
+ * display, the debugger) goes through Interpreter::step.
+ * codel: The codel the interpreter is on.
+ * steps: The number of steps performed so far.
+ * */
+pub struct Interpreter<'a> {
+    pub picture: &'a [Vec<Codel>],
+    pub machine: Machine,
+    pub codel: Codel,
+    pub dp: Direction,
+    pub cc: Direction,
+    pub steps: u64,
+}
+
+impl<'a> Interpreter<'a> {
+    /* Returns an interpreter at the start of the program: on the top-left codel, dp pointing
+     * right and cc pointing left.
+     * */
+    pub fn new(picture: &'a [Vec<Codel>], machine: Machine) -> Interpreter<'a> {
+        Interpreter {
+            picture,
+            machine,
+            codel: picture[0][0],
+            dp: Direction::Right,
+            cc: Direction::Left,
+            steps: 0,
+        }
+    }
+
+    /* Goes back to the start of the program with an empty stack. What was read and written stays
+     * read and written.
+     * */
+    pub fn restart(&mut self) {
+        self.codel = self.picture[0][0];
+        self.dp = Direction::Right;
+        self.cc = Direction::Left;
+        self.steps = 0;
+        self.machine.stack.clear();
+    }
+
+    /* Describes the state of the program: its block, dp, cc and the top of the stack. */
+    pub fn state(&self) -> String {
+        let size = match self.peek() {
+            Some((step, _)) => format!(", block of {} codels", step.block_size),
+            None => String::new(),
+        };
+        let stack = &self.machine.stack;
+        let top = &stack[stack.len() - cmp::min(stack.len(), 8)..];
+        format!(
+            "({}, {}) {:?} {:?}{}, dp: {:?}, cc: {:?}, stack of {} values ending with {:?}",
+            self.codel.x,
+            self.codel.y,
+            self.codel.color.lightness,
+            self.codel.color.hue,
+            size,
+            self.dp,
+            self.cc,
+            stack.len(),
+            top
+        )
+    }
+
+    /* Looks for the next step without performing it.
+     * returns: None if the program terminates, the move the interpreter will perform and the
+     * instruction it will execute otherwise.
+     * */
+    pub fn peek(&self) -> Option<(Move<'a>, Instruction)> {
+        let step = next_move(self.picture, &self.codel, self.dp, self.cc)?;
+        let instruction = Instruction::from_codels(&step.from, step.to);
+        Some((step, instruction))
+    }
+
+    /* Moves to the next block and executes the instruction given by the color change.
+     * returns: None if the program terminated, what was done otherwise.
+     * */
+    pub fn step(&mut self) -> Option<Step> {
+        let (step, instruction) = self.peek()?;
+        self.dp = step.dp;
+        self.cc = step.cc;
+        match instruction {
+            Instruction::Pointer => {
+                for _ in 0..self.machine.execute(Instruction::Pointer, 0) {
+                    self.dp = self.dp.rotate();
+                }
+            },
+            Instruction::Switch => {
+                for _ in 0..self.machine.execute(Instruction::Switch, 0) {
+                    self.cc = self.cc.opposite();
+                }
+            },
+            instruction => {
+                self.machine.execute(instruction, step.block_size as i64);
+            },
+        }
+        self.codel = *step.to;
+        self.steps += 1;
+        Some(Step {
+            from: step.from,
+            to: *step.to,
+            block_size: step.block_size,
+            dp: step.dp,
+            cc: step.cc,
+            instruction,
+        })
+    }
+}
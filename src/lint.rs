@@ -0,0 +1,376 @@
+use codel::*;
+use graph::*;
+use picture::*;
+use pietcolor::*;
+use std::*;
+
+/* Checks of a Piet picture for things that are probably mistakes: pixels the interpreter doesn't
+ * read the way they look, parts of the program that can't run and layouts that make the
+ * interpreter do something else than what the picture suggests. Positions are given in codels,
+ * like everywhere else.
+ * */
+
+/* A problem found in a picture.
+ * position: The codel the problem is about, None when it is about the whole picture.
+ * severity: "warning", or "note" for suggestions.
+ * */
+pub struct Diagnostic {
+    pub position: Option<(usize, usize)>,
+    pub severity: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(position: Option<(usize, usize)>, message: String) -> Diagnostic {
+        Diagnostic { position, severity: "warning", message }
+    }
+
+    /* Formats the diagnostic like compilers do, `file:x:y: warning: message`. */
+    pub fn format(&self, filename: &str) -> String {
+        match self.position {
+            Some((x, y)) => {
+                format!("{}:{}:{}: {}: {}", filename, x, y, self.severity, self.message)
+            },
+            None => format!("{}: {}: {}", filename, self.severity, self.message),
+        }
+    }
+}
+
+fn hex(pixel: &[u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", pixel[0], pixel[1], pixel[2])
+}
+
+fn describe(color: PietColor) -> String {
+    match color.hue {
+        Hue::White | Hue::Black | Hue::Smoke => format!("{:?}", color.hue),
+        hue => format!("{:?} {:?}", color.lightness, hue),
+    }
+}
+
+/* Returns the Piet color whose RGB value is the closest to the pixel. */
+fn closest_color(pixel: &[u8; 3]) -> PietColor {
+    use self::Hue::*;
+    use self::Lightness::*;
+    let mut colors = vec![];
+    for &hue in [Red, Yellow, Green, Cyan, Blue, Magenta].iter() {
+        for &lightness in [Light, Normal, Dark].iter() {
+            colors.push(PietColor { hue, lightness });
+        }
+    }
+    colors.push(PietColor { hue: White, lightness: Normal });
+    colors.push(PietColor { hue: Black, lightness: Normal });
+    let distance = |color: &PietColor| -> i64 {
+        let rgb = color.to_rgb();
+        (0..3).map(|i| (rgb[i] as i64 - pixel[i] as i64).pow(2)).sum()
+    };
+    *colors.iter().min_by_key(|color| distance(color)).unwrap()
+}
+
+/* Returns whether every codel_size x codel_size square of pixels has a single color. */
+fn is_uniform(pixels: &[Vec<[u8; 3]>], codel_size: usize) -> bool {
+    (0..pixels.len()).all(|y| {
+        (0..pixels[y].len()).all(|x| {
+            pixels[y][x] == pixels[y - y % codel_size][x - x % codel_size]
+        })
+    })
+}
+
+/* Pixels that aren't Piet colors or smoke read without syscalls, and how they are read. */
+fn check_colors(
+    pixels: &[Vec<[u8; 3]>],
+    picture: &[Vec<Codel>],
+    codel_size: usize,
+    syscalls: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for row in picture.iter() {
+        for codel in row.iter() {
+            let pixel = &pixels[codel.y * codel_size][codel.x * codel_size];
+            let position = Some((codel.x, codel.y));
+            match PietColor::from_rgb(pixel) {
+                None => {
+                    let message = format!(
+                        "{} isn't a Piet color, it is read as {} (the closest Piet color is {})",
+                        hex(pixel),
+                        describe(codel.color),
+                        describe(closest_color(pixel))
+                    );
+                    diagnostics.push(Diagnostic::warning(position, message));
+                },
+                Some(PietColor { hue: Hue::Smoke, .. }) if !syscalls => {
+                    let message = format!(
+                        "{} is smoke, which is read as {} as syscalls are disabled (-s enables \
+                         them)",
+                        hex(pixel),
+                        describe(codel.color)
+                    );
+                    diagnostics.push(Diagnostic::warning(position, message));
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/* Blocks the interpreter can't enter whatever the stack holds, and blocks it leaves towards the
+ * edge of the picture, making it retry with another cc or dp. The graph of the program follows
+ * every dp and cc that Pointer and Switch can give, so anything missing from it can't be reached.
+ * */
+fn check_flow(picture: &[Vec<Codel>], blocks: &Blocks, diagnostics: &mut Vec<Diagnostic>) {
+    let graph = build_graph(picture);
+    let mut reachable = vec![false; blocks.blocks.len()];
+    let mut edges = collections::BTreeSet::new();
+    for node in graph.nodes.iter() {
+        reachable[blocks.ids[node.codel.y][node.codel.x]] = true;
+        if node.codel.color.hue == Hue::White {
+            continue;
+        }
+        let (x, y) = (node.codel.x, node.codel.y);
+        let (_, farthest) = get_farthest_codel(picture, x, y, node.dp, node.cc);
+        let (dx, dy) = node.dp.to_vector();
+        let (x, y) = (farthest.x as isize + dx, farthest.y as isize + dy);
+        if y < 0 || y as usize >= picture.len() || x < 0 || x as usize >= picture[0].len() {
+            edges.insert((farthest.y, farthest.x, node.dp.name(), node.cc.name()));
+        }
+    }
+    for (y, x, dp, cc) in edges {
+        let message = format!(
+            "leaving this block with dp {} and cc {} runs into the edge of the picture, the \
+             interpreter retries with another direction",
+            dp,
+            cc
+        );
+        diagnostics.push(Diagnostic::warning(Some((x, y)), message));
+    }
+    for (id, block) in blocks.blocks.iter().enumerate() {
+        let (x, y) = block.codels[0];
+        let color = picture[y][x].color;
+        if reachable[id] || color.hue == Hue::White || color.hue == Hue::Black {
+            continue;
+        }
+        let size = block.codels.len();
+        let message = format!(
+            "this {} block of {} codel{} is unreachable from the start",
+            describe(color),
+            size,
+            if size == 1 { "" } else { "s" }
+        );
+        diagnostics.push(Diagnostic::warning(Some((x, y)), message));
+    }
+}
+
+/* White blocks of a single codel, which look like a gap between two blocks but make moves
+ * through them execute no instruction.
+ * */
+fn check_white_gaps(
+    picture: &[Vec<Codel>],
+    blocks: &Blocks,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for block in blocks.blocks.iter() {
+        let (x, y) = block.codels[0];
+        if block.codels.len() == 1 && picture[y][x].color.hue == Hue::White {
+            let message = String::from(
+                "single white codel, moving through it doesn't execute any instruction",
+            );
+            diagnostics.push(Diagnostic::warning(Some((x, y)), message));
+        }
+    }
+}
+
+/* Pictures whose size or codels don't match the codel size, and pictures that look drawn with
+ * larger codels than the ones they are read with.
+ * */
+fn check_codel_size(
+    pixels: &[Vec<[u8; 3]>],
+    codel_size: usize,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let (width, height) = (pixels[0].len(), pixels.len());
+    if width % codel_size != 0 || height % codel_size != 0 {
+        let message = format!(
+            "the picture is {}x{} pixels, which isn't a multiple of the codel size {}: the last \
+             {} columns and {} rows of pixels are ignored",
+            width,
+            height,
+            codel_size,
+            width % codel_size,
+            height % codel_size
+        );
+        diagnostics.push(Diagnostic::warning(None, message));
+    }
+    let (columns, rows) = (width / codel_size, height / codel_size);
+    let mut uniform = true;
+    for y in 0..rows {
+        for x in 0..columns {
+            let read = pixels[y * codel_size][x * codel_size];
+            let square = (y * codel_size..(y + 1) * codel_size)
+                .flat_map(|py| (x * codel_size..(x + 1) * codel_size).map(move |px| (px, py)));
+            let mut different = square.filter(|&(px, py)| pixels[py][px] != read);
+            if let Some((px, py)) = different.next() {
+                uniform = false;
+                let message = format!(
+                    "the pixel ({}, {}) is {} but this codel is read as {} with a codel size of \
+                     {}",
+                    px,
+                    py,
+                    hex(&pixels[py][px]),
+                    hex(&read),
+                    codel_size
+                );
+                diagnostics.push(Diagnostic::warning(Some((x, y)), message));
+            }
+        }
+    }
+    if !uniform {
+        return;
+    }
+    let larger = (2..cmp::min(columns, rows) + 1)
+        .rev()
+        .map(|factor| factor * codel_size)
+        .find(|&size| width % size == 0 && height % size == 0 && is_uniform(pixels, size));
+    if let Some(size) = larger {
+        let message = format!("the picture looks drawn with a codel size of {}, see -c", size);
+        diagnostics.push(Diagnostic { position: None, severity: "note", message });
+    }
+}
+
+/* Runs every check on a picture read with the given options.
+ * returns: The problems found, the ones about the whole picture first, then in reading order.
+ * */
+pub fn lint(
+    pixels: &[Vec<[u8; 3]>],
+    codel_size: usize,
+    default_color: PietColor,
+    syscalls: bool,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let picture = to_codels(pixels, codel_size, default_color, syscalls);
+    if picture.is_empty() || picture[0].is_empty() {
+        let message = format!("the picture has no codel with a codel size of {}", codel_size);
+        return vec![Diagnostic::warning(None, message)];
+    }
+    let blocks = get_blocks(&picture);
+    check_codel_size(pixels, codel_size, &mut diagnostics);
+    check_colors(pixels, &picture, codel_size, syscalls, &mut diagnostics);
+    check_flow(&picture, &blocks, &mut diagnostics);
+    check_white_gaps(&picture, &blocks, &mut diagnostics);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.position.map(|(x, y)| (y, x)));
+    diagnostics
+}
+
+/* returns: The status lint exits with, 1 if there are warnings and 0 if there are only notes. */
+pub fn exit_status(diagnostics: &[Diagnostic]) -> i32 {
+    if diagnostics.iter().any(|diagnostic| diagnostic.severity == "warning") { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 3] = [0xFF, 0x00, 0x00];
+    const BLUE: [u8; 3] = [0x00, 0x00, 0xFF];
+    const WHITE: [u8; 3] = [0xFF, 0xFF, 0xFF];
+    const BLACK: [u8; 3] = [0x00, 0x00, 0x00];
+
+    fn check(pixels: Vec<Vec<[u8; 3]>>, codel_size: usize, syscalls: bool) -> Vec<String> {
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        let diagnostics = lint(&pixels, codel_size, white, syscalls);
+        diagnostics.iter().map(|diagnostic| diagnostic.format("a.png")).collect()
+    }
+
+    fn has(diagnostics: &[String], expected: &str) -> bool {
+        diagnostics.iter().any(|diagnostic| diagnostic == expected)
+    }
+
+    #[test]
+    fn flags_colors_read_differently() {
+        let diagnostics = check(vec![vec![RED, [0x12, 0x34, 0x56], [0xC0, 0xC0, 0xC0]]], 1, false);
+        assert!(has(
+            &diagnostics,
+            "a.png:1:0: warning: #123456 isn't a Piet color, it is read as White (the closest \
+             Piet color is Black)"
+        ));
+        assert!(has(
+            &diagnostics,
+            "a.png:2:0: warning: #c0c0c0 is smoke, which is read as White as syscalls are \
+             disabled (-s enables them)"
+        ));
+        let diagnostics = check(vec![vec![RED, [0xC0, 0xC0, 0xC0]]], 1, true);
+        assert!(!diagnostics.iter().any(|diagnostic| diagnostic.contains("smoke")));
+    }
+
+    #[test]
+    fn flags_unreachable_blocks() {
+        let diagnostics = check(vec![vec![RED, BLACK, BLUE, BLUE]], 1, false);
+        assert!(has(
+            &diagnostics,
+            "a.png:2:0: warning: this Normal Blue block of 2 codels is unreachable from the start"
+        ));
+        let unreachable = diagnostics.iter().filter(|d| d.contains("unreachable")).count();
+        assert_eq!(unreachable, 1);
+    }
+
+    #[test]
+    fn flags_exits_into_the_edge() {
+        // Going right from the blue block runs into black, and the interpreter comes back to the
+        // red block going left, towards the edge
+        let diagnostics = check(vec![vec![RED, BLUE, BLACK]], 1, false);
+        assert_eq!(
+            diagnostics,
+            vec![
+                "a.png:0:0: warning: leaving this block with dp left and cc left runs into the \
+                 edge of the picture, the interpreter retries with another direction"
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_single_white_codels() {
+        let diagnostics = check(vec![vec![RED, WHITE, BLUE, WHITE, WHITE, RED]], 1, false);
+        let gaps: Vec<&String> = diagnostics.iter().filter(|d| d.contains("white")).collect();
+        assert_eq!(
+            gaps,
+            vec![
+                "a.png:1:0: warning: single white codel, moving through it doesn't execute any \
+                 instruction"
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_the_codel_size() {
+        let diagnostics = check(vec![vec![RED, RED, BLUE], vec![RED, RED, BLUE]], 2, false);
+        assert!(has(
+            &diagnostics,
+            "a.png: warning: the picture is 3x2 pixels, which isn't a multiple of the codel size \
+             2: the last 1 columns and 0 rows of pixels are ignored"
+        ));
+
+        let diagnostics = check(vec![vec![RED, RED], vec![RED, BLUE]], 2, false);
+        assert!(has(
+            &diagnostics,
+            "a.png:0:0: warning: the pixel (1, 1) is #0000ff but this codel is read as #ff0000 \
+             with a codel size of 2"
+        ));
+
+        let doubled = vec![vec![RED, RED, BLUE, BLUE], vec![RED, RED, BLUE, BLUE]];
+        assert_eq!(
+            check(doubled.clone(), 1, false)[0],
+            "a.png: note: the picture looks drawn with a codel size of 2, see -c"
+        );
+        let diagnostics = check(doubled, 2, false);
+        assert!(diagnostics.iter().all(|diagnostic| !diagnostic.contains("codel size")));
+    }
+
+    #[test]
+    fn fails_on_warnings() {
+        let note = Diagnostic { position: None, severity: "note", message: String::new() };
+        let warning = Diagnostic::warning(Some((0, 0)), String::new());
+        assert_eq!(exit_status(&[]), 0);
+        assert_eq!(exit_status(&[note]), 0);
+        assert_eq!(exit_status(&[warning]), 1);
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        assert_eq!(exit_status(&lint(&[vec![RED, [0x12, 0x34, 0x56]]], 1, white, false)), 1);
+    }
+}
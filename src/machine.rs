@@ -0,0 +1,392 @@
+use instruction::*;
+use session::*;
+use std::*;
+
+use std::io::prelude::*;
+
+/* Keeps what is written to it in memory, so that the output of a program can be sent elsewhere
+ * by the debugging servers and the display.
+ * */
+#[derive(Clone)]
+pub struct SharedBuffer(rc::Rc<cell::RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> SharedBuffer {
+        SharedBuffer(rc::Rc::new(cell::RefCell::new(vec![])))
+    }
+
+    /* Returns what was written since the last call, as text. */
+    pub fn take(&self) -> String {
+        let text = String::from_utf8_lossy(&self.0.borrow()).into_owned();
+        self.0.borrow_mut().clear();
+        text
+    }
+}
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* Returns the message of a panic caught with catch_unwind. Some instructions crash on bad input,
+ * such as InNumber reading something that isn't a number, which servers report as errors rather
+ * than stopping.
+ * */
+pub fn crash_message(payload: &Box<dyn any::Any + Send>) -> String {
+    match payload.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => payload.downcast_ref::<&str>().map_or(String::new(), |m| m.to_string()),
+    }
+}
+
+/* Writes to `inner`, keeping a copy of the bytes that went through in `copy`. */
+pub struct Tee<T> {
+    inner: T,
+    copy: SharedBuffer,
+}
+
+impl<T> Tee<T> {
+    pub fn new(inner: T, copy: SharedBuffer) -> Tee<T> {
+        Tee { inner, copy }
+    }
+}
+
+impl<T: io::Write> io::Write for Tee<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.copy.0.borrow_mut().extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/* The state of a running Piet program that doesn't depend on its position in the picture: the
+ * Piet stack, the input and the output. Every way of running a program goes through
+ * Machine::execute so that the instructions behave the same everywhere.
+ * */
+pub struct Machine {
+    pub stack: Vec<i64>,
+    input: Box<dyn io::Read>,
+    output: Box<dyn io::Write>,
+    debug: bool,
+    /* Records what the program reads and writes, or replays it. */
+    pub session: Option<Session>,
+    /* The number of reads, writes and syscalls performed so far. */
+    pub io_count: u64,
+    /* Receives a copy of what the program reads, whether it comes from the input or from a
+     * replayed session.
+     * */
+    pub read_copy: Option<SharedBuffer>,
+}
+
+impl Machine {
+    /* Returns a machine reading from stdin and writing to stdout. */
+    pub fn new(debug: bool) -> Machine {
+        Machine::with_io(debug, Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+
+    pub fn with_io(debug: bool, input: Box<dyn io::Read>, output: Box<dyn io::Write>) -> Machine {
+        Machine {
+            stack: Vec::new(),
+            input,
+            output,
+            debug,
+            session: None,
+            io_count: 0,
+            read_copy: None,
+        }
+    }
+
+    fn copy_read(&self, bytes: &[u8]) {
+        if let Some(ref copy) = self.read_copy {
+            copy.0.borrow_mut().extend_from_slice(bytes);
+        }
+    }
+
+    /* Calls `f` with the session, returns None if there is none. */
+    fn with_session<T, F: FnOnce(&mut Session, &mut Machine) -> T>(&mut self, f: F) -> Option<T> {
+        let mut session = self.session.take()?;
+        let result = f(&mut session, self);
+        self.session = Some(session);
+        Some(result)
+    }
+
+    /* Ends the session, which has to be done once the program stopped, before exiting.
+     * finished: Whether the program terminated rather than being stopped early.
+     * */
+    pub fn end_session(&mut self, finished: bool) {
+        if let Some(ref mut session) = self.session {
+            session.end(finished);
+        }
+    }
+
+    /* Reads a single byte of input, None at the end of the input or on errors. */
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn read_char(&mut self) -> Option<u8> {
+        // Reading again after the end of the input lets terminals send more input
+        self.read_byte().or_else(|| self.read_byte())
+    }
+
+    /* Reads input up to the end of the line, which is included in the result. */
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = vec![];
+        let mut byte = [0];
+        while self.input.read(&mut byte)? == 1 {
+            line.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    /* Performs the syscall described at the top of the stack. */
+    fn syscall(&mut self) {
+        if let Some(syscall_num) = self.stack.pop() {
+            let mut syscall_num = syscall_num;
+            let mut syscall_args = vec![];
+            if let Some(arg_count) = self.stack.pop() {
+                let stack_addr = &self.stack[0] as *const i64 as u64;
+                for _ in 0..arg_count {
+                    if let (Some(arg_type), Some(arg)) = (self.stack.pop(), self.stack.pop()) {
+                        match arg_type {
+                            1 => syscall_args.push(arg),
+                            2 => syscall_args.push(if arg < 0 { (stack_addr - arg.abs() as u64) as i64 } else { (stack_addr + arg as u64) as i64 }),
+                            _ => writeln!(self.output, "Bad arg_type!").unwrap(),
+                        }
+                    }
+                }
+            }
+            unsafe {
+            let result = match syscall_args.len() {
+                #[allow(deprecated)]
+                0 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          :
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                1 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                2 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                3 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                4 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                5 => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3]) "{r8}"(syscall_args[4])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+                #[allow(deprecated)]
+                _ => { llvm_asm!("syscall"
+                          : "+{rax}"(syscall_num)
+                          : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3]) "{r8}"(syscall_args[4])"{r9}"(syscall_args[5])
+                          : "rcx", "r11", "memory"
+                          : "volatile"); syscall_num },
+            };
+            self.stack.push(result);
+            }
+        }
+    }
+
+    /* Executes an instruction on the Piet stack.
+     * value: The size of the block that is left, pushed by Push.
+     * returns: The number of times dp has to be rotated for Pointer, or cc has to be toggled for
+     * Switch, 0 otherwise.
+     * */
+    pub fn execute(&mut self, instruction: Instruction, value: i64) -> usize {
+        match instruction {
+            Instruction::Push => self.stack.push(value),
+            Instruction::Pop => {
+                self.stack.pop();
+            },
+            Instruction::Add => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push(b + a);
+                }
+            },
+            Instruction::Sub => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push(b - a);
+                }
+            },
+            Instruction::Mul => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push(b * a);
+                }
+            },
+            Instruction::Div => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push(b / a);
+                }
+            },
+            Instruction::Mod => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push((b % a) + a);
+                }
+            },
+            Instruction::Not => {
+                if let Some(val) = self.stack.pop() {
+                    self.stack.push(if val == 0 { 1 } else { 0 })
+                }
+            },
+            Instruction::Greater => {
+                if let (Some(a), Some(b)) = (self.stack.pop(), self.stack.pop()) {
+                    self.stack.push(if b > a { 1 } else { 0 })
+                }
+            },
+            Instruction::Pointer => {
+                if let Some(a) = self.stack.pop() {
+                    if a > 0 {
+                        return (a % 4) as usize;
+                    }
+                }
+            },
+            Instruction::Switch => {
+                if let Some(a) = self.stack.pop() {
+                    if a > 0 {
+                        return (a % 2) as usize;
+                    }
+                }
+            },
+            Instruction::Duplicate => {
+                if let Some(a) = self.stack.pop() {
+                    self.stack.push(a);
+                    self.stack.push(a);
+                }
+            },
+            Instruction::Roll => {
+                if let (Some(count), Some(depth)) = (self.stack.pop(), self.stack.pop()) {
+                    let mut before: Vec<_> = self.stack
+                        .iter()
+                        .take(self.stack.len() - (depth as usize))
+                        .map(|e| *e)
+                        .collect();
+                    if count >= 0 {
+                        let mut rolled: Vec<_> = self.stack
+                            .iter()
+                            .skip(self.stack.len() - (count as usize))
+                            .map(|e| *e)
+                            .collect();
+                        let mut after: Vec<_> = self.stack
+                            .iter()
+                            .skip(self.stack.len() - (depth as usize))
+                            .take((depth - count) as usize)
+                            .map(|e| *e)
+                            .collect();
+                        before.append(&mut rolled);
+                        before.append(&mut after);
+                    } else {
+                        let mut rolled: Vec<_> = self.stack
+                            .iter()
+                            .skip(before.len())
+                            .take(count.abs() as usize)
+                            .map(|e| *e)
+                            .collect();
+                        let mut after: Vec<_> = self.stack
+                            .iter()
+                            .skip(before.len() + rolled.len())
+                            .map(|e| *e)
+                            .collect();
+                        before.append(&mut after);
+                        while let Some(v) = rolled.pop() {
+                            before.push(v);
+                        }
+                    }
+                    self.stack = before;
+                }
+            },
+            Instruction::InNumber => {
+                self.io_count += 1;
+                let line = self.with_session(|session, m| session.input_line(|| m.read_line()));
+                match line.unwrap_or_else(|| self.read_line()) {
+                    Ok(input) => {
+                        self.copy_read(input.as_bytes());
+                        self.stack.push(input.trim_end().parse().unwrap())
+                    },
+                    Err(error) => {
+                        if self.debug {
+                            panic!("{}", error)
+                        }
+                    },
+                }
+            },
+            Instruction::InChar => {
+                self.io_count += 1;
+                let char = self.with_session(|session, m| session.input_char(|| m.read_char()));
+                if let Some(char) = char.unwrap_or_else(|| self.read_char()) {
+                    self.copy_read(&[char]);
+                    self.stack.push(char as i64);
+                }
+            },
+            Instruction::OutNumber => {
+                if let Some(val) = self.stack.pop() {
+                    self.io_count += 1;
+                    self.with_session(|session, _| session.output(&val.to_string()));
+                    write!(self.output, "{}", val).unwrap();
+                }
+            },
+            Instruction::OutChar => {
+                if let Some(val) = self.stack.pop() {
+                    if let Some(val) = char::from_u32(val as u32) {
+                        self.io_count += 1;
+                        self.with_session(|session, _| session.output(&val.to_string()));
+                        write!(self.output, "{}", val).unwrap();
+                    }
+                }
+            },
+            Instruction::Syscall => {
+                self.io_count += 1;
+                writeln!(self.output, "syscall!").unwrap();
+                // Replayed syscalls aren't performed, the stack they left is restored instead
+                let stack = self.stack.clone();
+                let replayed = self.with_session(|session, machine| {
+                    session.syscall(&stack, || {
+                        machine.syscall();
+                        machine.stack.clone()
+                    })
+                });
+                match replayed {
+                    Some(stack) => self.stack = stack,
+                    None => self.syscall(),
+                }
+            },
+            Instruction::Nop => {},
+        }
+        0
+    }
+}
@@ -2,194 +2,271 @@
 
 mod pietcolor;
 mod codel;
+mod instruction;
+mod picture;
+mod graph;
+mod machine;
+mod interpreter;
+mod history;
+mod debugger;
+mod dap;
+mod gdbserver;
+mod optimizer;
+mod wat;
+mod numbers;
+mod generator;
+mod trace;
+mod session;
+mod cycles;
+mod profile;
+mod coverage;
+mod lint;
+mod render;
+mod observer;
+mod serve;
 
 use pietcolor::*;
 use codel::*;
+use picture::*;
+use graph::*;
+use machine::*;
+use interpreter::*;
+use debugger::*;
+use history::*;
+use dap::*;
+use gdbserver::*;
+use optimizer::*;
+use numbers::*;
+use generator::*;
+use trace::*;
+use session::*;
+use cycles::*;
+use profile::*;
+use coverage::*;
+use lint::*;
+use render::*;
+use observer::*;
+use serve::*;
 use std::*;
 
 use std::io::prelude::*;
 
 extern crate png;
+extern crate gif;
 extern crate getopts;
+#[macro_use]
+extern crate serde_json;
 
-#[cfg(feature = "default")]
+#[cfg(feature = "tui")]
 use display::*;
-#[cfg(feature = "default")]
+#[cfg(feature = "tui")]
 mod display;
-#[cfg(feature = "default")]
+#[cfg(feature = "tui")]
 extern crate termion;
 
-/* Fetches the farthest codel belonging to the same block as the codel at position x, y
- * picture: A matrix of Codels corresponding to the piet program.
- * x: The starting position within the matrix on the x axis
- * y: The starting position within the matriy on the y ayis
- * dp: The global direction pointer
- * cc: The global codel chooser
- * returns: An (usize, &Codel) tuple. For non-white blocks, the usize corresponds to the size of
- * the block, the &Codel is the farthest codel that belongs to the same block as the starting
- * position according to the direction pointer and cc.
- * For white blocks the usize is 0 and the codel is the farthest white codel in a straight line
- * in dp's direction.  For other blocks, this is basically a depth-first search implemented using
- * a stack instead of recursion.
+/* Compiles the picture to the language given by the --target option and writes the result to the
+ * file given by --output, or to stdout.
  * */
-fn get_farthest_codel(
-    picture: &Vec<Vec<Codel>>,
-    x: usize,
-    y: usize,
-    dp: Direction,
-    cc: Direction,
-) -> (usize, &Codel) {
-    // White blocks have a different algorithm
-    if picture[y][x].color.hue == Hue::White {
-        let (mut x, mut y) = (x as isize, y as isize);
-        let (tmpx, tmpy) = dp.to_vector();
-        while y >= 0 && y < picture.len() as isize && x >= 0 &&
-            x < picture[y as usize].len() as isize &&
-            picture[y as usize][x as usize].color.hue == Hue::White
-        {
-            y += tmpy;
-            x += tmpx;
-        }
-        y -= tmpy;
-        x -= tmpx;
-        return (0, &picture[y as usize][x as usize]);
+fn compile(matches: &getopts::Matches, picture: &[Vec<Codel>]) {
+    let target = matches.opt_str("target").unwrap_or(String::from("wat"));
+    let graph = build_graph(picture);
+    let program = if matches.opt_present("no-optimize") { lower(&graph) } else { optimize(&graph) };
+    let result = match target.as_str() {
+        "wat" => wat::compile(&graph, &program),
+        _ => Err(format!("Error: unknown compilation target {}.", target)),
+    };
+    let code = match result {
+        Ok(code) => code,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    };
+    let written = match matches.opt_str("o") {
+        Some(path) => fs::File::create(path).and_then(|mut f| f.write_all(code.as_bytes())),
+        None => io::stdout().write_all(code.as_bytes()),
+    };
+    if let Err(e) = written {
+        println!("Error: {}", e);
+        process::exit(1);
     }
+}
 
-    let mut codels_to_visit = vec![(x, y); 1];
-    let mut visited_codels = collections::HashSet::new();
+/* Returns the value of a numeric option, exiting if it isn't a number of at least `min`. */
+fn number_option(matches: &getopts::Matches, name: &str, default: i64, min: i64) -> i64 {
+    match matches.opt_str(name).map(|s| s.parse::<i64>()) {
+        None => default,
+        Some(Ok(num)) if num >= min => num,
+        _ => {
+            println!("Error: {} has to be a number greater than {}.", name, min - 1);
+            process::exit(1);
+        },
+    }
+}
 
-    let mut result = &picture[y][x];
-    while codels_to_visit.len() > 0 {
-        // Get the last codel added to the codel stack
-        let (tmpx, tmpy) = codels_to_visit.pop().unwrap();
-        // Mark it as visited
-        visited_codels.insert(&picture[tmpy][tmpx]);
-        // If the codel under the current codel is within our matrix bounds, its color matches the
-        // starting color and it hasn't been visited yet
-        if tmpy + 1 < picture.len() && &picture[tmpy + 1][tmpx].color == &picture[y][x].color &&
-            !visited_codels.contains(&picture[tmpy + 1][tmpx])
-        {
-            // Add it to the list of codels that need to be visited
-            codels_to_visit.push((tmpx, tmpy + 1));
-        }
-        // Same thing
-        if tmpy > 0 && &picture[tmpy - 1][tmpx].color == &picture[y][x].color &&
-            !visited_codels.contains(&picture[tmpy - 1][tmpx])
-        {
-            codels_to_visit.push((tmpx, tmpy - 1));
-        }
-        // Same thing
-        if tmpx + 1 < picture[tmpy].len() &&
-            &picture[tmpy][tmpx + 1].color == &picture[y][x].color &&
-            !visited_codels.contains(&picture[tmpy][tmpx + 1])
-        {
-            codels_to_visit.push((tmpx + 1, tmpy));
-        }
-        // Same thing
-        if tmpx > 0 && &picture[tmpy][tmpx - 1].color == &picture[y][x].color &&
-            !visited_codels.contains(&picture[tmpy][tmpx - 1])
-        {
-            codels_to_visit.push((tmpx - 1, tmpy));
-        }
-        result = result.compare_to(&picture[tmpy][tmpx], dp, cc);
+/* Writes a picture printing `text` to the file given by --output, or to stdout. */
+fn generate_print(matches: &getopts::Matches, text: &str, codel_size: usize) {
+    let mut synthesizer = Synthesizer::new(CostModel {
+        max_push: number_option(matches, "max-push", 12, 1),
+        codel_cost: 1,
+        instruction_cost: number_option(matches, "instruction-cost", 0, 0),
+    });
+    let colors = layout(&print_program(text, &mut synthesizer));
+    let written = match matches.opt_str("o") {
+        Some(path) => fs::File::create(path).and_then(|f| write_picture(f, &colors, codel_size)),
+        None => write_picture(io::stdout(), &colors, codel_size),
+    };
+    if let Err(e) = written {
+        println!("Error: {}", e);
+        process::exit(1);
     }
+}
 
-    return (visited_codels.len(), result);
+/* Returns the session given by --record or --replay, exiting if it can't be opened. */
+fn open_session(matches: &getopts::Matches) -> Option<Session> {
+    let session = match (matches.opt_str("record"), matches.opt_str("replay")) {
+        (None, None) => return None,
+        (Some(path), None) => Session::record(&path).map_err(|e| format!("Error: {}", e)),
+        (None, Some(path)) => Session::replay(&path),
+        (Some(_), Some(_)) => Err(String::from("Error: --record and --replay are exclusive.")),
+    };
+    match session {
+        Ok(session) => Some(session),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    }
 }
 
-/* Checks whether there is a non-white, non-black codel aligned with the current Codel in the
- * direction of the dp within the picture.
- * returns: A (bool, bool, &Codel) tuple. The first boolean is true if there is an available
- * codel in the direction of the direction pointer, false otherwise. The second bool indicates
- * whether the algorithm encountered white blocks between the current codel and the newly found
- * codel.  The returned Codel is the new codel when the bool is true and the starting codel when
- * the bool is false.
+/* Prints a summary of the coverage saved in `path` and writes the picture with the blocks that
+ * weren't covered highlighted to the file given by --output.
  * */
-fn can_go_in_direction<'a>(
-    picture: &'a Vec<Vec<Codel>>,
-    cur_codel: &'a Codel,
-    dp: Direction,
-) -> (bool, &'a Codel) {
-    let (mut tmpx, mut tmpy) = (cur_codel.x as isize, cur_codel.y as isize);
-    let vec = dp.to_vector();
-    tmpx += vec.0;
-    tmpy += vec.1;
-
-    // If we're not within the matrix's bounds or if the codel is black, we can't go in this
-    // direction
-    if tmpy < 0 || tmpy as usize >= picture.len() || tmpx < 0 ||
-        (tmpx as usize) >= picture[(tmpy as usize)].len() ||
-        picture[tmpy as usize][tmpx as usize].color.hue == Hue::Black
-    {
-        return (false, cur_codel);
+fn report_coverage(
+    matches: &getopts::Matches,
+    picture: &[Vec<Codel>],
+    path: &str,
+    codel_size: usize,
+) {
+    let coverage = match Coverage::load(path, picture) {
+        Ok(coverage) => coverage,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    };
+    print!("{}", coverage.report(picture));
+    if let Some(output) = matches.opt_str("o") {
+        let pixels = coverage.render(picture);
+        let written = fs::File::create(output).and_then(|f| write_pixels(f, &pixels, codel_size));
+        if let Err(e) = written {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
     }
-    // Return the found codel
-    return (true, &picture[tmpy as usize][tmpx as usize]);
 }
 
-fn get_picture(
-    filename: &string::String,
-    codel_size: usize,
-    default_color: PietColor,
-    syscalls_enabled: bool,
-) -> Vec<Vec<Codel>> {
-    let decoder = png::Decoder::new(fs::File::open(filename).unwrap());
-    let (info, mut reader) = decoder.read_info().unwrap();
-    let mut buffer = vec![0; info.buffer_size()];
-    reader.next_frame(&mut buffer).unwrap();
+/* Prints the problems found in a picture as compiler diagnostics, and exits with status 1 if
+ * there are warnings.
+ * */
+fn run_lint(filename: &str, codel_size: usize, default_color: PietColor, syscalls: bool) {
+    let pixels = match read_pixels(filename) {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        },
+    };
+    let diagnostics = lint(&pixels, codel_size, default_color, syscalls);
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic.format(filename));
+    }
+    process::exit(exit_status(&diagnostics));
+}
 
-    let values_per_pixel;
-    match info.color_type {
-        png::ColorType::RGBA => values_per_pixel = 4,
-        png::ColorType::RGB => values_per_pixel = 3,
-        _ => panic!("PNG picture is neither RGB or RGBA"),
+/* Creates the observer --viewer asks for. */
+#[cfg_attr(not(feature = "tui"), allow(unused_variables))]
+fn create_observer(matches: &getopts::Matches, picture: &[Vec<Codel>]) -> Box<dyn Observer> {
+    let default = if cfg!(feature = "tui") { "tui" } else { "log" };
+    let name = matches.opt_str("viewer").unwrap_or(String::from(default));
+    match name.as_str() {
+        #[cfg(feature = "tui")]
+        "tui" => {
+            let name = matches.opt_str("color-mode").unwrap_or(String::from("256"));
+            let mode = ColorMode::from_name(&name).unwrap_or_else(|| {
+                println!("Error: color-mode has to be 256, truecolor or 16.");
+                process::exit(1);
+            });
+            let trail = number_option(matches, "trail", 8, 0) as usize;
+            Box::new(setup_display(picture.to_vec(), mode, trail))
+        },
+        "log" => Box::new(LineLogger::new(Box::new(io::stderr()))),
+        _ => {
+            println!("Error: viewer has to be {}.", if cfg!(feature = "tui") {
+                "tui or log"
+            } else {
+                "log, tui needs the tui feature"
+            });
+            process::exit(1);
+        },
     }
-    let pic_width = info.width as usize;
-    let pic_height = info.height as usize;
+}
 
-    let mut picture: Vec<Vec<Codel>> =
-        vec![
-            vec![Codel { color: default_color.clone(), x: 0, y: 0 }; pic_width / codel_size];
-            pic_height / codel_size
-        ];
-    let mut i: isize = -1;
-    let syscall_codel = if syscalls_enabled { PietColor { hue: Hue::Smoke, lightness: Lightness::Normal } } else { default_color.clone() };
-    for pixel in buffer.chunks(values_per_pixel) {
-        i += 1;
-        let (mut x, mut y) = ((i as usize) % pic_width, (i as usize) / pic_width);
-        if x % codel_size != 0 || y % codel_size != 0 {
-            continue;
+/* Runs the program without a display, drawing its state every --steps-per-frame steps to the GIF
+ * or directory of PNG pictures given by --trace-video.
+ * */
+fn render_execution(matches: &getopts::Matches, picture: &[Vec<Codel>], limits: &Limits) {
+    let path = match matches.opt_str("trace-video") {
+        Some(path) => path,
+        None => {
+            println!("Error: render needs --trace-video to know where to draw the frames.");
+            process::exit(1);
+        },
+    };
+    let steps_per_frame = number_option(matches, "steps-per-frame", 1, 1) as u64;
+    let renderer = Renderer::new(picture, number_option(matches, "frame-scale", 16, 1) as usize);
+    let mut video = match Video::create(&path, &renderer) {
+        Ok(video) => video,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        },
+    };
+    let mut machine = Machine::new(false);
+    machine.session = open_session(matches);
+    let mut interpreter = Interpreter::new(picture, machine);
+    let started = time::Instant::now();
+    let mut interrupted = None;
+    let mut written = video.write(&renderer, renderer.frame(&interpreter));
+    // The number of steps performed when the last frame was drawn
+    let mut drawn = interpreter.steps;
+    loop {
+        if let Some(limit) = limits.reached(interpreter.steps, started) {
+            interrupted = Some(format!("Error: {}, at {}.", limit, interpreter.state()));
+            break;
+        }
+        if interpreter.step().is_none() {
+            break;
+        }
+        if interpreter.steps - drawn == steps_per_frame {
+            written = written.and_then(|_| video.write(&renderer, renderer.frame(&interpreter)));
+            drawn = interpreter.steps;
         }
-        x = x / codel_size;
-        y = y / codel_size;
-        picture[y][x].x = x;
-        picture[y][x].y = y;
-        picture[y][x].color = match &pixel[0..3] {
-            &[0xFF, 0xC0, 0xC0] => PietColor { hue: Hue::Red, lightness: Lightness::Light },
-            &[0xFF, 0x00, 0x00] => PietColor { hue: Hue::Red, lightness: Lightness::Normal },
-            &[0xC0, 0x00, 0x00] => PietColor { hue: Hue::Red, lightness: Lightness::Dark },
-            &[0xFF, 0xFF, 0xC0] => PietColor { hue: Hue::Yellow, lightness: Lightness::Light },
-            &[0xFF, 0xFF, 0x00] => PietColor { hue: Hue::Yellow, lightness: Lightness::Normal },
-            &[0xC0, 0xC0, 0x00] => PietColor { hue: Hue::Yellow, lightness: Lightness::Dark },
-            &[0xC0, 0xFF, 0xC0] => PietColor { hue: Hue::Green, lightness: Lightness::Light },
-            &[0x00, 0xFF, 0x00] => PietColor { hue: Hue::Green, lightness: Lightness::Normal },
-            &[0x00, 0xC0, 0x00] => PietColor { hue: Hue::Green, lightness: Lightness::Dark },
-            &[0xC0, 0xFF, 0xFF] => PietColor { hue: Hue::Cyan, lightness: Lightness::Light },
-            &[0x00, 0xFF, 0xFF] => PietColor { hue: Hue::Cyan, lightness: Lightness::Normal },
-            &[0x00, 0xC0, 0xC0] => PietColor { hue: Hue::Cyan, lightness: Lightness::Dark },
-            &[0xC0, 0xC0, 0xFF] => PietColor { hue: Hue::Blue, lightness: Lightness::Light },
-            &[0x00, 0x00, 0xFF] => PietColor { hue: Hue::Blue, lightness: Lightness::Normal },
-            &[0x00, 0x00, 0xC0] => PietColor { hue: Hue::Blue, lightness: Lightness::Dark },
-            &[0xFF, 0xC0, 0xFF] => PietColor { hue: Hue::Magenta, lightness: Lightness::Light },
-            &[0xFF, 0x00, 0xFF] => PietColor { hue: Hue::Magenta, lightness: Lightness::Normal },
-            &[0xC0, 0x00, 0xC0] => PietColor { hue: Hue::Magenta, lightness: Lightness::Dark },
-            &[0x00, 0x00, 0x00] => PietColor { hue: Hue::Black, lightness: Lightness::Normal },
-            &[0xFF, 0xFF, 0xFF] => PietColor { hue: Hue::White, lightness: Lightness::Normal },
-            &[0xC0, 0xC0, 0xC0] => syscall_codel.clone(),
-            _ => default_color.clone(),
-        };
     }
-    return picture;
+    interpreter.machine.end_session(interrupted.is_none());
+    // The final state is always drawn
+    if interpreter.steps != drawn {
+        written = written.and_then(|_| video.write(&renderer, renderer.frame(&interpreter)));
+    }
+    if let Err(e) = written.and_then(|_| video.finish(&renderer)) {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+    if let Some(message) = interrupted {
+        io::stdout().flush().ok();
+        eprintln!("{}", message);
+        process::exit(LIMIT_REACHED);
+    }
 }
 
 fn main() {
@@ -201,10 +278,65 @@ fn main() {
     opts.optopt("c", "codel_size", "Number of pixels per codels. Default: 1", "2");
     opts.optflag("d", "debug", "Use debug mode.");
     opts.optflag("s", "syscalls", "Enable the syscall instruction (color #C0C0C0)");
-    #[cfg(feature = "default")]
+    opts.optflag("v", "view", "Display the program being run.");
+    opts.optopt(
+        "",
+        "viewer",
+        "How --view displays the program: tui or log. Default: tui, log without the tui feature",
+        "NAME",
+    );
+    #[cfg(feature = "tui")]
     {
-        opts.optflag("v", "view", "Display the program being run.");
+        opts.optopt(
+            "",
+            "color-mode",
+            "Colors the display uses: 256, truecolor or 16. Default: 256",
+            "MODE",
+        );
+        opts.optopt("", "trail", "Number of moves the display draws the path of. Default: 8", "N");
     }
+    opts.optopt("", "target", "Language the compile command outputs. Default: wat", "wat");
+    opts.optopt(
+        "o",
+        "output",
+        "File compile and gen write to, and coverage report draws to. Default: stdout",
+        "FILE",
+    );
+    opts.optopt("", "max-push", "Largest number gen pushes with a single block. Default: 12", "N");
+    opts.optopt(
+        "",
+        "instruction-cost",
+        "Number of codels gen spends to save an instruction. Default: 0",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "history",
+        "Megabytes of memory debug and gdbserver use to undo steps. Default: 64",
+        "MB",
+    );
+    opts.optopt(
+        "",
+        "checkpoints",
+        "Number of steps between the copies of the stack the history keeps. Default: 1000",
+        "N",
+    );
+    opts.optflag("", "no-optimize", "Run or compile the program without optimizing it.");
+    opts.optopt("", "trace", "Write a JSON Lines trace of the steps to FILE.", "FILE");
+    opts.optopt("", "record", "Record the input and syscall results of the run to FILE.", "FILE");
+    opts.optopt("", "replay", "Run the program again with the input recorded in FILE.", "FILE");
+    opts.optopt("", "max-steps", "Stop the program after N steps.", "N");
+    opts.optopt("", "timeout", "Stop the program after SECS seconds.", "SECS");
+    opts.optflag("", "detect-loops", "Stop programs stuck in a loop that doesn't do I/O.");
+    opts.optflag("", "profile", "Print the most visited blocks and executed instructions.");
+    opts.optopt("", "profile-top", "Number of rows of the profile tables. Default: 10", "N");
+    opts.optopt("", "heatmap", "Write a picture of the visits of each block to FILE.", "FILE");
+    opts.optopt("", "coverage", "Add the blocks and exits the run covers to FILE.", "FILE");
+    opts.optopt("", "trace-stack", "Number of stack values written per step. Default: all", "N");
+    opts.optopt("", "trace-video", "GIF, or directory of PNG frames, render draws to.", "FILE");
+    opts.optopt("", "steps-per-frame", "Steps between the frames of render. Default: 1", "N");
+    opts.optopt("", "frame-scale", "Pixels per codel in the frames of render. Default: 16", "N");
+    opts.optopt("", "port", "Port serve listens on, on localhost. Default: 8080", "N");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(e) => {
@@ -213,10 +345,40 @@ fn main() {
         },
     };
 
-    if matches.free.len() != 1 {
-        let brief = format!("Usage: {} file.png [options]", args[0]);
-        print!("{}", opts.usage(&brief));
-        process::exit(1);
+    let free: Vec<&str> = matches.free.iter().map(|s| s.as_str()).collect();
+    let (command, argument) = match free.as_slice() {
+        ["dap"] => ("dap", ""),
+        [filename] => ("run", *filename),
+        ["compile", filename] => ("compile", *filename),
+        ["lint", filename] => ("lint", *filename),
+        ["render", filename] => ("render", *filename),
+        ["serve", filename] => ("serve", *filename),
+        ["debug", filename] => ("debug", *filename),
+        ["gdbserver", _, filename] => ("gdbserver", *filename),
+        ["gen", "print", text] => ("gen", *text),
+        ["coverage", "report", filename, _] => ("coverage", *filename),
+        _ => {
+            let usages = [
+                "[compile|debug] file.png [options]",
+                "gdbserver [host]:port|- file.png [options]",
+                "coverage report file.png coverage.json [options]",
+                "lint file.png [options]",
+                "render file.png --trace-video out.gif|directory [options]",
+                "serve file.png [--port N] [options]",
+                "gen print text [options]",
+                "dap",
+            ];
+            let lines: Vec<String> =
+                usages.iter().map(|usage| format!("{} {}", args[0], usage)).collect();
+            let brief = format!("Usage: {}", lines.join("\n       "));
+            print!("{}", opts.usage(&brief));
+            process::exit(1);
+        },
+    };
+
+    if command == "dap" {
+        run_dap();
+        return;
     }
 
     let debug = matches.opt_present("d");
@@ -230,321 +392,240 @@ fn main() {
         }
     }
 
+    if command == "gen" {
+        generate_print(&matches, argument, codel_size);
+        return;
+    }
+
     let default_color = if matches.opt_present("b") {
         PietColor { hue: Hue::Black, lightness: Lightness::Normal }
     } else {
         PietColor { hue: Hue::White, lightness: Lightness::Normal }
     };
 
-    let picture = get_picture(&matches.free[0], codel_size, default_color, matches.opt_present("s"));
-    let mut cur_codel = picture[0][0].clone();
-    let mut piet_stack: Vec<i64> = Vec::new();
-    let mut dp = Direction::Right;
-    let mut cc = Direction::Left;
-    let mut chars = io::stdin().bytes();
+    let syscalls = matches.opt_present("s");
+    if command == "lint" {
+        run_lint(argument, codel_size, default_color, syscalls);
+        return;
+    }
+    let picture = get_picture(&argument.to_string(), codel_size, default_color, syscalls);
+    if command == "compile" {
+        compile(&matches, &picture);
+        return;
+    }
+    if command == "coverage" {
+        report_coverage(&matches, &picture, free[3], codel_size);
+        return;
+    }
+    let history = HistorySize {
+        memory: (number_option(&matches, "history", 64, 0) as usize) << 20,
+        interval: number_option(&matches, "checkpoints", 1000, 1) as usize,
+    };
+    if command == "debug" {
+        let mut machine = Machine::new(false);
+        machine.session = open_session(&matches);
+        run_debugger(&picture, machine, history);
+        return;
+    }
+    if command == "gdbserver" {
+        run_gdbserver(&picture, free[1], history);
+        return;
+    }
+    if command == "serve" {
+        let port = number_option(&matches, "port", 8080, 0);
+        if port > u16::MAX as i64 {
+            println!("Error: port has to be a number lower than {}.", u16::MAX as u32 + 1);
+            process::exit(1);
+        }
+        run_server(&picture, port as u16);
+        return;
+    }
+
+    let limits = Limits {
+        max_steps: matches
+            .opt_str("max-steps")
+            .map(|_| number_option(&matches, "max-steps", 0, 0) as u64),
+        timeout: match matches.opt_str("timeout").map(|s| s.parse::<f64>()) {
+            None => None,
+            Some(Ok(secs)) if secs > 0.0 && secs.is_finite() => {
+                Some(time::Duration::from_secs_f64(secs))
+            },
+            _ => {
+                println!("Error: timeout has to be a positive number of seconds.");
+                process::exit(1);
+            },
+        },
+    };
+    if command == "render" {
+        render_execution(&matches, &picture, &limits);
+        return;
+    }
+    let stack_limit = matches
+        .opt_str("trace-stack")
+        .map(|_| number_option(&matches, "trace-stack", 0, 0) as usize);
+    let mut tracer = match matches.opt_str("trace") {
+        Some(path) => match Tracer::create(&path, argument, stack_limit) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+    let session = open_session(&matches);
+
+    let view = matches.opt_present("v") || matches.opt_present("viewer");
+
+    // Debug mode, traces, limits and the display follow the program codel by codel, which the
+    // optimized program doesn't do
+    let mut detector = if matches.opt_present("detect-loops") {
+        Some(LoopDetector::new())
+    } else {
+        None
+    };
+    let mut profiler = if matches.opt_present("profile") || matches.opt_present("heatmap") {
+        Some(Profiler::new(&picture))
+    } else {
+        None
+    };
+    let mut coverage = matches.opt_str("coverage").map(|path| {
+        match Coverage::load(&path, &picture) {
+            Ok(coverage) => (path, coverage),
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            },
+        }
+    });
+    let codel_by_codel = debug
+        || view
+        || coverage.is_some()
+        || tracer.is_some()
+        || detector.is_some()
+        || profiler.is_some()
+        || limits != Limits::default();
 
-    #[cfg(feature = "default")]
-    let disp_thread = if matches.opt_present("v") { setup_display(picture.clone()) } else { None };
+    let mut observer = if view { Some(create_observer(&matches, &picture)) } else { None };
+    let output = match observer {
+        Some(ref observer) => observer.output(),
+        None => None,
+    };
+    let output = output.unwrap_or_else(|| Box::new(io::stdout()));
+    let mut machine = match tracer {
+        Some(ref tracer) => tracer.machine(debug, output),
+        None => Machine::with_io(debug, Box::new(io::stdin()), output),
+    };
+    machine.session = session;
+    if !codel_by_codel && !matches.opt_present("no-optimize") {
+        optimize(&build_graph(&picture)).run(&mut machine);
+        machine.end_session(true);
+        return;
+    }
 
-    'main_loop: loop {
-        #[cfg(feature = "default")]
-        {
-            if let Some((_, ref channel)) = disp_thread {
-                if let Err(e) = channel.send((true, cur_codel.clone(), dp.clone(), cc.clone())) {
-                    println!("{}", e);
-                }
+    let started = time::Instant::now();
+    let mut interrupted = None;
+    let mut quit = false;
+    let mut interpreter = Interpreter::new(&picture, machine);
+    if let Some((_, ref mut coverage)) = coverage {
+        coverage.start(&interpreter);
+    }
+    loop {
+        if let Some(limit) = limits.reached(interpreter.steps, started) {
+            interrupted = Some(format!("Error: {}, at {}.", limit, interpreter.state()));
+            break;
+        }
+        if let Some(ref mut observer) = observer {
+            match observer.show(&interpreter) {
+                Control::Step => {},
+                Control::Restart => {
+                    interpreter.restart();
+                    if let Some((_, ref mut coverage)) = coverage {
+                        coverage.start(&interpreter);
+                    }
+                    if let Some(ref mut profiler) = profiler {
+                        profiler.reset();
+                    }
+                    detector = detector.map(|_| LoopDetector::new());
+                    continue;
+                },
+                Control::Quit => {
+                    quit = interpreter.peek().is_some();
+                    break;
+                },
             }
         }
         if debug {
-            println!("{:?}, {:?}, {:?}", cur_codel, dp, cc);
-            println!("{:?}", piet_stack);
-            if piet_stack.len() > 1 {
-            println!("{:>064b}", piet_stack[0]);
+            let stack = &interpreter.machine.stack;
+            println!("{:?}, {:?}, {:?}", interpreter.codel, interpreter.dp, interpreter.cc);
+            println!("{:?}", stack);
+            if stack.len() > 1 {
+            println!("{:>064b}", stack[0]);
             }
         }
-        let next_codel;
-        let mut attempts = 0;
-        let mut block_size;
-        'codel_choosing: loop {
-            let result = get_farthest_codel(&picture, cur_codel.x, cur_codel.y, dp, cc);
-            block_size = result.0;
-            let farthest_codel = result.1;
-            match (attempts % 2, can_go_in_direction(&picture, farthest_codel, dp)) {
-                (_, (true, codel)) => {
-                    next_codel = codel;
-                    break 'codel_choosing;
-                },
-                (0, (false, codel)) => {
-                    cur_codel = codel.clone();
-                    cc = cc.opposite();
-                },
-                (1, (false, codel)) => {
-                    cur_codel = codel.clone();
-                    dp = dp.rotate();
-                },
-                _ => unreachable!(),
-            }
-            attempts += 1;
-            if attempts >= 8 {
-                break 'main_loop;
+        let codel = interpreter.codel;
+        let operands = operands(&interpreter);
+        let step = match interpreter.step() {
+            Some(step) => step,
+            None => break,
+        };
+        if let Some(ref mut tracer) = tracer {
+            if let Err(e) = tracer.record(&codel, &step, operands, &interpreter) {
+                println!("Error: {}", e);
+                process::exit(1);
             }
         }
-        match cur_codel.diff_to(next_codel) {
-            // Push
-            (0, 1) => piet_stack.push(block_size as i64),
-            // Pop
-            (0, 2) => {
-                piet_stack.pop();
-            },
-            // Add
-            (1, 0) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push(b + a);
-                }
-            },
-            // Sub
-            (1, 1) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push(b - a);
-                }
-            },
-            // Mul
-            (1, 2) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push(b * a);
-                }
-            },
-            // Div
-            (2, 0) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push(b / a);
-                }
-            },
-            // Mod
-            (2, 1) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push((b % a) + a);
-                }
-            },
-            // Not
-            (2, 2) => {
-                if let Some(val) = piet_stack.pop() {
-                    piet_stack.push(if val == 0 { 1 } else { 0 })
-                }
-            },
-            // Greater
-            (3, 0) => {
-                if let (Some(a), Some(b)) = (piet_stack.pop(), piet_stack.pop()) {
-                    piet_stack.push(if b > a { 1 } else { 0 })
-                }
-            },
-            // Pointer
-            (3, 1) => {
-                if let Some(a) = piet_stack.pop() {
-                    for _ in 0..a {
-                        dp = dp.rotate();
-                    }
-                }
-            },
-            // Switch
-            (3, 2) => {
-                if let Some(a) = piet_stack.pop() {
-                    for _ in 0..a {
-                        cc = cc.opposite();
-                    }
-                }
-            },
-            // Duplicate
-            (4, 0) => {
-                if let Some(a) = piet_stack.pop() {
-                    piet_stack.push(a);
-                    piet_stack.push(a);
-                }
-            },
-            // Roll
-            (4, 1) => {
-                if let (Some(count), Some(depth)) = (piet_stack.pop(), piet_stack.pop()) {
-                    let mut before: Vec<_> = piet_stack
-                        .iter()
-                        .take(piet_stack.len() - (depth as usize))
-                        .map(|e| *e)
-                        .collect();
-                    if count >= 0 {
-                        let mut rolled: Vec<_> = piet_stack
-                            .iter()
-                            .skip(piet_stack.len() - (count as usize))
-                            .map(|e| *e)
-                            .collect();
-                        let mut after: Vec<_> = piet_stack
-                            .iter()
-                            .skip(piet_stack.len() - (depth as usize))
-                            .take((depth - count) as usize)
-                            .map(|e| *e)
-                            .collect();
-                        before.append(&mut rolled);
-                        before.append(&mut after);
-                    } else {
-                        let mut rolled: Vec<_> = piet_stack
-                            .iter()
-                            .skip(before.len())
-                            .take(count.abs() as usize)
-                            .map(|e| *e)
-                            .collect();
-                        let mut after: Vec<_> = piet_stack
-                            .iter()
-                            .skip(before.len() + rolled.len())
-                            .map(|e| *e)
-                            .collect();
-                        before.append(&mut after);
-                        while let Some(v) = rolled.pop() {
-                            before.push(v);
-                        }
-                    }
-                    piet_stack = before;
-                }
-            },
-            // in(number)
-            (4, 2) => {
-                let mut input = String::new();
-                match io::stdin().read_line(&mut input) {
-                    Ok(_) => piet_stack.push(input.trim_end().parse().unwrap()),
-                    Err(error) => {
-                        if debug {
-                            panic!("{}", error)
-                        }
-                    },
-                }
-            },
-            // in(char)
-            (5, 0) => {
-                if let Some(Ok(char)) = chars.next() {
-                    piet_stack.push(char as i64);
-                } else {
-                    chars = io::stdin().bytes();
-                    if let Some(Ok(char)) = chars.next() {
-                        piet_stack.push(char as i64);
-                    }
-                }
-            },
-            // out(number)
-            (5, 1) => {
-                if let Some(val) = piet_stack.pop() {
-                    #[cfg(feature = "default")]
-                    {
-                        if let Some(_) = disp_thread {
-                            print!(
-                                "{}{}{}",
-                                termion::screen::ToMainScreen,
-                                val,
-                                termion::screen::ToAlternateScreen
-                            );
-                        }
-                    }
-
-                    print!("{}", val);
-                }
-            },
-            // out(char)
-            (5, 2) => {
-                if let Some(val) = piet_stack.pop() {
-                    if let Some(val) = char::from_u32(val as u32) {
-                        #[cfg(feature = "default")]
-                        {
-                            if let Some(_) = disp_thread {
-                                print!(
-                                    "{}{}{}",
-                                    termion::screen::ToMainScreen,
-                                    val,
-                                    termion::screen::ToAlternateScreen
-                                );
-                            }
-                        }
-                        print!("{}", val);
-                    }
-                }
-            },
-            (6, _) => {
-                if cur_codel.color.hue == Hue::Smoke {
-                    println!("syscall!");
-                    if let Some(syscall_num) = piet_stack.pop() {
-                        let mut syscall_num = syscall_num;
-                        let mut syscall_args = vec![];
-                        if let Some(arg_count) = piet_stack.pop() {
-                            let stack_addr = &piet_stack[0] as *const i64 as u64;
-                            for _ in 0..arg_count {
-                                if let (Some(arg_type), Some(arg)) = (piet_stack.pop(), piet_stack.pop()) {
-                                    match arg_type {
-                                        1 => syscall_args.push(arg),
-                                        2 => syscall_args.push(if arg < 0 { (stack_addr - arg.abs() as u64) as i64 } else { (stack_addr + arg as u64) as i64 }),
-                                        _ => println!("Bad arg_type!"),
-                                    }
-                                }
-                            }
-                        }
-                        unsafe {
-                        let result = match syscall_args.len() {
-                            #[allow(deprecated)]
-                            0 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      :
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            1 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            2 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            3 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            4 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            5 => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3]) "{r8}"(syscall_args[4])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                            #[allow(deprecated)]
-                            _ => { llvm_asm!("syscall"
-                                      : "+{rax}"(syscall_num)
-                                      : "{rdi}"(syscall_args[0]) "{rsi}"(syscall_args[1]) "{rdx}"(syscall_args[2]) "{r10}"(syscall_args[3]) "{r8}"(syscall_args[4])"{r9}"(syscall_args[5])
-                                      : "rcx", "r11", "memory"
-                                      : "volatile"); syscall_num },
-                        };
-                        piet_stack.push(result);
-                        }
-                    }
-                }
-            },
-            (a, b) => panic!("Error: differences are ({},{}))", a, b),
+        if let Some(ref mut profiler) = profiler {
+            profiler.record(&step);
+        }
+        if let Some((_, ref mut coverage)) = coverage {
+            coverage.record(&step);
+        }
+        if let Some(length) = detector.as_mut().and_then(|d| d.check(&interpreter)) {
+            let state = interpreter.state();
+            let stuck = describe_loop(&mut interpreter, length);
+            interrupted = Some(format!("Error: the program is stuck in {}, at {}.", stuck, state));
+            break;
+        }
+    }
+    interpreter.machine.end_session(interrupted.is_none() && !quit);
+    if let Some(ref mut tracer) = tracer {
+        if let Err(e) = tracer.flush() {
+            println!("Error: {}", e);
+            process::exit(1);
         }
-        cur_codel = next_codel.clone();
     }
 
-    #[cfg(feature = "default")]
-    {
-        if let Some((handle, channel)) = disp_thread {
-            if let Err(e) = channel.send((false, cur_codel, dp, cc)) {
-                println!("{}", e);
-            }
-            if let Err(e) = handle.join() {
-                println!("{:?}", e);
+    if let Some((path, coverage)) = coverage {
+        if let Err(e) = coverage.save(&path) {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+    if let Some(ref profiler) = profiler {
+        if matches.opt_present("profile") {
+            let top = number_option(&matches, "profile-top", 10, 1) as usize;
+            io::stdout().flush().ok();
+            eprint!("{}", profiler.report(&picture, top));
+        }
+        if let Some(path) = matches.opt_str("heatmap") {
+            let heatmap = profiler.heatmap(&picture);
+            let written =
+                fs::File::create(path).and_then(|f| write_pixels(f, &heatmap, codel_size));
+            if let Err(e) = written {
+                println!("Error: {}", e);
+                process::exit(1);
             }
         }
     }
+
+    if let Some(observer) = observer {
+        observer.close();
+    }
+
+    if let Some(message) = interrupted {
+        io::stdout().flush().ok();
+        eprintln!("{}", message);
+        process::exit(LIMIT_REACHED);
+    }
 }
@@ -0,0 +1,342 @@
+use instruction::*;
+use optimizer::*;
+use std::*;
+
+/* Synthesis of instruction sequences pushing a given number.
+ *
+ * A Push instruction pushes the size of the block it leaves, so pushing large numbers directly
+ * takes a lot of codels. Numbers are built instead out of small pushes combined with Duplicate,
+ * Add, Sub, Mul and Div:
+ * - products of two numbers, squares using Duplicate,
+ * - powers of a pushable number, using a chain of Duplicate followed by a chain of Mul,
+ * - products or powers plus or minus a small number,
+ * - powers divided by a small number,
+ * - 0 as `push 1, not` and negative numbers as 0 minus their opposite.
+ * Numbers larger than SEARCH_LIMIT are written in base max_push instead, as there are too many
+ * ways of building them.
+ * Every sequence leaves exactly one more value on the stack and doesn't look at what's below it,
+ * so Roll is never needed.
+ * */
+
+/* How expensive a sequence of instructions is. The cost of an instruction is `codel_cost` for
+ * each codel of its block plus `instruction_cost`, which lets generators favor small pictures or
+ * short programs.
+ * max_push: Largest number pushed by a single block.
+ * */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostModel {
+    pub max_push: i64,
+    pub codel_cost: i64,
+    pub instruction_cost: i64,
+}
+
+impl CostModel {
+    /* Returns the number of codels of the block of the instruction. */
+    pub fn codels(op: &Op) -> i64 {
+        if op.instruction == Instruction::Push {
+            op.value
+        } else {
+            1
+        }
+    }
+
+    pub fn cost(&self, ops: &[Op]) -> i64 {
+        ops.iter()
+            .map(|op| CostModel::codels(op) * self.codel_cost + self.instruction_cost)
+            .sum()
+    }
+}
+
+// Largest number whose sequence is searched exhaustively, larger ones are split into digits
+const SEARCH_LIMIT: i64 = 1 << 21;
+
+fn op(instruction: Instruction) -> Op {
+    Op { instruction, value: 0 }
+}
+
+fn push(value: i64) -> Op {
+    Op { instruction: Instruction::Push, value }
+}
+
+/* Finds cheap sequences of instructions pushing numbers, according to a cost model. Sequences
+ * are remembered, so a single synthesizer should be used for all the numbers of a program.
+ * */
+pub struct Synthesizer {
+    model: CostModel,
+    memo: collections::HashMap<i64, Vec<Op>>,
+}
+
+impl Synthesizer {
+    pub fn new(model: CostModel) -> Synthesizer {
+        Synthesizer { model, memo: collections::HashMap::new() }
+    }
+
+    pub fn model(&self) -> &CostModel {
+        &self.model
+    }
+
+    /* Keeps the cheapest of two sequences, an empty sequence meaning that there is none. */
+    fn cheapest(&self, best: Vec<Op>, ops: Vec<Op>) -> Vec<Op> {
+        if best.is_empty() || (!ops.is_empty() && self.model.cost(&ops) < self.model.cost(&best)) {
+            ops
+        } else {
+            best
+        }
+    }
+
+    /* Returns a sequence of instructions pushing `value`. */
+    pub fn number(&mut self, value: i64) -> Vec<Op> {
+        if let Some(ops) = self.memo.get(&value) {
+            return ops.clone();
+        }
+        let max_push = self.model.max_push;
+        let ops = if value == 0 {
+            vec![push(1), op(Instruction::Not)]
+        } else if value < 0 {
+            // The opposite of i64::MIN doesn't fit in an i64
+            let (opposite, correction) =
+                if value == i64::MIN { (i64::MAX, Some(1)) } else { (-value, None) };
+            let mut ops = self.number(0);
+            ops.extend(self.number(opposite));
+            ops.push(op(Instruction::Sub));
+            if let Some(correction) = correction {
+                ops.extend(self.number(correction));
+                ops.push(op(Instruction::Sub));
+            }
+            ops
+        } else if value == 1 {
+            vec![push(1)]
+        } else if value > SEARCH_LIMIT {
+            self.digits(value)
+        } else {
+            let mut best = if value <= max_push { vec![push(value)] } else { vec![] };
+            let ops = self.factored(value);
+            best = self.cheapest(best, ops);
+            let ops = self.power(value);
+            best = self.cheapest(best, ops);
+            // Offsets would lead to larger numbers, which could lead back to this one
+            if value > max_push {
+                for offset in 1..max_push {
+                    best = self.offset(best, value, offset);
+                }
+                best = self.divided(best, value);
+            }
+            if best.is_empty() {
+                // Only happens when max_push is 1
+                best = self.number(value - max_push);
+                best.push(push(max_push));
+                best.push(op(Instruction::Add));
+            }
+            best
+        };
+        self.memo.insert(value, ops.clone());
+        ops
+    }
+
+    /* Returns a sequence pushing `value` as its quotient by max_push times max_push, plus the
+     * remainder.
+     * */
+    fn digits(&mut self, value: i64) -> Vec<Op> {
+        // A base of 1 would never get below SEARCH_LIMIT
+        let base = cmp::max(self.model.max_push, 2);
+        let mut ops = self.number(value / base);
+        ops.extend(self.number(base));
+        ops.push(op(Instruction::Mul));
+        if value % base != 0 {
+            ops.extend(self.number(value % base));
+            ops.push(op(Instruction::Add));
+        }
+        ops
+    }
+
+    /* Returns the cheapest of `best` and of `value` computed as a product or power plus or minus
+     * `offset`.
+     * */
+    fn offset(&mut self, mut best: Vec<Op>, value: i64, offset: i64) -> Vec<Op> {
+        let candidates = [
+            (value.checked_sub(offset), Instruction::Add),
+            (value.checked_add(offset), Instruction::Sub),
+        ];
+        for &(base, instruction) in candidates.iter() {
+            let base = match base {
+                Some(base) => base,
+                None => continue,
+            };
+            let mut ops = self.factored(base);
+            let power = self.power(base);
+            ops = self.cheapest(ops, power);
+            if ops.is_empty() {
+                continue;
+            }
+            ops.extend(self.number(offset));
+            ops.push(op(instruction));
+            best = self.cheapest(best, ops);
+        }
+        best
+    }
+
+    /* Returns the cheapest of `best` and of `value` computed as a power divided by a pushable
+     * number.
+     * */
+    fn divided(&mut self, mut best: Vec<Op>, value: i64) -> Vec<Op> {
+        for divisor in 2..self.model.max_push + 1 {
+            let dividend = match value.checked_mul(divisor) {
+                Some(dividend) => dividend,
+                None => break,
+            };
+            for remainder in 0..divisor {
+                let mut ops = match dividend.checked_add(remainder) {
+                    Some(dividend) => self.power(dividend),
+                    None => vec![],
+                };
+                if ops.is_empty() {
+                    continue;
+                }
+                ops.extend(self.number(divisor));
+                ops.push(op(Instruction::Div));
+                best = self.cheapest(best, ops);
+            }
+        }
+        best
+    }
+
+    /* Returns the cheapest sequence pushing `value` as the product of two smaller numbers, an
+     * empty sequence if `value` is prime.
+     * */
+    fn factored(&mut self, value: i64) -> Vec<Op> {
+        let mut best = vec![];
+        let mut factor = 2;
+        while factor <= value / factor {
+            if value % factor == 0 {
+                let mut ops = self.number(factor);
+                if factor * factor == value {
+                    ops.push(op(Instruction::Duplicate));
+                } else {
+                    ops.extend(self.number(value / factor));
+                }
+                ops.push(op(Instruction::Mul));
+                best = self.cheapest(best, ops);
+            }
+            factor += 1;
+        }
+        best
+    }
+
+    /* Returns the cheapest sequence pushing `value` as a power of a pushable number, with an
+     * exponent of at least 3, an empty sequence if there is none.
+     * */
+    fn power(&mut self, value: i64) -> Vec<Op> {
+        let mut best = vec![];
+        for base in 2..self.model.max_push + 1 {
+            let mut power = base;
+            let mut exponent = 1;
+            while let Some(next) = power.checked_mul(base) {
+                if next > value {
+                    break;
+                }
+                power = next;
+                exponent += 1;
+            }
+            if power != value || exponent < 3 {
+                continue;
+            }
+            let mut ops = self.number(base);
+            for _ in 1..exponent {
+                ops.push(op(Instruction::Duplicate));
+            }
+            for _ in 1..exponent {
+                ops.push(op(Instruction::Mul));
+            }
+            best = self.cheapest(best, ops);
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::*;
+
+    fn model(max_push: i64) -> CostModel {
+        CostModel { max_push, codel_cost: 1, instruction_cost: 0 }
+    }
+
+    /* Runs a sequence on a stack holding a single value.
+     * returns: The stack afterwards.
+     * */
+    fn run(ops: &[Op]) -> Vec<i64> {
+        let mut machine = Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()));
+        machine.stack = vec![99];
+        for op in ops.iter() {
+            machine.execute(op.instruction, op.value);
+        }
+        machine.stack
+    }
+
+    #[test]
+    fn pushes_the_number() {
+        let values = [
+            0,
+            1,
+            -1,
+            -17,
+            12,
+            13,
+            144,
+            1000,
+            1024,
+            65537,
+            SEARCH_LIMIT,
+            SEARCH_LIMIT + 1,
+            123_456_789_012,
+            i64::MAX,
+            i64::MIN + 1,
+            i64::MIN,
+        ];
+        for &max_push in [12, 5, 1].iter() {
+            let mut synthesizer = Synthesizer::new(model(max_push));
+            for &value in values.iter() {
+                let ops = synthesizer.number(value);
+                assert_eq!(run(&ops), vec![99, value], "{} with max_push {}", value, max_push);
+                for op in ops.iter().filter(|op| op.instruction == Instruction::Push) {
+                    assert!(op.value >= 1 && op.value <= max_push, "{:?}", op);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn picks_the_cheapest_sequence() {
+        let model = model(12);
+        let mut synthesizer = Synthesizer::new(model);
+        // (3 * 4) squared is cheaper than 12 squared
+        let ops = synthesizer.number(144);
+        let square = vec![push(12), op(Instruction::Duplicate), op(Instruction::Mul)];
+        assert_eq!((model.cost(&ops), model.cost(&square)), (10, 14));
+        // 8 squared twice
+        assert!(model.cost(&synthesizer.number(4096)) <= 12);
+
+        // Instructions costing more than codels favor a single large push
+        let mut synthesizer =
+            Synthesizer::new(CostModel { max_push: 200, codel_cost: 1, instruction_cost: 100 });
+        assert_eq!(synthesizer.number(144), vec![push(144)]);
+
+        // No product or direct push is cheaper than what was found
+        let mut synthesizer = Synthesizer::new(model);
+        for value in 2..500 {
+            let cost = model.cost(&synthesizer.number(value));
+            if value <= 12 {
+                assert!(cost <= value, "{}", value);
+            }
+            for factor in 2..value {
+                if value % factor == 0 {
+                    let mut ops = synthesizer.number(factor);
+                    ops.extend(synthesizer.number(value / factor));
+                    ops.push(op(Instruction::Mul));
+                    assert!(cost <= model.cost(&ops), "{} = {} * ...", value, factor);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use interpreter::*;
+use std::*;
+
+/* What an observer lets the interpreter do once it has been shown a state.
+ * Step: Perform the next step.
+ * Restart: Go back to the start of the program.
+ * Quit: Stop the program.
+ * */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Step,
+    // Only the TUI lets users restart programs
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    Restart,
+    Quit,
+}
+
+/* A front end following a program run by --view. The interpreter shows it its state before each
+ * step, and once more when the program has terminated, and does what it answers, so front ends
+ * can pause, step or restart the program.
+ * */
+pub trait Observer {
+    /* returns: Where the program should write its output, None to let it write to stdout. */
+    fn output(&self) -> Option<Box<dyn io::Write>> {
+        None
+    }
+
+    /* Shows the state of the interpreter before its next step.
+     * returns: What the interpreter should do next.
+     * */
+    fn show(&mut self, interpreter: &Interpreter) -> Control;
+
+    /* Called once the program is over, to let the front end clean up. */
+    fn close(self: Box<Self>) {}
+}
+
+/* An observer writing a line per step, for terminals the TUI can't draw on and for logs. */
+pub struct LineLogger {
+    log: Box<dyn io::Write>,
+}
+
+impl LineLogger {
+    pub fn new(log: Box<dyn io::Write>) -> LineLogger {
+        LineLogger { log }
+    }
+}
+
+impl Observer for LineLogger {
+    fn show(&mut self, interpreter: &Interpreter) -> Control {
+        use std::io::Write;
+        let next = match interpreter.peek() {
+            Some((_, instruction)) => format!("next: {}", instruction.name()),
+            None => String::from("terminated"),
+        };
+        match writeln!(self.log, "{}: {}, {}", interpreter.steps, interpreter.state(), next) {
+            Ok(_) => Control::Step,
+            Err(_) => Control::Quit,
+        }
+    }
+}
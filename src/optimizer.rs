@@ -0,0 +1,349 @@
+use graph::*;
+use instruction::*;
+use machine::*;
+use std::*;
+
+/* An instruction of a basic block. value: The number pushed by Push. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Op {
+    pub instruction: Instruction,
+    pub value: i64,
+}
+
+/* What happens once the instructions of a basic block have been executed.
+ * Halt: The program terminates.
+ * Jump: Execution continues with the given block.
+ * Pointer, Switch: The instruction is executed and execution continues with the n-th block of
+ * the list, n being the number of times dp was rotated or cc was toggled.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Halt,
+    Jump(usize),
+    Pointer(Vec<usize>),
+    Switch(Vec<usize>),
+}
+
+/* A sequence of instructions always executed one after the other.
+ * node: The node of the program graph the block starts at.
+ * */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub node: usize,
+    pub ops: Vec<Op>,
+    pub terminator: Terminator,
+}
+
+pub struct Program {
+    pub blocks: Vec<BasicBlock>,
+    pub start: usize,
+}
+
+impl Program {
+    /* Runs the program until it terminates. */
+    pub fn run(&self, machine: &mut Machine) {
+        let mut block = self.start;
+        loop {
+            let current = &self.blocks[block];
+            for op in current.ops.iter() {
+                machine.execute(op.instruction, op.value);
+            }
+            block = match current.terminator {
+                Terminator::Halt => return,
+                Terminator::Jump(next) => next,
+                Terminator::Pointer(ref next) => next[machine.execute(Instruction::Pointer, 0)],
+                Terminator::Switch(ref next) => next[machine.execute(Instruction::Switch, 0)],
+            };
+        }
+    }
+}
+
+/* Splits the program graph into basic blocks.
+ * merge: When false, every node gets its own block, which gives a program executing the exact
+ * same instructions as the interpreter. When true, nodes are merged with the node that follows
+ * them whenever that node can't be reached from anywhere else.
+ * */
+fn split(graph: &Graph, merge: bool) -> Program {
+    // A node starts a basic block unless it has a single predecessor that always leads to it
+    let mut predecessors = vec![0; graph.nodes.len()];
+    let mut leaders = vec![!merge; graph.nodes.len()];
+    leaders[graph.start] = true;
+    for node in graph.nodes.iter() {
+        if let Exit::Step { instruction, ref next, .. } = node.exit {
+            for id in next.iter() {
+                predecessors[*id] += 1;
+                if instruction == Instruction::Pointer || instruction == Instruction::Switch {
+                    leaders[*id] = true;
+                }
+            }
+        }
+    }
+    for (id, count) in predecessors.iter().enumerate() {
+        if *count != 1 {
+            leaders[id] = true;
+        }
+    }
+
+    let mut block_ids = vec![0; graph.nodes.len()];
+    let mut starts = vec![];
+    for (id, leader) in leaders.iter().enumerate() {
+        if *leader {
+            block_ids[id] = starts.len();
+            starts.push(id);
+        }
+    }
+
+    let mut blocks = vec![];
+    for start in starts {
+        let mut ops = vec![];
+        let mut id = start;
+        let terminator = loop {
+            match graph.nodes[id].exit {
+                Exit::Halt => break Terminator::Halt,
+                Exit::Step { instruction: Instruction::Pointer, ref next, .. } => {
+                    break Terminator::Pointer(next.iter().map(|n| block_ids[*n]).collect());
+                },
+                Exit::Step { instruction: Instruction::Switch, ref next, .. } => {
+                    break Terminator::Switch(next.iter().map(|n| block_ids[*n]).collect());
+                },
+                Exit::Step { instruction, value, ref next } => {
+                    ops.push(Op { instruction, value });
+                    if leaders[next[0]] {
+                        break Terminator::Jump(block_ids[next[0]]);
+                    }
+                    id = next[0];
+                },
+            }
+        };
+        blocks.push(BasicBlock { node: start, ops, terminator });
+    }
+    Program { blocks, start: block_ids[graph.start] }
+}
+
+/* Computes `b <instruction> a` the way Machine::execute does, None if the instruction can't be
+ * folded or if evaluating it would fail at run time.
+ * */
+fn fold(instruction: Instruction, b: i64, a: i64) -> Option<i64> {
+    match instruction {
+        Instruction::Add => b.checked_add(a),
+        Instruction::Sub => b.checked_sub(a),
+        Instruction::Mul => b.checked_mul(a),
+        Instruction::Div => b.checked_div(a),
+        Instruction::Mod => b.checked_rem(a).and_then(|r| r.checked_add(a)),
+        Instruction::Greater => Some(if b > a { 1 } else { 0 }),
+        _ => None,
+    }
+}
+
+/* Rewrites the end of a sequence of instructions into a shorter equivalent one.
+ * returns: Whether the sequence was changed.
+ * */
+fn simplify_tail(ops: &mut Vec<Op>) -> bool {
+    use instruction::Instruction::*;
+    let push = |value| Op { instruction: Push, value };
+    let len = ops.len();
+    if len >= 1 && ops[len - 1].instruction == Nop {
+        ops.pop();
+        return true;
+    }
+    if len >= 2 {
+        match (ops[len - 2], ops[len - 1].instruction) {
+            (Op { instruction: Push, .. }, Pop) | (Op { instruction: Duplicate, .. }, Pop) => {
+                ops.truncate(len - 2);
+                return true;
+            },
+            (Op { instruction: Push, value }, Not) => {
+                ops.truncate(len - 2);
+                ops.push(push(if value == 0 { 1 } else { 0 }));
+                return true;
+            },
+            (Op { instruction: Push, value }, Duplicate) => {
+                ops[len - 1] = push(value);
+                return true;
+            },
+            _ => {},
+        }
+    }
+    if len >= 3 && ops[len - 3].instruction == Push && ops[len - 2].instruction == Push {
+        let (b, a) = (ops[len - 3].value, ops[len - 2].value);
+        if let Some(value) = fold(ops[len - 1].instruction, b, a) {
+            ops.truncate(len - 3);
+            ops.push(push(value));
+            return true;
+        }
+    }
+    false
+}
+
+/* Folds constant arithmetic and removes instructions that cancel each other out. */
+fn peephole(ops: &[Op]) -> Vec<Op> {
+    let mut result = Vec::with_capacity(ops.len());
+    for op in ops.iter() {
+        result.push(*op);
+        while simplify_tail(&mut result) {}
+    }
+    result
+}
+
+/* Returns the program graph as a program made of one basic block per node. */
+pub fn lower(graph: &Graph) -> Program {
+    split(graph, false)
+}
+
+/* Returns the program graph as a program made of optimized basic blocks. */
+pub fn optimize(graph: &Graph) -> Program {
+    let mut program = split(graph, true);
+    for block in program.blocks.iter_mut() {
+        block.ops = peephole(&block.ops);
+    }
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codel::*;
+    use generator::*;
+    use interpreter::*;
+    use numbers::*;
+    use picture::*;
+    use pietcolor::*;
+
+    fn push(value: i64) -> Op {
+        Op { instruction: Instruction::Push, value }
+    }
+
+    fn op(instruction: Instruction) -> Op {
+        Op { instruction, value: 0 }
+    }
+
+    fn picture(ops: &[Op]) -> Vec<Vec<Codel>> {
+        let colors = layout(ops);
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, false)
+    }
+
+    fn machine(input: &str, output: &SharedBuffer) -> Machine {
+        let input = Box::new(io::Cursor::new(input.as_bytes().to_vec()));
+        Machine::with_io(false, input, Box::new(output.clone()))
+    }
+
+    #[test]
+    fn removes_instructions_that_cancel_out() {
+        use instruction::Instruction::*;
+        assert_eq!(peephole(&[op(InChar), push(3), op(Pop)]), vec![op(InChar)]);
+        assert_eq!(peephole(&[op(InChar), op(Duplicate), op(Pop)]), vec![op(InChar)]);
+        assert_eq!(peephole(&[push(0), op(Not)]), vec![push(1)]);
+        assert_eq!(peephole(&[push(-5), op(Not)]), vec![push(0)]);
+        assert_eq!(peephole(&[push(4), op(Duplicate)]), vec![push(4), push(4)]);
+        assert_eq!(peephole(&[op(Nop), op(OutChar), op(Nop)]), vec![op(OutChar)]);
+        // Rewrites are applied again to what they leave
+        let ops = vec![push(2), push(3), op(Add), push(4), op(Mul), op(Duplicate), op(Pop)];
+        assert_eq!(peephole(&ops), vec![push(20)]);
+        assert_eq!(peephole(&[push(2), push(3), op(Add), op(Pop)]), vec![]);
+        // Instructions that can't be removed are kept
+        let ops = vec![op(InNumber), op(Duplicate), op(Not), op(Pop), push(7), op(Roll)];
+        assert_eq!(peephole(&ops), ops);
+    }
+
+    #[test]
+    fn folds_like_the_machine() {
+        use instruction::Instruction::*;
+        let values = [0, 1, -1, 3, -3, 7, -7, 100];
+        for &instruction in [Add, Sub, Mul, Div, Mod, Greater].iter() {
+            for &b in values.iter() {
+                for &a in values.iter() {
+                    let folded = peephole(&[push(b), push(a), op(instruction)]);
+                    if (instruction == Div || instruction == Mod) && a == 0 {
+                        assert_eq!(folded.len(), 3, "{:?} by zero was folded", instruction);
+                        continue;
+                    }
+                    let mut machine = machine("", &SharedBuffer::new());
+                    machine.stack = vec![b, a];
+                    machine.execute(instruction, 0);
+                    let expected = vec![push(machine.stack[0])];
+                    assert_eq!(folded, expected, "{} {:?} {}", b, instruction, a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn skips_folds_that_would_fail() {
+        use instruction::Instruction::*;
+        let unchanged = |b: i64, a: i64, instruction: Instruction| {
+            let ops = vec![push(b), push(a), op(instruction)];
+            assert_eq!(peephole(&ops), ops, "{} {:?} {} was folded", b, instruction, a);
+        };
+        unchanged(5, 0, Div);
+        unchanged(5, 0, Mod);
+        unchanged(i64::MAX, 1, Add);
+        unchanged(i64::MIN, 1, Sub);
+        unchanged(i64::MAX, 2, Mul);
+        unchanged(i64::MIN, -1, Div);
+        unchanged(i64::MIN, -1, Mod);
+        // The remainder fits, adding the divisor to it doesn't
+        unchanged(i64::MAX - 1, i64::MAX, Mod);
+        assert_eq!(peephole(&[push(i64::MAX), push(i64::MIN), op(Greater)]), vec![push(1)]);
+    }
+
+    #[test]
+    fn splits_into_basic_blocks() {
+        let model = CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 };
+        let ops = print_program("Hi", &mut Synthesizer::new(model));
+        let graph = build_graph(&picture(&ops));
+
+        let lowered = lower(&graph);
+        assert_eq!(lowered.blocks.len(), graph.nodes.len());
+        assert_eq!(lowered.start, graph.start);
+        for (id, block) in lowered.blocks.iter().enumerate() {
+            assert_eq!(block.node, id);
+            assert!(block.ops.len() <= 1);
+        }
+
+        let optimized = optimize(&graph);
+        assert!(optimized.blocks.len() < lowered.blocks.len());
+        assert_eq!(optimized.blocks[optimized.start].node, graph.start);
+        let count = |program: &Program| {
+            let ops = program.blocks.iter().flat_map(|block| block.ops.iter());
+            ops.filter(|op| op.instruction != Instruction::Nop).count()
+        };
+        assert!(count(&optimized) < count(&lowered));
+    }
+
+    #[test]
+    fn runs_like_the_interpreter() {
+        use instruction::Instruction::*;
+        let mut synthesizer = Synthesizer::new(CostModel {
+            max_push: 12,
+            codel_cost: 1,
+            instruction_cost: 0,
+        });
+        let mut ops = print_program("Hello!", &mut synthesizer);
+        for &value in [7, -3, 100, 5].iter() {
+            ops.extend(synthesizer.number(value));
+        }
+        ops.extend(vec![op(Greater), op(Duplicate), op(Not), op(Add), op(OutNumber)]);
+        for &value in [-17, 5, 17, 5].iter() {
+            ops.extend(synthesizer.number(value));
+        }
+        ops.extend(vec![op(Mod), op(OutNumber), op(Div), op(OutNumber), op(Mul), op(OutNumber)]);
+        ops.extend(vec![op(InNumber), op(InChar), op(OutChar), op(Duplicate), op(Add)]);
+        ops.push(op(OutNumber));
+        let picture = picture(&ops);
+        let input = "-42\nab";
+
+        let output = SharedBuffer::new();
+        let mut interpreter = Interpreter::new(&picture, machine(input, &output));
+        while interpreter.step().is_some() {}
+        let expected = (output.take(), interpreter.machine.stack);
+        let graph = build_graph(&picture);
+        for program in [lower(&graph), optimize(&graph)].iter() {
+            let mut machine = machine(input, &output);
+            program.run(&mut machine);
+            assert_eq!((output.take(), machine.stack), expected);
+        }
+    }
+}
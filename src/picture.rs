@@ -0,0 +1,348 @@
+use codel::*;
+use pietcolor::*;
+use std::*;
+
+/* Fetches the farthest codel belonging to the same block as the codel at position x, y
+ * picture: A matrix of Codels corresponding to the piet program.
+ * x: The starting position within the matrix on the x axis
+ * y: The starting position within the matriy on the y ayis
+ * dp: The global direction pointer
+ * cc: The global codel chooser
+ * returns: An (usize, &Codel) tuple. For non-white blocks, the usize corresponds to the size of
+ * the block, the &Codel is the farthest codel that belongs to the same block as the starting
+ * position according to the direction pointer and cc.
+ * For white blocks the usize is 0 and the codel is the farthest white codel in a straight line
+ * in dp's direction.  For other blocks, this is basically a depth-first search implemented using
+ * a stack instead of recursion.
+ * */
+pub fn get_farthest_codel(
+    picture: &[Vec<Codel>],
+    x: usize,
+    y: usize,
+    dp: Direction,
+    cc: Direction,
+) -> (usize, &Codel) {
+    // White blocks have a different algorithm
+    if picture[y][x].color.hue == Hue::White {
+        let (mut x, mut y) = (x as isize, y as isize);
+        let (tmpx, tmpy) = dp.to_vector();
+        while y >= 0 && y < picture.len() as isize && x >= 0 &&
+            x < picture[y as usize].len() as isize &&
+            picture[y as usize][x as usize].color.hue == Hue::White
+        {
+            y += tmpy;
+            x += tmpx;
+        }
+        y -= tmpy;
+        x -= tmpx;
+        return (0, &picture[y as usize][x as usize]);
+    }
+
+    let mut codels_to_visit = vec![(x, y); 1];
+    let mut visited_codels = collections::HashSet::new();
+
+    let mut result = &picture[y][x];
+    while codels_to_visit.len() > 0 {
+        // Get the last codel added to the codel stack
+        let (tmpx, tmpy) = codels_to_visit.pop().unwrap();
+        // Mark it as visited
+        visited_codels.insert(&picture[tmpy][tmpx]);
+        // If the codel under the current codel is within our matrix bounds, its color matches the
+        // starting color and it hasn't been visited yet
+        if tmpy + 1 < picture.len() && &picture[tmpy + 1][tmpx].color == &picture[y][x].color &&
+            !visited_codels.contains(&picture[tmpy + 1][tmpx])
+        {
+            // Add it to the list of codels that need to be visited
+            codels_to_visit.push((tmpx, tmpy + 1));
+        }
+        // Same thing
+        if tmpy > 0 && &picture[tmpy - 1][tmpx].color == &picture[y][x].color &&
+            !visited_codels.contains(&picture[tmpy - 1][tmpx])
+        {
+            codels_to_visit.push((tmpx, tmpy - 1));
+        }
+        // Same thing
+        if tmpx + 1 < picture[tmpy].len() &&
+            &picture[tmpy][tmpx + 1].color == &picture[y][x].color &&
+            !visited_codels.contains(&picture[tmpy][tmpx + 1])
+        {
+            codels_to_visit.push((tmpx + 1, tmpy));
+        }
+        // Same thing
+        if tmpx > 0 && &picture[tmpy][tmpx - 1].color == &picture[y][x].color &&
+            !visited_codels.contains(&picture[tmpy][tmpx - 1])
+        {
+            codels_to_visit.push((tmpx - 1, tmpy));
+        }
+        result = result.compare_to(&picture[tmpy][tmpx], dp, cc);
+    }
+
+    return (visited_codels.len(), result);
+}
+
+/* Checks whether there is a non-white, non-black codel aligned with the current Codel in the
+ * direction of the dp within the picture.
+ * returns: A (bool, bool, &Codel) tuple. The first boolean is true if there is an available
+ * codel in the direction of the direction pointer, false otherwise. The second bool indicates
+ * whether the algorithm encountered white blocks between the current codel and the newly found
+ * codel.  The returned Codel is the new codel when the bool is true and the starting codel when
+ * the bool is false.
+ * */
+pub fn can_go_in_direction<'a>(
+    picture: &'a [Vec<Codel>],
+    cur_codel: &'a Codel,
+    dp: Direction,
+) -> (bool, &'a Codel) {
+    let (mut tmpx, mut tmpy) = (cur_codel.x as isize, cur_codel.y as isize);
+    let vec = dp.to_vector();
+    tmpx += vec.0;
+    tmpy += vec.1;
+
+    // If we're not within the matrix's bounds or if the codel is black, we can't go in this
+    // direction
+    if tmpy < 0 || tmpy as usize >= picture.len() || tmpx < 0 ||
+        (tmpx as usize) >= picture[(tmpy as usize)].len() ||
+        picture[tmpy as usize][tmpx as usize].color.hue == Hue::Black
+    {
+        return (false, cur_codel);
+    }
+    // Return the found codel
+    return (true, &picture[tmpy as usize][tmpx as usize]);
+}
+
+/* Reads the pixels of a PNG picture.
+ * returns: The RGB values of the pixels, row by row, or a description of why the picture can't be
+ * read.
+ * */
+pub fn read_pixels(filename: &str) -> Result<Vec<Vec<[u8; 3]>>, String> {
+    let file = fs::File::open(filename).map_err(|e| format!("{}: {}", filename, e))?;
+    let decoder = png::Decoder::new(file);
+    let (info, mut reader) = decoder.read_info().map_err(|e| format!("{}: {}", filename, e))?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(|e| format!("{}: {}", filename, e))?;
+
+    let values_per_pixel;
+    match info.color_type {
+        png::ColorType::RGBA => values_per_pixel = 4,
+        png::ColorType::RGB => values_per_pixel = 3,
+        _ => return Err(String::from("PNG picture is neither RGB or RGBA")),
+    }
+    let pic_width = info.width as usize;
+    Ok(buffer
+        .chunks(values_per_pixel * pic_width)
+        .map(|row| {
+            row.chunks(values_per_pixel).map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect()
+        })
+        .collect())
+}
+
+/* Turns pixels into codels, the color of a codel being the one of its top-left pixel. Pixels
+ * that aren't Piet colors, and smoke when syscalls are disabled, become `default_color`.
+ * Incomplete codels on the right and bottom edges are dropped.
+ * */
+pub fn to_codels(
+    pixels: &[Vec<[u8; 3]>],
+    codel_size: usize,
+    default_color: PietColor,
+    syscalls_enabled: bool,
+) -> Vec<Vec<Codel>> {
+    let syscall_codel = if syscalls_enabled {
+        PietColor { hue: Hue::Smoke, lightness: Lightness::Normal }
+    } else {
+        default_color
+    };
+    let (width, height) = (pixels.get(0).map_or(0, |row| row.len()), pixels.len());
+    (0..height / codel_size)
+        .map(|y| {
+            (0..width / codel_size)
+                .map(|x| {
+                    let color = match PietColor::from_rgb(&pixels[y * codel_size][x * codel_size]) {
+                        Some(PietColor { hue: Hue::Smoke, .. }) => syscall_codel,
+                        Some(color) => color,
+                        None => default_color,
+                    };
+                    Codel { color, x, y }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn get_picture(
+    filename: &string::String,
+    codel_size: usize,
+    default_color: PietColor,
+    syscalls_enabled: bool,
+) -> Vec<Vec<Codel>> {
+    let pixels = read_pixels(filename).unwrap_or_else(|e| panic!("{}", e));
+    to_codels(&pixels, codel_size, default_color, syscalls_enabled)
+}
+
+/* Writes a matrix of colors as an RGB PNG picture, each color being drawn as a codel_size x
+ * codel_size square.
+ * */
+pub fn write_picture<W: io::Write>(
+    output: W,
+    colors: &[Vec<PietColor>],
+    codel_size: usize,
+) -> io::Result<()> {
+    let pixels: Vec<Vec<[u8; 3]>> = colors
+        .iter()
+        .map(|row| row.iter().map(|color| color.to_rgb()).collect())
+        .collect();
+    write_pixels(output, &pixels, codel_size)
+}
+
+/* Writes a matrix of RGB values as a PNG picture, each value being drawn as a codel_size x
+ * codel_size square.
+ * */
+pub fn write_pixels<W: io::Write>(
+    output: W,
+    pixels: &[Vec<[u8; 3]>],
+    codel_size: usize,
+) -> io::Result<()> {
+    use png::HasParameters;
+
+    let (width, height) = (pixels[0].len() * codel_size, pixels.len() * codel_size);
+    let mut data = Vec::with_capacity(width * height * 3);
+    for row in pixels.iter() {
+        for _ in 0..codel_size {
+            for pixel in row.iter() {
+                for _ in 0..codel_size {
+                    data.extend_from_slice(pixel);
+                }
+            }
+        }
+    }
+    let mut encoder = png::Encoder::new(output, width as u32, height as u32);
+    encoder.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/* The result of looking for the codel the interpreter should move to next.
+ * from: The codel the interpreter leaves from, i.e. the farthest codel of the current block
+ * when the first attempt failed, the starting codel otherwise.
+ * exit: The farthest codel of the block in the direction of dp and cc, the one the interpreter
+ * actually leaves the block from.
+ * to: The codel that will be entered.
+ * block_size: The size of the block being left, 0 for white blocks.
+ * dp, cc: The direction pointer and codel chooser once every failed attempt has been applied.
+ * */
+pub struct Move<'a> {
+    pub from: Codel,
+    // Only the TUI draws where moves start
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    pub exit: &'a Codel,
+    pub to: &'a Codel,
+    pub block_size: usize,
+    pub dp: Direction,
+    pub cc: Direction,
+}
+
+/* Tries to leave the block the codel `cur_codel` belongs to, alternately toggling cc and
+ * rotating dp when the way is blocked.
+ * returns: None if the program terminates because all 8 attempts failed, the Move to perform
+ * otherwise.
+ * */
+pub fn next_move<'a>(
+    picture: &'a [Vec<Codel>],
+    cur_codel: &Codel,
+    dp: Direction,
+    cc: Direction,
+) -> Option<Move<'a>> {
+    let (mut cur_codel, mut dp, mut cc) = (cur_codel.clone(), dp, cc);
+    let mut attempts = 0;
+    loop {
+        let (block_size, farthest_codel) =
+            get_farthest_codel(picture, cur_codel.x, cur_codel.y, dp, cc);
+        match (attempts % 2, can_go_in_direction(picture, farthest_codel, dp)) {
+            (_, (true, codel)) => {
+                let exit = farthest_codel;
+                return Some(Move { from: cur_codel, exit, to: codel, block_size, dp, cc });
+            },
+            (0, (false, codel)) => {
+                cur_codel = codel.clone();
+                cc = cc.opposite();
+            },
+            (1, (false, codel)) => {
+                cur_codel = codel.clone();
+                dp = dp.rotate();
+            },
+            _ => unreachable!(),
+        }
+        attempts += 1;
+        if attempts >= 8 {
+            return None;
+        }
+    }
+}
+
+/* A color block: a set of contiguous codels of the same color.
+ * codels: The (x, y) positions of the codels of the block, in reading order.
+ * */
+pub struct Block {
+    pub codels: Vec<(usize, usize)>,
+}
+
+/* The color blocks of a picture.
+ * ids: A matrix of the same size as the picture giving the index of the block each codel
+ * belongs to.
+ * blocks: The blocks, in the order in which their first codel appears in the picture.
+ * */
+pub struct Blocks {
+    pub ids: Vec<Vec<usize>>,
+    pub blocks: Vec<Block>,
+}
+
+impl Blocks {
+    pub fn block_of(&self, codel: &Codel) -> &Block {
+        &self.blocks[self.ids[codel.y][codel.x]]
+    }
+}
+
+/* Splits the picture into color blocks. White and black regions are blocks too, even though
+ * the interpreter never treats them as a whole.
+ * */
+pub fn get_blocks(picture: &[Vec<Codel>]) -> Blocks {
+    let unlabeled = usize::max_value();
+    let mut ids: Vec<Vec<usize>> = picture.iter().map(|row| vec![unlabeled; row.len()]).collect();
+    let mut blocks = Vec::new();
+    for y in 0..picture.len() {
+        for x in 0..picture[y].len() {
+            if ids[y][x] != unlabeled {
+                continue;
+            }
+            let color = picture[y][x].color;
+            let mut codels = vec![];
+            let mut codels_to_visit = vec![(x, y)];
+            ids[y][x] = blocks.len();
+            while let Some((tmpx, tmpy)) = codels_to_visit.pop() {
+                codels.push((tmpx, tmpy));
+                let mut neighbours = vec![];
+                if tmpy + 1 < picture.len() && tmpx < picture[tmpy + 1].len() {
+                    neighbours.push((tmpx, tmpy + 1));
+                }
+                if tmpy > 0 && tmpx < picture[tmpy - 1].len() {
+                    neighbours.push((tmpx, tmpy - 1));
+                }
+                if tmpx + 1 < picture[tmpy].len() {
+                    neighbours.push((tmpx + 1, tmpy));
+                }
+                if tmpx > 0 {
+                    neighbours.push((tmpx - 1, tmpy));
+                }
+                for (nx, ny) in neighbours {
+                    if ids[ny][nx] == unlabeled && picture[ny][nx].color == color {
+                        ids[ny][nx] = blocks.len();
+                        codels_to_visit.push((nx, ny));
+                    }
+                }
+            }
+            codels.sort_by_key(|&(x, y)| (y, x));
+            blocks.push(Block { codels });
+        }
+    }
+    Blocks { ids, blocks }
+}
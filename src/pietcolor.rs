@@ -1,4 +1,4 @@
-#[cfg(feature = "default")]
+#[cfg(feature = "tui")]
 extern crate termion;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -150,103 +150,259 @@ impl PietColor {
     pub fn diff_to(self, other: &PietColor) -> (usize, usize) {
         (self.hue.diff_to(&other.hue), self.lightness.diff_to(&other.lightness))
     }
+
+    /* Returns the color that is `diff` away from this one, diff being a (hue, lightness)
+     * difference as returned by diff_to. Only meaningful for the 18 colors of the hue cycle.
+     * */
+    pub fn shifted(self, diff: (usize, usize)) -> PietColor {
+        use self::Hue::*;
+        use self::Lightness::*;
+        let hues = [Red, Yellow, Green, Cyan, Blue, Magenta];
+        let lightnesses = [Light, Normal, Dark];
+        let hue = hues.iter().position(|h| *h == self.hue).unwrap();
+        let lightness = lightnesses.iter().position(|l| *l == self.lightness).unwrap();
+        PietColor {
+            hue: hues[(hue + diff.0) % hues.len()],
+            lightness: lightnesses[(lightness + diff.1) % lightnesses.len()],
+        }
+    }
+
+    /* Returns the color of a pixel, None if the pixel isn't one of the colors of Piet. */
+    pub fn from_rgb(pixel: &[u8]) -> Option<PietColor> {
+        use self::Hue::*;
+        use self::Lightness::*;
+        match pixel {
+            &[0xFF, 0xC0, 0xC0] => Some(PietColor { hue: Red, lightness: Light }),
+            &[0xFF, 0x00, 0x00] => Some(PietColor { hue: Red, lightness: Normal }),
+            &[0xC0, 0x00, 0x00] => Some(PietColor { hue: Red, lightness: Dark }),
+            &[0xFF, 0xFF, 0xC0] => Some(PietColor { hue: Yellow, lightness: Light }),
+            &[0xFF, 0xFF, 0x00] => Some(PietColor { hue: Yellow, lightness: Normal }),
+            &[0xC0, 0xC0, 0x00] => Some(PietColor { hue: Yellow, lightness: Dark }),
+            &[0xC0, 0xFF, 0xC0] => Some(PietColor { hue: Green, lightness: Light }),
+            &[0x00, 0xFF, 0x00] => Some(PietColor { hue: Green, lightness: Normal }),
+            &[0x00, 0xC0, 0x00] => Some(PietColor { hue: Green, lightness: Dark }),
+            &[0xC0, 0xFF, 0xFF] => Some(PietColor { hue: Cyan, lightness: Light }),
+            &[0x00, 0xFF, 0xFF] => Some(PietColor { hue: Cyan, lightness: Normal }),
+            &[0x00, 0xC0, 0xC0] => Some(PietColor { hue: Cyan, lightness: Dark }),
+            &[0xC0, 0xC0, 0xFF] => Some(PietColor { hue: Blue, lightness: Light }),
+            &[0x00, 0x00, 0xFF] => Some(PietColor { hue: Blue, lightness: Normal }),
+            &[0x00, 0x00, 0xC0] => Some(PietColor { hue: Blue, lightness: Dark }),
+            &[0xFF, 0xC0, 0xFF] => Some(PietColor { hue: Magenta, lightness: Light }),
+            &[0xFF, 0x00, 0xFF] => Some(PietColor { hue: Magenta, lightness: Normal }),
+            &[0xC0, 0x00, 0xC0] => Some(PietColor { hue: Magenta, lightness: Dark }),
+            &[0x00, 0x00, 0x00] => Some(PietColor { hue: Black, lightness: Normal }),
+            &[0xFF, 0xFF, 0xFF] => Some(PietColor { hue: White, lightness: Normal }),
+            &[0xC0, 0xC0, 0xC0] => Some(PietColor { hue: Smoke, lightness: Normal }),
+            _ => None,
+        }
+    }
+
+    pub fn to_rgb(self) -> [u8; 3] {
+        use self::Lightness::*;
+        match self.hue {
+            Hue::Red => {
+                match self.lightness {
+                    Light => [0xFF, 0xC0, 0xC0],
+                    Normal => [0xFF, 0x00, 0x00],
+                    Dark => [0xC0, 0x00, 0x00],
+                }
+            },
+            Hue::Yellow => {
+                match self.lightness {
+                    Light => [0xFF, 0xFF, 0xC0],
+                    Normal => [0xFF, 0xFF, 0x00],
+                    Dark => [0xC0, 0xC0, 0x00],
+                }
+            },
+            Hue::Green => {
+                match self.lightness {
+                    Light => [0xC0, 0xFF, 0xC0],
+                    Normal => [0x00, 0xFF, 0x00],
+                    Dark => [0x00, 0xC0, 0x00],
+                }
+            },
+            Hue::Cyan => {
+                match self.lightness {
+                    Light => [0xC0, 0xFF, 0xFF],
+                    Normal => [0x00, 0xFF, 0xFF],
+                    Dark => [0x00, 0xC0, 0xC0],
+                }
+            },
+            Hue::Blue => {
+                match self.lightness {
+                    Light => [0xC0, 0xC0, 0xFF],
+                    Normal => [0x00, 0x00, 0xFF],
+                    Dark => [0x00, 0x00, 0xC0],
+                }
+            },
+            Hue::Magenta => {
+                match self.lightness {
+                    Light => [0xFF, 0xC0, 0xFF],
+                    Normal => [0xFF, 0x00, 0xFF],
+                    Dark => [0xC0, 0x00, 0xC0],
+                }
+            },
+            Hue::Black => [0x00, 0x00, 0x00],
+            Hue::Smoke => [0xC0, 0xC0, 0xC0],
+            Hue::White => [0xFF, 0xFF, 0xFF],
+        }
+    }
 }
 
-#[cfg(feature = "default")]
-impl ::std::fmt::Display for PietColor {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+#[cfg(feature = "tui")]
+impl PietColor {
+    /* Returns the color of the 256 colors palette of terminals that is the closest to this one. */
+    pub fn ansi_value(self) -> u8 {
         use self::Lightness::*;
-        use termion::color::*;
         match self.hue {
             Hue::Red => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LR", Bg(AnsiValue(217)), Fg(AnsiValue(217))),
-                    Normal => write!(f, "{}{}NR", Bg(AnsiValue(196)), Fg(AnsiValue(196))),
-                    Dark => write!(f, "{}{}DR", Bg(AnsiValue(124)), Fg(AnsiValue(124))),
+                    Light => 217,
+                    Normal => 196,
+                    Dark => 124,
                 }
             },
             Hue::Yellow => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LY", Bg(AnsiValue(229)), Fg(AnsiValue(229))),
-                    Normal => write!(f, "{}{}NY", Bg(AnsiValue(226)), Fg(AnsiValue(226))),
-                    Dark => write!(f, "{}{}DY", Bg(AnsiValue(142)), Fg(AnsiValue(142))),
+                    Light => 229,
+                    Normal => 226,
+                    Dark => 142,
                 }
             },
             Hue::Green => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LG", Bg(AnsiValue(157)), Fg(AnsiValue(157))),
-                    Normal => write!(f, "{}{}NG", Bg(AnsiValue(46)), Fg(AnsiValue(46))),
-                    Dark => write!(f, "{}{}DG", Bg(AnsiValue(34)), Fg(AnsiValue(34))),
+                    Light => 157,
+                    Normal => 46,
+                    Dark => 34,
                 }
             },
             Hue::Cyan => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LC", Bg(AnsiValue(159)), Fg(AnsiValue(159))),
-                    Normal => write!(f, "{}{}NC", Bg(AnsiValue(51)), Fg(AnsiValue(51))),
-                    Dark => write!(f, "{}{}DC", Bg(AnsiValue(37)), Fg(AnsiValue(37))),
+                    Light => 159,
+                    Normal => 51,
+                    Dark => 37,
                 }
             },
             Hue::Blue => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LB", Bg(AnsiValue(147)), Fg(AnsiValue(147))),
-                    Normal => write!(f, "{}{}NB", Bg(AnsiValue(21)), Fg(AnsiValue(21))),
-                    Dark => write!(f, "{}{}DB", Bg(AnsiValue(19)), Fg(AnsiValue(19))),
+                    Light => 147,
+                    Normal => 21,
+                    Dark => 19,
                 }
             },
             Hue::Magenta => {
                 match self.lightness {
-                    Light => write!(f, "{}{}LM", Bg(AnsiValue(219)), Fg(AnsiValue(219))),
-                    Normal => write!(f, "{}{}NM", Bg(AnsiValue(201)), Fg(AnsiValue(201))),
-                    Dark => write!(f, "{}{}DM", Bg(AnsiValue(127)), Fg(AnsiValue(127))),
+                    Light => 219,
+                    Normal => 201,
+                    Dark => 127,
                 }
             },
-            Hue::White => write!(f, "{}{}WW", Bg(AnsiValue(231)), Fg(AnsiValue(231))),
-            Hue::Smoke => write!(f, "{}{}SS", Bg(AnsiValue(250)), Fg(AnsiValue(250))),
-            Hue::Black => write!(f, "{}{}BB", Bg(AnsiValue(0)), Fg(AnsiValue(0))),
+            Hue::White => 231,
+            Hue::Smoke => 250,
+            Hue::Black => 0,
+        }
+    }
+}
+
+/* How colors are drawn in terminals.
+ * TrueColor: The exact RGB values, for terminals that support 24-bit colors.
+ * Ansi256: The closest colors of the 256 colors palette.
+ * Ansi16: The 8 basic colors and their bright variants, which every terminal has. They can't
+ * tell all lightnesses apart, so codels are labelled with the initials of their color.
+ * */
+#[cfg(feature = "tui")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+#[cfg(feature = "tui")]
+impl ColorMode {
+    /* Returns the mode called `name` on the command line, "truecolor", "256" or "16". */
+    pub fn from_name(name: &str) -> Option<ColorMode> {
+        match name {
+            "truecolor" => Some(ColorMode::TrueColor),
+            "256" => Some(ColorMode::Ansi256),
+            "16" => Some(ColorMode::Ansi16),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "tui")]
+impl PietColor {
+    /* Returns the basic color closest to this one, as the number used by the escape codes, and
+     * whether its bright variant should be used.
+     * */
+    fn ansi16(self) -> (u8, bool) {
+        let bright = self.lightness != Lightness::Dark;
+        match self.hue {
+            Hue::Black => (0, false),
+            Hue::Red => (1, bright),
+            Hue::Green => (2, bright),
+            Hue::Yellow => (3, bright),
+            Hue::Blue => (4, bright),
+            Hue::Magenta => (5, bright),
+            Hue::Cyan => (6, bright),
+            Hue::Smoke => (7, false),
+            Hue::White => (7, true),
         }
-        // Hue::Red => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(255, 192, 192))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(255, 0, 0))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(192, 0, 0))),
-        //     }
-        // },
-        // Hue::Yellow => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(255, 255, 192))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(255, 255, 0))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(192, 192, 0))),
-        //     }
-        // },
-        // Hue::Green => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(192, 255, 192))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(0, 255, 0))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(0, 192, 0))),
-        //     }
-        // },
-        // Hue::Cyan => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(192, 255, 255))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(0, 255, 255))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(255, 192, 192))),
-        //     }
-        // },
-        // Hue::Blue => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(192, 192, 255))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(0, 0, 255))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(0, 0, 192))),
-        //     }
-        // },
-        // Hue::Magenta => {
-        //     match self.lightness {
-        //         Light => write!(f, "{} ", Bg(Rgb(255, 192, 255))),
-        //         Normal => write!(f, "{} ", Bg(Rgb(255, 0, 255))),
-        //         Dark => write!(f, "{} ", Bg(Rgb(192, 0, 192))),
-        //     }
-        // },
-        // Hue::White => write!(f, "{} ", Bg(Rgb(255, 255, 255))),
-        // Hue::Black => write!(f, "{} ", Bg(Rgb(0, 0, 0))),
+    }
+
+    /* returns: The escape code setting the foreground color to this color. */
+    pub fn fg(self, mode: ColorMode) -> String {
+        use termion::color::*;
+        match mode {
+            ColorMode::TrueColor => {
+                let [red, green, blue] = self.to_rgb();
+                Fg(Rgb(red, green, blue)).to_string()
+            },
+            ColorMode::Ansi256 => Fg(AnsiValue(self.ansi_value())).to_string(),
+            ColorMode::Ansi16 => {
+                let (value, bright) = self.ansi16();
+                format!("\x1b[{}m", if bright { 90 } else { 30 } + value)
+            },
+        }
+    }
+
+    /* returns: The escape code setting the background color to this color. */
+    pub fn bg(self, mode: ColorMode) -> String {
+        use termion::color::*;
+        match mode {
+            ColorMode::TrueColor => {
+                let [red, green, blue] = self.to_rgb();
+                Bg(Rgb(red, green, blue)).to_string()
+            },
+            ColorMode::Ansi256 => Bg(AnsiValue(self.ansi_value())).to_string(),
+            ColorMode::Ansi16 => {
+                let (value, bright) = self.ansi16();
+                format!("\x1b[{}m", if bright { 100 } else { 40 } + value)
+            },
+        }
+    }
+
+    /* Returns the two characters showing a codel of this color: the initials of the color, like
+     * LR for light red. They are only readable with Ansi16, where colors alone are ambiguous.
+     * */
+    pub fn cell(self, mode: ColorMode) -> String {
+        let initials: String = match self.hue {
+            Hue::White => String::from("WW"),
+            Hue::Smoke => String::from("SS"),
+            Hue::Black => String::from("BB"),
+            hue => {
+                let name = format!("{:?}{:?}", self.lightness, hue);
+                name.chars().filter(|c| c.is_uppercase()).collect()
+            },
+        };
+        let foreground = match (mode, self.ansi16()) {
+            (ColorMode::Ansi16, (0, _)) | (ColorMode::Ansi16, (1..=6, false)) => {
+                String::from("\x1b[97m")
+            },
+            (ColorMode::Ansi16, _) => String::from("\x1b[30m"),
+            _ => self.fg(mode),
+        };
+        format!("{}{}{}", self.bg(mode), foreground, initials)
     }
 }
@@ -0,0 +1,185 @@
+use codel::*;
+use instruction::*;
+use interpreter::*;
+use picture::*;
+use std::*;
+
+/* Counts how often a program enters each color block and executes each instruction, to find the
+ * regions of a program where it spends its time.
+ * visits: The number of times each block was entered, indexed like blocks.blocks.
+ * */
+pub struct Profiler {
+    blocks: Blocks,
+    visits: Vec<u64>,
+    instructions: collections::HashMap<Instruction, u64>,
+    steps: u64,
+}
+
+impl Profiler {
+    /* Returns a profiler for a program starting on the top-left codel, whose block counts as
+     * visited once.
+     * */
+    pub fn new(picture: &[Vec<Codel>]) -> Profiler {
+        let blocks = get_blocks(picture);
+        let mut visits = vec![0; blocks.blocks.len()];
+        visits[blocks.ids[0][0]] = 1;
+        Profiler { blocks, visits, instructions: collections::HashMap::new(), steps: 0 }
+    }
+
+    /* Forgets the counts, for a new run of the program. */
+    pub fn reset(&mut self) {
+        for visits in self.visits.iter_mut() {
+            *visits = 0;
+        }
+        self.visits[self.blocks.ids[0][0]] = 1;
+        self.instructions.clear();
+        self.steps = 0;
+    }
+
+    pub fn record(&mut self, step: &Step) {
+        self.visits[self.blocks.ids[step.to.y][step.to.x]] += 1;
+        *self.instructions.entry(step.instruction).or_insert(0) += 1;
+        self.steps += 1;
+    }
+
+    /* Returns tables of the `top` most visited blocks and most executed instructions. */
+    pub fn report(&self, picture: &[Vec<Codel>], top: usize) -> String {
+        let visited = (0..self.visits.len()).filter(|&id| self.visits[id] > 0);
+        let mut blocks: Vec<usize> = visited.collect();
+        blocks.sort_by_key(|&id| cmp::Reverse(self.visits[id]));
+        let mut report = format!("Profile of {} steps\n{:>12}  block\n", self.steps, "visits");
+        for &id in blocks.iter().take(top) {
+            let block = &self.blocks.blocks[id];
+            let (x, y) = block.codels[0];
+            let color = picture[y][x].color;
+            report.push_str(&format!(
+                "{:>12}  ({}, {}) {:?} {:?}, {} codels\n",
+                self.visits[id],
+                x,
+                y,
+                color.lightness,
+                color.hue,
+                block.codels.len()
+            ));
+        }
+
+        let mut instructions: Vec<(&Instruction, &u64)> = self.instructions.iter().collect();
+        instructions.sort_by_key(|&(instruction, &count)| {
+            (cmp::Reverse(count), instruction.name())
+        });
+        report.push_str(&format!("{:>12}  instruction\n", "count"));
+        for &(instruction, count) in instructions.iter().take(top) {
+            report.push_str(&format!("{:>12}  {}\n", count, instruction.name()));
+        }
+        report
+    }
+
+    /* Returns the colors of the picture overlaid with the number of visits of their block, from
+     * blue for the least visited blocks to red for the most visited ones. Counts are on a
+     * logarithmic scale, and blocks that were never visited are grayed out.
+     * */
+    pub fn heatmap(&self, picture: &[Vec<Codel>]) -> Vec<Vec<[u8; 3]>> {
+        let most = self.visits.iter().cloned().max().unwrap_or(0);
+        let scale = ((most + 1) as f64).ln();
+        let mix = |a: [u8; 3], b: [u8; 3], t: f64| {
+            let channel = |i: usize| (a[i] as f64 * (1.0 - t) + b[i] as f64 * t).round() as u8;
+            [channel(0), channel(1), channel(2)]
+        };
+        picture
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|codel| {
+                        let color = codel.color.to_rgb();
+                        let visits = self.visits[self.blocks.ids[codel.y][codel.x]];
+                        if visits == 0 {
+                            return mix(color, [0x80, 0x80, 0x80], 0.75);
+                        }
+                        let heat =
+                            if scale > 0.0 { ((visits + 1) as f64).ln() / scale } else { 1.0 };
+                        mix(color, mix([0, 0, 0xFF], [0xFF, 0, 0], heat), 0.6)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::*;
+    use pietcolor::*;
+
+    fn color(hue: Hue, lightness: Lightness) -> PietColor {
+        PietColor { hue, lightness }
+    }
+
+    /* Pushes going right and pops going left forever, next to a block it never enters. */
+    fn picture() -> Vec<Vec<Codel>> {
+        let row: Vec<[u8; 3]> = [
+            color(Hue::Red, Lightness::Light),
+            color(Hue::Red, Lightness::Normal),
+            color(Hue::Black, Lightness::Normal),
+            color(Hue::Blue, Lightness::Dark),
+        ]
+        .iter()
+        .map(|color| color.to_rgb())
+        .collect();
+        to_codels(&[row], 1, color(Hue::White, Lightness::Normal), false)
+    }
+
+    fn run(picture: &[Vec<Codel>], profiler: &mut Profiler, count: usize) {
+        let machine = Machine::with_io(false, Box::new(io::empty()), Box::new(io::sink()));
+        let mut interpreter = Interpreter::new(picture, machine);
+        for _ in 0..count {
+            profiler.record(&interpreter.step().unwrap());
+        }
+    }
+
+    #[test]
+    fn counts_blocks_and_instructions() {
+        let picture = picture();
+        let mut profiler = Profiler::new(&picture);
+        run(&picture, &mut profiler, 10);
+        assert_eq!(
+            profiler.report(&picture, 10),
+            "Profile of 10 steps\n      visits  block\n\
+             \x20          6  (0, 0) Light Red, 1 codels\n\
+             \x20          5  (1, 0) Normal Red, 1 codels\n\
+             \x20      count  instruction\n\
+             \x20          5  pop\n\
+             \x20          5  push\n"
+        );
+        let top = profiler.report(&picture, 1);
+        assert!(top.contains("(0, 0) Light Red") && !top.contains("(1, 0) Normal Red"));
+        assert!(top.ends_with("count  instruction\n           5  pop\n"));
+    }
+
+    #[test]
+    fn draws_the_heatmap() {
+        let picture = picture();
+        let mut profiler = Profiler::new(&picture);
+        run(&picture, &mut profiler, 10);
+        // The most visited block is the reddest, blocks that weren't visited are grayed out
+        assert_eq!(
+            profiler.heatmap(&picture),
+            vec![vec![[255, 77, 77], [243, 0, 12], [96, 96, 96], [96, 96, 144]]]
+        );
+    }
+
+    #[test]
+    fn forgets_counts_on_reset() {
+        let picture = picture();
+        let mut profiler = Profiler::new(&picture);
+        let fresh = profiler.report(&picture, 10);
+        run(&picture, &mut profiler, 10);
+        profiler.reset();
+        assert_eq!(profiler.report(&picture, 10), fresh);
+        assert_eq!(profiler.visits, vec![1, 0, 0, 0]);
+
+        run(&picture, &mut profiler, 1);
+        assert!(profiler.report(&picture, 10).starts_with("Profile of 1 steps\n"));
+        assert_eq!(profiler.visits, vec![1, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,231 @@
+use codel::*;
+use interpreter::*;
+use picture::*;
+use std::*;
+
+/* Drawing of the execution of a program as a sequence of frames, each showing the picture with
+ * the current color block outlined and an arrow on the current codel pointing in the direction
+ * of dp, a dot next to it showing the side cc points to. Frames are drawn with the colors of a
+ * small palette, so that they can be written as GIF frames as they are.
+ * */
+
+// Color of the outline of the current block, which isn't a Piet color
+const OUTLINE: [u8; 3] = [0x80, 0x80, 0x80];
+// How long frames are shown in the GIF, in hundredths of a second, the last one staying longer
+const FRAME_DELAY: u16 = 10;
+const LAST_FRAME_DELAY: u16 = 200;
+
+/* scale: The size of the square of pixels each codel is drawn as.
+ * palette: Every color the frames use, frames being made of indexes in it.
+ * background: The picture without the overlays.
+ * */
+pub struct Renderer {
+    blocks: Blocks,
+    scale: usize,
+    pub width: usize,
+    pub height: usize,
+    palette: Vec<[u8; 3]>,
+    background: Vec<u8>,
+}
+
+impl Renderer {
+    pub fn new(picture: &[Vec<Codel>], scale: usize) -> Renderer {
+        let mut palette = vec![OUTLINE];
+        for row in picture.iter() {
+            for codel in row.iter() {
+                let color = codel.color.to_rgb();
+                if !palette.contains(&color) {
+                    palette.push(color);
+                }
+            }
+        }
+        for color in [[0, 0, 0], [0xFF, 0xFF, 0xFF]].iter() {
+            if !palette.contains(color) {
+                palette.push(*color);
+            }
+        }
+        let (width, height) = (picture[0].len() * scale, picture.len() * scale);
+        let mut background = vec![0; width * height];
+        for row in picture.iter() {
+            for codel in row.iter() {
+                let color = codel.color.to_rgb();
+                let index = palette.iter().position(|c| *c == color).unwrap() as u8;
+                for y in codel.y * scale..(codel.y + 1) * scale {
+                    for x in codel.x * scale..(codel.x + 1) * scale {
+                        background[y * width + x] = index;
+                    }
+                }
+            }
+        }
+        Renderer { blocks: get_blocks(picture), scale, width, height, palette, background }
+    }
+
+    fn index_of(&self, color: [u8; 3]) -> u8 {
+        self.palette.iter().position(|c| *c == color).unwrap() as u8
+    }
+
+    /* Returns whether the codel at (x, y), which can be outside of the picture, belongs to the
+     * block `id`.
+     * */
+    fn in_block(&self, x: isize, y: isize, id: usize) -> bool {
+        if x < 0 || y < 0 || y as usize >= self.blocks.ids.len() {
+            return false;
+        }
+        let row = &self.blocks.ids[y as usize];
+        (x as usize) < row.len() && row[x as usize] == id
+    }
+
+    /* Draws the state of the interpreter.
+     * returns: The indexes in the palette of the pixels of the frame, row by row.
+     * */
+    pub fn frame(&self, interpreter: &Interpreter) -> Vec<u8> {
+        let mut frame = self.background.clone();
+        let scale = self.scale;
+        let id = self.blocks.ids[interpreter.codel.y][interpreter.codel.x];
+        let outline = self.index_of(OUTLINE);
+        let thickness = cmp::max(1, scale / 8);
+        for &(x, y) in self.blocks.blocks[id].codels.iter() {
+            let (cx, cy) = (x as isize, y as isize);
+            let left = !self.in_block(cx - 1, cy, id);
+            let right = !self.in_block(cx + 1, cy, id);
+            let up = !self.in_block(cx, cy - 1, id);
+            let down = !self.in_block(cx, cy + 1, id);
+            for dy in 0..scale {
+                for dx in 0..scale {
+                    let border = (left && dx < thickness)
+                        || (right && dx >= scale - thickness)
+                        || (up && dy < thickness)
+                        || (down && dy >= scale - thickness);
+                    if border {
+                        frame[(y * scale + dy) * self.width + x * scale + dx] = outline;
+                    }
+                }
+            }
+        }
+
+        // Black arrows on light colors, white ones on dark colors
+        let [red, green, blue] = interpreter.codel.color.to_rgb();
+        let luminance = 0.299 * red as f64 + 0.587 * green as f64 + 0.114 * blue as f64;
+        let ink = self.index_of(if luminance < 128.0 { [0xFF, 0xFF, 0xFF] } else { [0, 0, 0] });
+        let (x0, y0) = (interpreter.codel.x * scale, interpreter.codel.y * scale);
+        let size = scale as f64;
+        // The arrow is drawn as if dp pointed right, u going along dp and v across it
+        for u in 0..scale {
+            for v in 0..scale {
+                let (along, across) = (u as f64 + 0.5, v as f64 + 0.5);
+                let (base, tip) = (size / 4.0, size * 3.0 / 4.0);
+                let half_width = size / 4.0 * (tip - along) / (tip - base);
+                if along < base || along > tip || (across - size / 2.0).abs() > half_width {
+                    continue;
+                }
+                let (dx, dy) = match interpreter.dp {
+                    Direction::Right => (u, v),
+                    Direction::Left => (scale - 1 - u, v),
+                    Direction::Down => (v, u),
+                    Direction::Up => (v, scale - 1 - u),
+                };
+                frame[(y0 + dy) * self.width + x0 + dx] = ink;
+            }
+        }
+        let (sx, sy) = interpreter.cc.relative_to(interpreter.dp).to_vector();
+        let dot = cmp::max(1, scale / 8) as isize;
+        let offset = (scale * 3 / 8) as isize;
+        let (cx, cy) = ((scale / 2) as isize + sx * offset, (scale / 2) as isize + sy * offset);
+        for dy in cy - dot / 2..cy - dot / 2 + dot {
+            for dx in cx - dot / 2..cx - dot / 2 + dot {
+                if dx >= 0 && dy >= 0 && (dx as usize) < scale && (dy as usize) < scale {
+                    frame[(y0 + dy as usize) * self.width + x0 + dx as usize] = ink;
+                }
+            }
+        }
+        frame
+    }
+
+    /* returns: The RGB values of the pixels of a frame. */
+    pub fn pixels(&self, frame: &[u8]) -> Vec<Vec<[u8; 3]>> {
+        frame
+            .chunks(self.width)
+            .map(|row| row.iter().map(|&index| self.palette[index as usize]).collect())
+            .collect()
+    }
+}
+
+/* Where frames are written.
+ * Gif: An animated GIF. Frames are written once the next one is known, so that the last one can
+ * be shown longer.
+ * Pngs: A directory in which each frame is written as a PNG picture, frame-00000.png being the
+ * first one.
+ * */
+pub enum Video {
+    Gif { encoder: gif::Encoder<fs::File>, pending: Option<Vec<u8>> },
+    Pngs { directory: String, count: usize },
+}
+
+impl Video {
+    /* Creates a GIF if `path` ends with .gif, a directory of PNG pictures otherwise. */
+    pub fn create(path: &str, renderer: &Renderer) -> Result<Video, String> {
+        use gif::SetParameter;
+        if !path.ends_with(".gif") {
+            fs::create_dir_all(path).map_err(|e| format!("can't create {}: {}", path, e))?;
+            return Ok(Video::Pngs { directory: String::from(path), count: 0 });
+        }
+        if renderer.width > u16::MAX as usize || renderer.height > u16::MAX as usize {
+            return Err(format!(
+                "frames of {}x{} pixels are too large for a GIF, see --frame-scale",
+                renderer.width,
+                renderer.height
+            ));
+        }
+        let palette: Vec<u8> = renderer.palette.iter().flat_map(|c| c.iter().cloned()).collect();
+        let file = fs::File::create(path).map_err(|e| format!("can't create {}: {}", path, e))?;
+        let (width, height) = (renderer.width as u16, renderer.height as u16);
+        let mut encoder = gif::Encoder::new(file, width, height, &palette)
+            .map_err(|e| format!("can't write {}: {}", path, e))?;
+        encoder.set(gif::Repeat::Infinite).map_err(|e| format!("can't write {}: {}", path, e))?;
+        Ok(Video::Gif { encoder, pending: None })
+    }
+
+    fn write_gif_frame(
+        encoder: &mut gif::Encoder<fs::File>,
+        renderer: &Renderer,
+        frame: &[u8],
+        delay: u16,
+    ) -> io::Result<()> {
+        let (width, height) = (renderer.width as u16, renderer.height as u16);
+        let mut frame = gif::Frame::from_indexed_pixels(width, height, frame, None);
+        frame.delay = delay;
+        encoder.write_frame(&frame)
+    }
+
+    pub fn write(&mut self, renderer: &Renderer, frame: Vec<u8>) -> io::Result<()> {
+        match *self {
+            Video::Gif { ref mut encoder, ref mut pending } => {
+                if let Some(previous) = pending.take() {
+                    Video::write_gif_frame(encoder, renderer, &previous, FRAME_DELAY)?;
+                }
+                *pending = Some(frame);
+                Ok(())
+            },
+            Video::Pngs { ref directory, ref mut count } => {
+                let path = path::Path::new(directory).join(format!("frame-{:05}.png", count));
+                *count += 1;
+                fs::File::create(path).and_then(|f| write_pixels(f, &renderer.pixels(&frame), 1))
+            },
+        }
+    }
+
+    /* Writes the last frame. */
+    pub fn finish(&mut self, renderer: &Renderer) -> io::Result<()> {
+        match *self {
+            Video::Gif { ref mut encoder, ref mut pending } => {
+                match pending.take() {
+                    Some(last) => {
+                        Video::write_gif_frame(encoder, renderer, &last, LAST_FRAME_DELAY)
+                    },
+                    None => Ok(()),
+                }
+            },
+            Video::Pngs { .. } => Ok(()),
+        }
+    }
+}
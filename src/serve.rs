@@ -0,0 +1,402 @@
+use codel::*;
+use interpreter::*;
+use machine::*;
+use picture::*;
+use pietcolor::*;
+use serde_json::Value;
+use std::*;
+
+use std::io::prelude::*;
+
+/* A web viewer of a program, for the serve command. The page, which is bundled in the binary,
+ * shows the picture in a canvas with controls to step and run the program, and talks to the
+ * interpreter through a JSON API:
+ * GET /api/picture: The size of the picture and the colors of its codels, row by row.
+ * GET /api/state: The state of the program.
+ * POST /api/step: Performs {"steps": n} steps, 1 if the body is empty, and returns the state.
+ * POST /api/restart: Starts the program again with {"input": text} as its input, and returns
+ * the state.
+ * Errors are answered with {"error": message}.
+ * */
+
+const PAGE: &str = include_str!("viewer.html");
+// Steps a single request can perform, so that the server keeps answering
+const MAX_STEPS: u64 = 1000000;
+const MAX_BODY: usize = 1 << 20;
+
+/* A run of the program being viewed.
+ * output: Where the program writes, text holding everything it wrote.
+ * error: Why the program stopped before terminating, if it did.
+ * */
+struct Run<'a> {
+    interpreter: Interpreter<'a>,
+    blocks: Blocks,
+    output: SharedBuffer,
+    text: String,
+    error: Option<String>,
+}
+
+impl<'a> Run<'a> {
+    fn new(picture: &'a [Vec<Codel>], input: &str) -> Run<'a> {
+        let output = SharedBuffer::new();
+        let input = Box::new(io::Cursor::new(input.as_bytes().to_vec()));
+        let machine = Machine::with_io(false, input, Box::new(output.clone()));
+        Run {
+            interpreter: Interpreter::new(picture, machine),
+            blocks: get_blocks(picture),
+            output,
+            text: String::new(),
+            error: None,
+        }
+    }
+
+    /* Performs at most `steps` steps, stopping if the program terminates or crashes, which
+     * some instructions do on bad input.
+     * */
+    fn step(&mut self, steps: u64) {
+        for _ in 0..cmp::min(steps, MAX_STEPS) {
+            if self.error.is_some() {
+                break;
+            }
+            let interpreter = &mut self.interpreter;
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| interpreter.step())) {
+                Ok(Some(_)) => {},
+                Ok(None) => break,
+                Err(e) => self.error = Some(format!("the program crashed: {}", crash_message(&e))),
+            }
+        }
+        self.text.push_str(&self.output.take());
+    }
+
+    fn state(&self) -> Value {
+        let interpreter = &self.interpreter;
+        let codel = &interpreter.codel;
+        // White codels don't make blocks for the interpreter, only the current one is shown
+        let block = if codel.color.hue == Hue::White {
+            vec![(codel.x, codel.y)]
+        } else {
+            self.blocks.block_of(codel).codels.clone()
+        };
+        let next = interpreter.peek().map(|(_, instruction)| instruction.name());
+        json!({
+            "steps": interpreter.steps,
+            "running": next.is_some() && self.error.is_none(),
+            "codel": {"x": codel.x, "y": codel.y},
+            "block": block,
+            "dp": interpreter.dp.name(),
+            "cc": interpreter.cc.name(),
+            "next": next,
+            "stack": interpreter.machine.stack,
+            "output": self.text,
+            "error": self.error,
+        })
+    }
+}
+
+fn picture_json(picture: &[Vec<Codel>]) -> Value {
+    let colors: Vec<Vec<String>> = picture
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|codel| {
+                    let [red, green, blue] = codel.color.to_rgb();
+                    format!("#{:02x}{:02x}{:02x}", red, green, blue)
+                })
+                .collect()
+        })
+        .collect();
+    json!({"width": picture[0].len(), "height": picture.len(), "colors": colors})
+}
+
+/* An HTTP request, the query being removed from the path. */
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 3 {
+        return Err(String::from("malformed request line"));
+    }
+    let (method, path) = (words[0].to_string(), words[1].split('?').next().unwrap().to_string());
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap().trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if name == "content-length" {
+            length = value.parse().map_err(|_| String::from("bad Content-Length"))?;
+        }
+    }
+    if length > MAX_BODY {
+        return Err(String::from("request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(Request { method, path, body })
+}
+
+/* Parses the body of a request as a JSON object, an empty body being an empty object. */
+fn parse_body(body: &[u8]) -> Result<Value, String> {
+    if body.iter().all(|byte| byte.is_ascii_whitespace()) {
+        return Ok(json!({}));
+    }
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) if value.is_object() => Ok(value),
+        Ok(_) => Err(String::from("the body has to be a JSON object")),
+        Err(e) => Err(format!("invalid JSON: {}", e)),
+    }
+}
+
+/* Answers a request.
+ * returns: The status, content type and body of the response.
+ * */
+fn handle<'a>(
+    request: &Request,
+    run: &mut Run<'a>,
+    picture: &'a [Vec<Codel>],
+) -> (&'static str, &'static str, Vec<u8>) {
+    let json = |status, value: Value| (status, "application/json", value.to_string().into_bytes());
+    let error = |status, message: String| json(status, json!({ "error": message }));
+    let body = || parse_body(&request.body);
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") | ("GET", "/index.html") => {
+            ("200 OK", "text/html; charset=utf-8", PAGE.as_bytes().to_vec())
+        },
+        ("GET", "/api/picture") => json("200 OK", picture_json(picture)),
+        ("GET", "/api/state") => json("200 OK", run.state()),
+        ("POST", "/api/step") => {
+            let steps = body().and_then(|body| match body.get("steps") {
+                None => Ok(1),
+                Some(steps) => {
+                    steps.as_u64().ok_or(String::from("steps has to be a positive integer"))
+                },
+            });
+            match steps {
+                Ok(steps) => {
+                    run.step(steps);
+                    json("200 OK", run.state())
+                },
+                Err(e) => error("400 Bad Request", e),
+            }
+        },
+        ("POST", "/api/restart") => {
+            let input = body().and_then(|body| match body.get("input") {
+                None => Ok(String::new()),
+                Some(input) => {
+                    input.as_str().map(String::from).ok_or(String::from("input has to be text"))
+                },
+            });
+            match input {
+                Ok(input) => {
+                    *run = Run::new(picture, &input);
+                    json("200 OK", run.state())
+                },
+                Err(e) => error("400 Bad Request", e),
+            }
+        },
+        (_, "/") | (_, "/index.html") | (_, "/api/picture") | (_, "/api/state") |
+        (_, "/api/step") | (_, "/api/restart") => {
+            error("405 Method Not Allowed", format!("{} isn't allowed here", request.method))
+        },
+        (_, path) => error("404 Not Found", format!("{} doesn't exist", path)),
+    }
+}
+
+/* Reads a request from the connection and answers it, closing the connection afterwards. */
+fn serve_connection<'a>(
+    stream: net::TcpStream,
+    run: &mut Run<'a>,
+    picture: &'a [Vec<Codel>],
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(5)))?;
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let (status, content_type, body) = match read_request(&mut reader) {
+        Ok(request) => handle(&request, run, picture),
+        Err(e) => {
+            let body = json!({ "error": e }).to_string().into_bytes();
+            ("400 Bad Request", "application/json", body)
+        },
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/* Serves the web viewer of the program on localhost until pieti is stopped. Port 0 lets the
+ * system choose a free port.
+ * */
+pub fn run_server(picture: &[Vec<Codel>], port: u16) {
+    let listener = match net::TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    };
+    match listener.local_addr() {
+        Ok(address) => eprintln!("Serving the viewer on http://{}/", address),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+    let mut run = Run::new(picture, "");
+    for stream in listener.incoming() {
+        let served = stream.and_then(|stream| serve_connection(stream, &mut run, picture));
+        if let Err(e) = served {
+            eprintln!("Error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use numbers::*;
+    use optimizer::*;
+
+    fn picture(ops: &[Op]) -> Vec<Vec<Codel>> {
+        let colors = layout(ops);
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, false)
+    }
+
+    fn hello() -> Vec<Vec<Codel>> {
+        let model = CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 };
+        picture(&print_program("Hello", &mut Synthesizer::new(model)))
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: String::from(method),
+            path: String::from(path),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    /* returns: The status of the response and its body, parsed as JSON. */
+    fn answer<'a>(
+        request: Request,
+        run: &mut Run<'a>,
+        picture: &'a [Vec<Codel>],
+    ) -> (&'static str, Value) {
+        let (status, content_type, body) = handle(&request, run, picture);
+        assert_eq!(content_type, "application/json");
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn reads_requests() {
+        let raw = "POST /api/step?debug HTTP/1.1\r\nHost: localhost\r\ncontent-length: 13\r\n\r\n\
+                   {\"steps\": 10}";
+        let request = read_request(&mut io::Cursor::new(raw.as_bytes())).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/step");
+        assert_eq!(request.body, b"{\"steps\": 10}".to_vec());
+
+        let request = read_request(&mut io::Cursor::new(&b"GET / HTTP/1.1\r\n\r\n"[..])).unwrap();
+        assert_eq!((request.method.as_str(), request.body.len()), ("GET", 0));
+        assert!(read_request(&mut io::Cursor::new(&b"GET /\r\n\r\n"[..])).is_err());
+        let large = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+        assert!(read_request(&mut io::Cursor::new(large.as_bytes())).is_err());
+    }
+
+    #[test]
+    fn parses_bodies() {
+        assert_eq!(parse_body(b"").unwrap(), json!({}));
+        assert_eq!(parse_body(b" {\"steps\": 3} ").unwrap(), json!({ "steps": 3 }));
+        assert!(parse_body(b"[1]").is_err());
+        assert!(parse_body(b"{").is_err());
+    }
+
+    #[test]
+    fn serves_the_page_and_the_picture() {
+        let picture = hello();
+        let mut run = Run::new(&picture, "");
+        let (status, content_type, body) = handle(&request("GET", "/", ""), &mut run, &picture);
+        assert_eq!((status, content_type), ("200 OK", "text/html; charset=utf-8"));
+        assert_eq!(body, PAGE.as_bytes().to_vec());
+
+        let (status, body) = answer(request("GET", "/api/picture", ""), &mut run, &picture);
+        assert_eq!(status, "200 OK");
+        assert_eq!(body["width"], picture[0].len());
+        assert_eq!(body["height"], picture.len());
+        assert_eq!(body["colors"][0][0], "#ffc0c0");
+    }
+
+    #[test]
+    fn steps_the_program() {
+        let picture = hello();
+        let mut run = Run::new(&picture, "");
+        let (status, state) = answer(request("GET", "/api/state", ""), &mut run, &picture);
+        assert_eq!(status, "200 OK");
+        assert_eq!((state["steps"].clone(), state["running"].clone()), (json!(0), json!(true)));
+
+        let (_, state) = answer(request("POST", "/api/step", ""), &mut run, &picture);
+        assert_eq!(state["steps"], 1);
+        assert_eq!(state["stack"].as_array().unwrap().len(), 1);
+
+        let steps = r#"{"steps": 100000}"#;
+        let (status, state) = answer(request("POST", "/api/step", steps), &mut run, &picture);
+        assert_eq!(status, "200 OK");
+        assert_eq!(state["running"], false);
+        assert_eq!(state["output"], "Hello");
+        assert_eq!(state["error"], Value::Null);
+    }
+
+    #[test]
+    fn restarts_with_input() {
+        use instruction::Instruction::*;
+        let echo = [InChar, InChar, OutChar, OutChar];
+        let ops: Vec<Op> = echo.iter().map(|&i| Op { instruction: i, value: 0 }).collect();
+        let picture = picture(&ops);
+        let mut run = Run::new(&picture, "");
+        let steps = r#"{"steps": 100}"#;
+        answer(request("POST", "/api/step", steps), &mut run, &picture);
+
+        let input = r#"{"input": "ab"}"#;
+        let (status, state) = answer(request("POST", "/api/restart", input), &mut run, &picture);
+        assert_eq!(status, "200 OK");
+        assert_eq!((state["steps"].clone(), state["output"].clone()), (json!(0), json!("")));
+        let (_, state) = answer(request("POST", "/api/step", steps), &mut run, &picture);
+        assert_eq!(state["output"], "ba");
+    }
+
+    #[test]
+    fn answers_errors() {
+        let picture = hello();
+        let mut run = Run::new(&picture, "");
+        let mut status =
+            |method, path, body| answer(request(method, path, body), &mut run, &picture);
+
+        let (code, body) = status("POST", "/api/step", "{");
+        assert_eq!(code, "400 Bad Request");
+        assert!(body["error"].as_str().unwrap().starts_with("invalid JSON"));
+        assert_eq!(status("POST", "/api/step", r#"{"steps": -1}"#).0, "400 Bad Request");
+        assert_eq!(status("POST", "/api/restart", r#"{"input": 1}"#).0, "400 Bad Request");
+        assert_eq!(status("GET", "/api/nothing", "").0, "404 Not Found");
+        assert_eq!(status("GET", "/api/step", "").0, "405 Method Not Allowed");
+        assert_eq!(status("DELETE", "/", "").0, "405 Method Not Allowed");
+        // Errors don't change the program
+        assert_eq!(status("GET", "/api/state", "").1["steps"], 0);
+    }
+}
@@ -0,0 +1,249 @@
+use serde_json::Value;
+use std::*;
+
+use std::io::prelude::*;
+
+/* Recordings of what a program reads, writes and gets from syscalls, so that runs can be
+ * reproduced. A recording is a JSON Lines file: a header, then one line per event in the order
+ * the program performed them. Events are compared when they are replayed, and the first
+ * difference stops the program, as what follows can't be reproduced.
+ * */
+
+pub const SESSION_VERSION: u64 = 1;
+
+// Exit status of a replay that doesn't do what was recorded
+pub const DIVERGED: i32 = 3;
+
+/* Records the events of a run to a file, or replays them from a file.
+ * Replay: The events left to replay, with their line in the file.
+ * */
+pub enum Session {
+    Record(io::BufWriter<fs::File>),
+    Replay(String, collections::VecDeque<(usize, Value)>),
+}
+
+impl Session {
+    pub fn record(path: &str) -> io::Result<Session> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        let header = json!({ "format": "pieti-session", "version": SESSION_VERSION });
+        writeln!(file, "{}", header)?;
+        Ok(Session::Record(file))
+    }
+
+    pub fn replay(path: &str) -> Result<Session, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Error: {}: {}", path, e))?;
+        let mut events = collections::VecDeque::new();
+        for (i, line) in text.lines().enumerate() {
+            match serde_json::from_str::<Value>(line) {
+                Ok(event) => events.push_back((i + 1, event)),
+                Err(e) => return Err(format!("Error: {}:{}: {}", path, i + 1, e)),
+            }
+        }
+        match events.pop_front() {
+            Some((_, ref header))
+                if header["format"] == "pieti-session" && header["version"] == SESSION_VERSION => {
+                Ok(Session::Replay(String::from(path), events))
+            },
+            _ => Err(format!("Error: {} isn't a version {} recording.", path, SESSION_VERSION)),
+        }
+    }
+
+    /* Records an event with the result of `perform`, or checks that the recording holds the
+     * same event and returns its result without calling `perform`.
+     * event: What the program does, which has to be the same when the run is replayed.
+     * */
+    fn event<F: FnOnce() -> Value>(&mut self, mut event: Value, perform: F) -> Value {
+        match *self {
+            Session::Record(ref mut file) => {
+                let result = perform();
+                if !result.is_null() {
+                    event["result"] = result.clone();
+                }
+                if let Err(e) = writeln!(file, "{}", event) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+                result
+            },
+            Session::Replay(ref path, ref mut events) => {
+                let (line, mut recorded) = match events.pop_front() {
+                    Some(recorded) => recorded,
+                    None => diverged(path, None, &format!("it ends before {}", event)),
+                };
+                let result = recorded.as_object_mut().and_then(|fields| fields.remove("result"));
+                if recorded != event {
+                    let difference = format!("{} was recorded, {} happened", recorded, event);
+                    diverged(path, Some(line), &difference);
+                }
+                result.unwrap_or(Value::Null)
+            },
+        }
+    }
+
+    /* Returns the character read by `read`, or the recorded one. */
+    pub fn input_char<F: FnOnce() -> Option<u8>>(&mut self, read: F) -> Option<u8> {
+        let result = self.event(json!({ "event": "inc" }), || json!(read()));
+        result.as_u64().map(|char| char as u8)
+    }
+
+    /* Returns the line read by `read`, or the recorded one. */
+    pub fn input_line<F: FnOnce() -> io::Result<String>>(&mut self, read: F) -> io::Result<String> {
+        let result = self.event(json!({ "event": "inn" }), || match read() {
+            Ok(line) => json!({ "line": line }),
+            Err(e) => json!({ "error": e.to_string() }),
+        });
+        match result["line"].as_str() {
+            Some(line) => Ok(String::from(line)),
+            None => {
+                // Only the message of the error is recorded, not its kind
+                let error = result["error"].as_str().unwrap_or("no input");
+                Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            },
+        }
+    }
+
+    /* Records or checks output, which is written by the program in both cases. */
+    pub fn output(&mut self, text: &str) {
+        self.event(json!({ "event": "output", "text": text }), || Value::Null);
+    }
+
+    /* Returns the stack after the syscall performed by `perform`, or the recorded one, as
+     * syscalls can write anywhere in the stack.
+     * stack: The stack before the syscall.
+     * */
+    pub fn syscall<F: FnOnce() -> Vec<i64>>(&mut self, stack: &[i64], perform: F) -> Vec<i64> {
+        let result = self.event(json!({ "event": "syscall", "stack": stack }), || json!(perform()));
+        match serde_json::from_value(result) {
+            Ok(stack) => stack,
+            Err(e) => diverged(self.path(), None, &format!("a syscall result is invalid: {}", e)),
+        }
+    }
+
+    fn path(&self) -> &str {
+        match *self {
+            Session::Record(..) => "",
+            Session::Replay(ref path, _) => path,
+        }
+    }
+
+    /* Writes what is left of the recording, or checks that every event was replayed.
+     * finished: Whether the program terminated. A run stopped before that doesn't get to the end
+     * of its recording, but what it recorded is written all the same.
+     * */
+    pub fn end(&mut self, finished: bool) {
+        match *self {
+            Session::Record(ref mut file) => {
+                if let Err(e) = file.flush() {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            },
+            Session::Replay(_, _) if !finished => {},
+            Session::Replay(ref path, ref events) => {
+                if let Some(&(line, ref recorded)) = events.front() {
+                    let difference = format!("{} was recorded after the end", recorded);
+                    diverged(path, Some(line), &difference);
+                }
+            },
+        }
+    }
+}
+
+/* Stops a replay that doesn't match its recording. */
+fn diverged(path: &str, line: Option<usize>, difference: &str) -> ! {
+    let location = line.map(|line| format!(":{}", line)).unwrap_or_default();
+    eprintln!("Error: the run diverged from {}{}: {}", path, location, difference);
+    process::exit(DIVERGED);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine::*;
+
+    fn temporary(name: &str) -> String {
+        let path = env::temp_dir().join(format!("pieti-session-{}-{}.jsonl", name, process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    /* Reads a character and a number, prints them back and ends the session.
+     * returns: What was printed.
+     * */
+    fn run(input: &str, session: Session) -> String {
+        use instruction::Instruction::*;
+        let output = SharedBuffer::new();
+        let input = Box::new(io::Cursor::new(input.as_bytes().to_vec()));
+        let mut machine = Machine::with_io(false, input, Box::new(output.clone()));
+        machine.session = Some(session);
+        for &instruction in [InChar, InNumber, OutNumber, OutChar].iter() {
+            machine.execute(instruction, 0);
+        }
+        machine.end_session(true);
+        output.take()
+    }
+
+    #[test]
+    fn replays_a_recording() {
+        let path = temporary("replay");
+        assert_eq!(run("a42\n", Session::record(&path).unwrap()), "42a");
+        let recording = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> =
+            recording.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                json!({ "format": "pieti-session", "version": SESSION_VERSION }),
+                json!({ "event": "inc", "result": 97 }),
+                json!({ "event": "inn", "result": { "line": "42\n" } }),
+                json!({ "event": "output", "text": "42" }),
+                json!({ "event": "output", "text": "a" }),
+            ]
+        );
+
+        // The input comes from the recording
+        assert_eq!(run("", Session::replay(&path).unwrap()), "42a");
+        fs::remove_file(&path).unwrap_or(());
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let path = temporary("other");
+        fs::write(&path, "{\"format\": \"pieti-trace\", \"version\": 1}\n").unwrap();
+        assert!(Session::replay(&path).is_err());
+        fs::write(&path, "not json\n").unwrap();
+        assert!(Session::replay(&path).is_err());
+        fs::remove_file(&path).unwrap_or(());
+    }
+
+    /* Replays the recording named by PIETI_DIVERGING_RECORDING, which exits the process. It is
+     * run in a process of its own by diverging_replays_exit.
+     * */
+    #[test]
+    #[ignore]
+    fn diverging_replay() {
+        if let Ok(path) = env::var("PIETI_DIVERGING_RECORDING") {
+            run("", Session::replay(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn diverging_replays_exit() {
+        let path = temporary("diverging");
+        run("a42\n", Session::record(&path).unwrap());
+        // The program prints the character it reads, which no longer is the one recorded
+        let recording = fs::read_to_string(&path).unwrap().replace("97", "98");
+        fs::write(&path, recording).unwrap();
+
+        let status = process::Command::new(env::current_exe().unwrap())
+            .arg("--ignored")
+            .arg("--exact")
+            .arg("session::tests::diverging_replay")
+            .env("PIETI_DIVERGING_RECORDING", &path)
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .status()
+            .unwrap();
+        fs::remove_file(&path).unwrap_or(());
+        assert_eq!(status.code(), Some(DIVERGED));
+    }
+}
@@ -0,0 +1,219 @@
+use codel::*;
+use interpreter::*;
+use machine::*;
+use serde_json::Value;
+use std::*;
+
+use std::io::prelude::*;
+
+/* Traces of the steps performed by a program, written as JSON Lines for analysis scripts. The
+ * schema is described in the readme, and TRACE_VERSION has to be incremented whenever a field is
+ * removed or its meaning changes. Adding fields doesn't change the version.
+ * */
+
+pub const TRACE_VERSION: u64 = 1;
+
+/* Returns the values an instruction is about to take from the stack, the deepest first. Push
+ * takes the size of the block instead.
+ * */
+pub fn operands(interpreter: &Interpreter) -> Vec<i64> {
+    use instruction::Instruction::*;
+    let instruction = match interpreter.peek() {
+        Some((step, Push)) => return vec![step.block_size as i64],
+        Some((_, instruction)) => instruction,
+        None => return vec![],
+    };
+    let count = match instruction {
+        Push | InNumber | InChar | Nop => 0,
+        Pop | Not | Pointer | Switch | Duplicate | OutNumber | OutChar => 1,
+        Add | Sub | Mul | Div | Mod | Greater | Roll | Syscall => 2,
+    };
+    let stack = &interpreter.machine.stack;
+    stack[stack.len() - cmp::min(count, stack.len())..].to_vec()
+}
+
+fn color(codel: &Codel) -> String {
+    let [red, green, blue] = codel.color.to_rgb();
+    format!("#{:02x}{:02x}{:02x}", red, green, blue)
+}
+
+/* Writes a trace to a file.
+ * stack_limit: The number of values at the top of the stack written after each step, None to
+ * write the whole stack.
+ * input, output: Copies of what the program reads and writes, see Tracer::machine.
+ * */
+pub struct Tracer {
+    file: io::BufWriter<fs::File>,
+    stack_limit: Option<usize>,
+    input: SharedBuffer,
+    output: SharedBuffer,
+}
+
+impl Tracer {
+    /* Creates the trace file and writes its header. */
+    pub fn create(path: &str, program: &str, stack_limit: Option<usize>) -> io::Result<Tracer> {
+        let mut tracer = Tracer {
+            file: io::BufWriter::new(fs::File::create(path)?),
+            stack_limit,
+            input: SharedBuffer::new(),
+            output: SharedBuffer::new(),
+        };
+        tracer.write(json!({
+            "format": "pieti-trace",
+            "version": TRACE_VERSION,
+            "program": program,
+        }))?;
+        Ok(tracer)
+    }
+
+    /* Returns a machine reading from stdin and writing to `output`, whose I/O is traced. What
+     * the program reads is traced even when it comes from a replayed session.
+     * */
+    pub fn machine(&self, debug: bool, output: Box<dyn io::Write>) -> Machine {
+        let output = Tee::new(output, self.output.clone());
+        let mut machine = Machine::with_io(debug, Box::new(io::stdin()), Box::new(output));
+        machine.read_copy = Some(self.input.clone());
+        machine
+    }
+
+    fn write(&mut self, record: Value) -> io::Result<()> {
+        writeln!(self.file, "{}", record)
+    }
+
+    /* Writes the record of a step.
+     * codel: The codel the interpreter was on before the step.
+     * operands: What `operands` returned before the step.
+     * */
+    pub fn record(
+        &mut self,
+        codel: &Codel,
+        step: &Step,
+        operands: Vec<i64>,
+        interpreter: &Interpreter,
+    ) -> io::Result<()> {
+        let stack = &interpreter.machine.stack;
+        let shown = cmp::min(self.stack_limit.unwrap_or(stack.len()), stack.len());
+        let record = json!({
+            "step": interpreter.steps,
+            "block": { "x": codel.x, "y": codel.y, "size": step.block_size, "color": color(codel) },
+            "to": { "x": step.to.x, "y": step.to.y, "color": color(&step.to) },
+            "dp": interpreter.dp.name(),
+            "cc": interpreter.cc.name(),
+            "instruction": step.instruction.name(),
+            "operands": operands,
+            "stack": &stack[stack.len() - shown..],
+            "stack_depth": stack.len(),
+            "input": self.input.take(),
+            "output": self.output.take(),
+        });
+        self.write(record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use generator::*;
+    use instruction::Instruction::*;
+    use optimizer::*;
+    use picture::*;
+    use pietcolor::*;
+
+    /* Traces a program that reads a character, adds 2 to it and prints the sum. */
+    #[test]
+    fn writes_the_schema() {
+        let ops: Vec<Op> = [(Push, 2), (InChar, 0), (Add, 0), (OutNumber, 0)]
+            .iter()
+            .map(|&(instruction, value)| Op { instruction, value })
+            .collect();
+        let pixels: Vec<Vec<_>> =
+            layout(&ops).iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        let picture = to_codels(&pixels, 1, white, false);
+
+        let path = env::temp_dir().join(format!("pieti-trace-{}.jsonl", process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut tracer = Tracer::create(&path, "program.png", Some(1)).unwrap();
+        let output = Tee::new(io::sink(), tracer.output.clone());
+        let input = Box::new(io::Cursor::new(b"a".to_vec()));
+        let mut machine = Machine::with_io(false, input, Box::new(output));
+        machine.read_copy = Some(tracer.input.clone());
+        let mut interpreter = Interpreter::new(&picture, machine);
+        loop {
+            let codel = interpreter.codel;
+            let operands = operands(&interpreter);
+            match interpreter.step() {
+                Some(step) => tracer.record(&codel, &step, operands, &interpreter).unwrap(),
+                None => break,
+            }
+        }
+        tracer.flush().unwrap();
+
+        let trace = fs::read_to_string(&path).unwrap();
+        let records: Vec<Value> =
+            trace.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let header =
+            json!({ "format": "pieti-trace", "version": TRACE_VERSION, "program": "program.png" });
+        let expected = vec![
+            header,
+            json!({
+                "step": 1,
+                "block": { "x": 0, "y": 0, "size": 2, "color": "#ffc0c0" },
+                "to": { "x": 2, "y": 0, "color": "#ff0000" },
+                "dp": "right",
+                "cc": "left",
+                "instruction": "push",
+                "operands": [2],
+                "stack": [2],
+                "stack_depth": 1,
+                "input": "",
+                "output": "",
+            }),
+            json!({
+                "step": 2,
+                "block": { "x": 2, "y": 0, "size": 1, "color": "#ff0000" },
+                "to": { "x": 3, "y": 0, "color": "#ff00ff" },
+                "dp": "right",
+                "cc": "left",
+                "instruction": "inc",
+                "operands": [],
+                "stack": [97],
+                "stack_depth": 2,
+                "input": "a",
+                "output": "",
+            }),
+            json!({
+                "step": 3,
+                "block": { "x": 3, "y": 0, "size": 1, "color": "#ff00ff" },
+                "to": { "x": 4, "y": 0, "color": "#ff0000" },
+                "dp": "right",
+                "cc": "left",
+                "instruction": "add",
+                "operands": [2, 97],
+                "stack": [99],
+                "stack_depth": 1,
+                "input": "",
+                "output": "",
+            }),
+            json!({
+                "step": 4,
+                "block": { "x": 4, "y": 0, "size": 1, "color": "#ff0000" },
+                "to": { "x": 5, "y": 0, "color": "#c000c0" },
+                "dp": "right",
+                "cc": "left",
+                "instruction": "outn",
+                "operands": [99],
+                "stack": [],
+                "stack_depth": 0,
+                "input": "",
+                "output": "99",
+            }),
+        ];
+        assert_eq!(records, expected);
+        fs::remove_file(&path).unwrap_or(());
+    }
+}
@@ -0,0 +1,612 @@
+use graph::*;
+use instruction::*;
+use optimizer::*;
+use std::fmt::Write;
+
+/* Compilation of Piet programs to the WebAssembly text format.
+ *
+ * Every basic block of the program becomes a function that performs the instructions of the
+ * block and returns the id of the next block, or -1 when the program terminates. The exported
+ * `run` function calls these functions through a table until -1 is returned.
+ *
+ * The Piet stack lives in the exported memory, as 64 bits values starting at address 0, and
+ * grows as needed. The number of values on the stack is returned by the exported `stack_size`
+ * function.
+ *
+ * The module imports the following functions from "env":
+ * - read_char() -> i64: Returns the next byte of input, or -1 if there is none.
+ * - read_int() -> i64: Returns the next number of input.
+ * - write_char(i64): Writes the given unicode code point.
+ * - write_int(i64): Writes the given number.
+ *
+ * The instructions behave like they do in the interpreter, including when the stack doesn't
+ * hold enough values. Arithmetic wraps around on overflow. Dividing by zero, and rolling
+ * deeper than the stack or more than the roll depth, trap. Syscalls can't be compiled.
+ * */
+
+const RUNTIME: &str = r#"
+  (import "env" "read_char" (func $read_char (result i64)))
+  (import "env" "read_int" (func $read_int (result i64)))
+  (import "env" "write_char" (func $write_char (param i64)))
+  (import "env" "write_int" (func $write_int (param i64)))
+
+  (memory (export "memory") 1)
+  (global $sp (mut i32) (i32.const 0))
+  (type $step (func (result i32)))
+
+  (func (export "stack_size") (result i32)
+    (global.get $sp))
+
+  (func $push (param $value i64)
+    (if (i32.gt_u (i32.mul (i32.add (global.get $sp) (i32.const 1)) (i32.const 8))
+                  (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+          (then unreachable))))
+    (i64.store (i32.mul (global.get $sp) (i32.const 8)) (local.get $value))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 1))))
+
+  (func $pop (result i64)
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 1)))
+    (i64.load (i32.mul (global.get $sp) (i32.const 8))))
+
+  ;; Binary instructions pop both of their operands before checking them, a lone value is lost.
+  (func $has_two (result i32)
+    (if (i32.ge_u (global.get $sp) (i32.const 2))
+      (then (return (i32.const 1))))
+    (global.set $sp (i32.const 0))
+    (i32.const 0))
+
+  (func $drop
+    (if (i32.gt_u (global.get $sp) (i32.const 0))
+      (then (drop (call $pop)))))
+
+  (func $add (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.add (call $pop) (local.get $a))))))
+
+  (func $sub (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.sub (call $pop) (local.get $a))))))
+
+  (func $mul (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.mul (call $pop) (local.get $a))))))
+
+  (func $div (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.div_s (call $pop) (local.get $a))))))
+
+  (func $mod (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.add (i64.rem_s (call $pop) (local.get $a)) (local.get $a))))))
+
+  (func $not
+    (if (i32.gt_u (global.get $sp) (i32.const 0))
+      (then (call $push (i64.extend_i32_u (i64.eqz (call $pop)))))))
+
+  (func $greater (local $a i64)
+    (if (call $has_two)
+      (then
+        (local.set $a (call $pop))
+        (call $push (i64.extend_i32_u (i64.gt_s (call $pop) (local.get $a)))))))
+
+  ;; Returns how many times dp has to be rotated.
+  (func $pointer (result i32) (local $a i64)
+    (if (i32.eqz (global.get $sp))
+      (then (return (i32.const 0))))
+    (local.set $a (call $pop))
+    (if (i64.le_s (local.get $a) (i64.const 0))
+      (then (return (i32.const 0))))
+    (i32.wrap_i64 (i64.rem_u (local.get $a) (i64.const 4))))
+
+  ;; Returns how many times cc has to be toggled.
+  (func $switch (result i32) (local $a i64)
+    (if (i32.eqz (global.get $sp))
+      (then (return (i32.const 0))))
+    (local.set $a (call $pop))
+    (if (i64.le_s (local.get $a) (i64.const 0))
+      (then (return (i32.const 0))))
+    (i32.wrap_i64 (i64.rem_u (local.get $a) (i64.const 2))))
+
+  (func $duplicate (local $a i64)
+    (if (i32.gt_u (global.get $sp) (i32.const 0))
+      (then
+        (local.set $a (call $pop))
+        (call $push (local.get $a))
+        (call $push (local.get $a)))))
+
+  ;; Moves the value at index $from of the stack to index $to, shifting the values in between.
+  (func $move (param $from i32) (param $to i32) (local $value i64)
+    (local.set $value (i64.load (i32.mul (local.get $from) (i32.const 8))))
+    (if (i32.lt_u (local.get $from) (local.get $to))
+      (then
+        (memory.copy
+          (i32.mul (local.get $from) (i32.const 8))
+          (i32.mul (i32.add (local.get $from) (i32.const 1)) (i32.const 8))
+          (i32.mul (i32.sub (local.get $to) (local.get $from)) (i32.const 8))))
+      (else
+        (memory.copy
+          (i32.mul (i32.add (local.get $to) (i32.const 1)) (i32.const 8))
+          (i32.mul (local.get $to) (i32.const 8))
+          (i32.mul (i32.sub (local.get $from) (local.get $to)) (i32.const 8)))))
+    (i64.store (i32.mul (local.get $to) (i32.const 8)) (local.get $value)))
+
+  ;; Like the interpreter, a negative roll leaves the rolled values in reverse order.
+  (func $roll (local $count i64) (local $depth i64) (local $base i32) (local $top i32)
+              (local $n i32) (local $i i32)
+    (if (i32.eqz (call $has_two))
+      (then (return)))
+    (local.set $count (call $pop))
+    (local.set $depth (call $pop))
+    (if (i32.or (i64.lt_s (local.get $depth) (i64.const 0))
+                (i64.gt_s (local.get $depth) (i64.extend_i32_u (global.get $sp))))
+      (then unreachable))
+    (if (i32.or (i64.gt_s (local.get $count) (local.get $depth))
+                (i64.lt_s (local.get $count) (i64.sub (i64.const 0) (local.get $depth))))
+      (then unreachable))
+    (local.set $top (i32.sub (global.get $sp) (i32.const 1)))
+    (local.set $base (i32.sub (global.get $sp) (i32.wrap_i64 (local.get $depth))))
+    (if (i64.ge_s (local.get $count) (i64.const 0))
+      (then
+        (local.set $n (i32.wrap_i64 (local.get $count)))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_s (local.get $i) (local.get $n)))
+            (call $move (local.get $top) (local.get $base))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $next))))
+      (else
+        (local.set $n (i32.wrap_i64 (i64.sub (i64.const 0) (local.get $count))))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_s (local.get $i) (local.get $n)))
+            (call $move (local.get $base) (i32.sub (local.get $top) (local.get $i)))
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br $next))))))
+
+  (func $in_number
+    (call $push (call $read_int)))
+
+  (func $in_char (local $a i64)
+    (local.set $a (call $read_char))
+    (if (i64.ge_s (local.get $a) (i64.const 0))
+      (then (call $push (local.get $a)))))
+
+  (func $out_number
+    (if (i32.gt_u (global.get $sp) (i32.const 0))
+      (then (call $write_int (call $pop)))))
+
+  (func $out_char
+    (if (i32.gt_u (global.get $sp) (i32.const 0))
+      (then (call $write_char (call $pop)))))
+"#;
+
+/* Returns the name of the runtime function implementing the instruction, None for the
+ * instructions that are compiled inline.
+ * */
+fn runtime_function(instruction: Instruction) -> Option<&'static str> {
+    use instruction::Instruction::*;
+    match instruction {
+        Pop => Some("$drop"),
+        Add => Some("$add"),
+        Sub => Some("$sub"),
+        Mul => Some("$mul"),
+        Div => Some("$div"),
+        Mod => Some("$mod"),
+        Not => Some("$not"),
+        Greater => Some("$greater"),
+        Duplicate => Some("$duplicate"),
+        Roll => Some("$roll"),
+        InNumber => Some("$in_number"),
+        InChar => Some("$in_char"),
+        OutNumber => Some("$out_number"),
+        OutChar => Some("$out_char"),
+        Push | Pointer | Switch | Syscall | Nop => None,
+    }
+}
+
+/* Writes the end of the function of a block whose next block depends on the value returned by
+ * `selector`: one wasm block per possible next block, br_table jumping out of the right one.
+ * */
+fn write_branch(out: &mut String, selector: &str, next: &[usize]) {
+    let labels: Vec<String> = (0..next.len()).map(|i| format!("$next{}", i)).collect();
+    write!(out, "   ").unwrap();
+    for label in labels.iter().rev() {
+        write!(out, " (block {}", label).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "      (br_table {} (call {}))", labels.join(" "), selector).unwrap();
+    for (i, id) in next.iter().enumerate() {
+        if i + 1 < next.len() {
+            writeln!(out, "    ) (return (i32.const {}))", id).unwrap();
+        } else {
+            writeln!(out, "    ) (i32.const {})", id).unwrap();
+        }
+    }
+}
+
+/* Compiles a program into a WebAssembly module in text format.
+ * graph: The graph the program was built from, used to annotate the output.
+ * returns: The module, or an error message if the program uses instructions that can't be
+ * compiled.
+ * */
+pub fn compile(graph: &Graph, program: &Program) -> Result<String, String> {
+    let mut out = String::new();
+    writeln!(out, "(module").unwrap();
+    out.push_str(RUNTIME);
+    writeln!(out).unwrap();
+
+    for (id, block) in program.blocks.iter().enumerate() {
+        let node = &graph.nodes[block.node];
+        let (x, y) = (node.codel.x, node.codel.y);
+        writeln!(out, "  ;; ({}, {}), dp: {:?}, cc: {:?}", x, y, node.dp, node.cc).unwrap();
+        writeln!(out, "  (func $n{} (type $step)", id).unwrap();
+        for op in block.ops.iter() {
+            match op.instruction {
+                Instruction::Syscall => {
+                    return Err(format!(
+                        "Error: can't compile the syscall in the block starting at ({}, {}).",
+                        x,
+                        y
+                    ));
+                },
+                Instruction::Push => {
+                    writeln!(out, "    (call $push (i64.const {}))", op.value).unwrap();
+                },
+                instruction => {
+                    if let Some(function) = runtime_function(instruction) {
+                        writeln!(out, "    (call {})", function).unwrap();
+                    }
+                },
+            }
+        }
+        match block.terminator {
+            Terminator::Halt => writeln!(out, "    (i32.const -1)").unwrap(),
+            Terminator::Jump(next) => writeln!(out, "    (i32.const {})", next).unwrap(),
+            Terminator::Pointer(ref next) => write_branch(&mut out, "$pointer", next),
+            Terminator::Switch(ref next) => write_branch(&mut out, "$switch", next),
+        }
+        out.pop();
+        writeln!(out, ")").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    write!(out, "  (table {} funcref)\n  (elem (i32.const 0)", program.blocks.len()).unwrap();
+    for id in 0..program.blocks.len() {
+        write!(out, " $n{}", id).unwrap();
+    }
+    writeln!(out, ")").unwrap();
+    writeln!(
+        out,
+        r#"
+  (func (export "run") (local $state i32)
+    (local.set $state (i32.const {}))
+    (block $halt
+      (loop $step
+        (br_if $halt (i32.lt_s (local.get $state) (i32.const 0)))
+        (local.set $state (call_indirect (type $step) (local.get $state)))
+        (br $step)))))"#,
+        program.start
+    ).unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codel::*;
+    use generator::*;
+    use numbers::*;
+    use picture::*;
+    use pietcolor::*;
+    use std::*;
+
+    /* The modules are checked by looking at their structure, parsed as S-expressions. */
+    #[derive(Debug, Clone, PartialEq)]
+    enum Sexp {
+        Atom(String),
+        List(Vec<Sexp>),
+    }
+
+    impl Sexp {
+        fn atom(&self) -> Option<&str> {
+            match *self {
+                Sexp::Atom(ref atom) => Some(atom),
+                Sexp::List(_) => None,
+            }
+        }
+
+        fn items(&self) -> &[Sexp] {
+            match *self {
+                Sexp::List(ref items) => items,
+                Sexp::Atom(_) => &[],
+            }
+        }
+
+        /* Returns the first atom of a list, which names what the list is. */
+        fn head(&self) -> Option<&str> {
+            self.items().first().and_then(Sexp::atom)
+        }
+
+        /* Returns the second atom of a list, the name of a function or the label of a block. */
+        fn name(&self) -> Option<&str> {
+            self.items().get(1).and_then(Sexp::atom)
+        }
+
+        /* Calls `f` on this expression and every expression it contains. */
+        fn walk<'a, F: FnMut(&'a Sexp)>(&'a self, f: &mut F) {
+            f(self);
+            for item in self.items() {
+                item.walk(f);
+            }
+        }
+    }
+
+    fn parse(text: &str) -> Sexp {
+        let mut lists = vec![vec![]];
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '(' => lists.push(vec![]),
+                ')' => {
+                    let list = lists.pop().unwrap();
+                    lists.last_mut().expect("unbalanced parentheses").push(Sexp::List(list));
+                },
+                ';' if chars.peek() == Some(&';') => {
+                    // Comments go to the end of the line
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                },
+                c if c.is_whitespace() => {},
+                c => {
+                    let mut atom = c.to_string();
+                    while let Some(&next) = chars.peek() {
+                        let open = atom.len() == 1 || !atom.ends_with('"');
+                        let quoted = atom.starts_with('"') && open;
+                        if !quoted && (next.is_whitespace() || next == '(' || next == ')') {
+                            break;
+                        }
+                        atom.push(next);
+                        chars.next();
+                    }
+                    lists.last_mut().unwrap().push(Sexp::Atom(atom));
+                },
+            }
+        }
+        let mut top = lists.pop().unwrap();
+        assert!(lists.is_empty() && top.len() == 1, "unbalanced parentheses");
+        top.pop().unwrap()
+    }
+
+    /* Parses a module and checks what every module has to hold: a function per block, all of
+     * them in the table in order, calls to functions that exist, and a start at the first block
+     * of the program.
+     * */
+    fn check_module(text: &str, program: &Program) -> Sexp {
+        let module = parse(text);
+        assert_eq!(module.head(), Some("module"));
+        let mut defined = collections::HashSet::new();
+        let mut called = vec![];
+        module.walk(&mut |sexp| match sexp.head() {
+            Some("func") => {
+                if let Some(name) = sexp.name().filter(|name| name.starts_with('$')) {
+                    defined.insert(name.to_string());
+                }
+            },
+            Some("call") => called.push(sexp.name().unwrap().to_string()),
+            _ => {},
+        });
+        for name in called.iter() {
+            assert!(defined.contains(name), "{} is called but not defined", name);
+        }
+        let blocks: Vec<String> = (0..program.blocks.len()).map(|id| format!("$n{}", id)).collect();
+        for name in blocks.iter() {
+            assert!(defined.contains(name), "{} isn't defined", name);
+        }
+        let elem = module.items().iter().find(|item| item.head() == Some("elem")).unwrap();
+        let table: Vec<&str> = elem.items()[2..].iter().filter_map(Sexp::atom).collect();
+        assert_eq!(table, blocks.iter().map(|name| name.as_str()).collect::<Vec<&str>>());
+        let run = module
+            .items()
+            .iter()
+            .find(|item| item.head() == Some("func") && item.items()[1].name() == Some("\"run\""))
+            .unwrap();
+        let start = &run.items()[3];
+        assert_eq!(start.head(), Some("local.set"));
+        assert_eq!(constant(&start.items()[2]), program.start as i64);
+        module
+    }
+
+    fn function<'a>(module: &'a Sexp, name: &str) -> &'a Sexp {
+        let is_function = |item: &&Sexp| item.head() == Some("func") && item.name() == Some(name);
+        module.items().iter().find(is_function).unwrap()
+    }
+
+    /* Returns the value of (i32.const value), or of (return (i32.const value)). */
+    fn constant(sexp: &Sexp) -> i64 {
+        match sexp.head() {
+            Some("return") => constant(&sexp.items()[1]),
+            Some("i32.const") | Some("i64.const") => sexp.name().unwrap().parse().unwrap(),
+            _ => panic!("{:?} isn't a constant", sexp),
+        }
+    }
+
+    /* Describes what the function of a block does, such as ["push 5", "out_number", "-> -1"],
+     * "-> n" being the block it returns and "branch" a br_table.
+     * */
+    fn body(function: &Sexp) -> Vec<String> {
+        function.items()[3..]
+            .iter()
+            .map(|item| match item.head() {
+                Some("call") if item.name() == Some("$push") => {
+                    format!("push {}", constant(&item.items()[2]))
+                },
+                Some("call") => item.name().unwrap()[1..].to_string(),
+                Some("i32.const") => format!("-> {}", constant(item)),
+                Some("block") => String::from("branch"),
+                _ => panic!("unexpected {:?}", item),
+            })
+            .collect()
+    }
+
+    /* Returns the expression that follows the block labeled `label`, where a branch to that
+     * label continues.
+     * */
+    fn after_block<'a>(sexp: &'a Sexp, label: &str) -> Option<&'a Sexp> {
+        let items = sexp.items();
+        for (i, item) in items.iter().enumerate() {
+            if item.head() == Some("block") && item.name() == Some(label) {
+                return items.get(i + 1);
+            }
+            if let Some(after) = after_block(item, label) {
+                return Some(after);
+            }
+        }
+        None
+    }
+
+    /* Returns the function called to choose the branch of a block, and the block returned for
+     * each value it can return.
+     * */
+    fn branches(function: &Sexp) -> (String, Vec<i64>) {
+        let mut table = None;
+        function.walk(&mut |sexp| {
+            if sexp.head() == Some("br_table") {
+                table = Some(sexp);
+            }
+        });
+        let table = table.unwrap().items();
+        let selector = table.last().unwrap().name().unwrap().to_string();
+        let next = table[1..table.len() - 1]
+            .iter()
+            .map(|label| constant(after_block(function, label.atom().unwrap()).unwrap()))
+            .collect();
+        (selector, next)
+    }
+
+    fn picture(colors: &[Vec<PietColor>], syscalls: bool) -> Vec<Vec<Codel>> {
+        let pixels: Vec<Vec<_>> =
+            colors.iter().map(|row| row.iter().map(|c| c.to_rgb()).collect()).collect();
+        let white = PietColor { hue: Hue::White, lightness: Lightness::Normal };
+        to_codels(&pixels, 1, white, syscalls)
+    }
+
+    fn push(value: i64) -> Op {
+        Op { instruction: Instruction::Push, value }
+    }
+
+    fn op(instruction: Instruction) -> Op {
+        Op { instruction, value: 0 }
+    }
+
+    #[test]
+    fn compiles_every_block() {
+        let model = CostModel { max_push: 12, codel_cost: 1, instruction_cost: 0 };
+        let ops = print_program("Hello, world!", &mut Synthesizer::new(model));
+        let picture = picture(&layout(&ops), false);
+        let graph = build_graph(&picture);
+        for program in [lower(&graph), optimize(&graph)].iter() {
+            check_module(&compile(&graph, program).unwrap(), program);
+        }
+    }
+
+    #[test]
+    fn compiles_instructions_in_order() {
+        use instruction::Instruction::*;
+        let ops = vec![push(2), push(3), op(Add), op(Duplicate), op(OutNumber), op(InChar)];
+        let picture = picture(&layout(&ops), false);
+        let graph = build_graph(&picture);
+
+        // Every instruction is kept, following the blocks from the start
+        let program = lower(&graph);
+        let module = check_module(&compile(&graph, &program).unwrap(), &program);
+        let mut performed = vec![];
+        let mut block = program.start as i64;
+        while block >= 0 {
+            let mut body = body(function(&module, &format!("$n{}", block)));
+            let next = body.pop().unwrap();
+            performed.extend(body);
+            block = next["-> ".len()..].parse().unwrap();
+        }
+        let expected = ["push 2", "push 3", "add", "duplicate", "out_number", "in_char"];
+        assert_eq!(performed, expected);
+
+        let program = optimize(&graph);
+        let module = check_module(&compile(&graph, &program).unwrap(), &program);
+        let body = body(function(&module, &format!("$n{}", program.start)));
+        assert_eq!(body, vec!["push 5", "push 5", "out_number", "in_char", "-> -1"]);
+    }
+
+    #[test]
+    fn branches_on_pointer_and_switch() {
+        // Pushes 1 and rotates dp leaving the second codel, toggles cc coming back to it
+        let colors = vec![vec![
+            PietColor { hue: Hue::Red, lightness: Lightness::Light },
+            PietColor { hue: Hue::Red, lightness: Lightness::Normal },
+            PietColor { hue: Hue::Cyan, lightness: Lightness::Dark },
+        ]];
+        let picture = picture(&colors, false);
+        let graph = build_graph(&picture);
+        for program in [lower(&graph), optimize(&graph)].iter() {
+            let module = check_module(&compile(&graph, program).unwrap(), program);
+            let mut selectors = vec![];
+            for (id, block) in program.blocks.iter().enumerate() {
+                let (selector, next) = match block.terminator {
+                    Terminator::Pointer(ref next) => ("$pointer", next),
+                    Terminator::Switch(ref next) => ("$switch", next),
+                    _ => continue,
+                };
+                let function = function(&module, &format!("$n{}", id));
+                // The last block is reached by falling through
+                let body = body(function);
+                assert_eq!(body[body.len() - 2], "branch");
+                let next: Vec<i64> = next.iter().map(|&id| id as i64).collect();
+                assert_eq!(branches(function), (String::from(selector), next));
+                selectors.push(selector);
+            }
+            assert!(selectors.contains(&"$pointer") && selectors.contains(&"$switch"));
+        }
+    }
+
+    #[test]
+    fn refuses_syscalls() {
+        let colors = vec![vec![
+            PietColor { hue: Hue::Red, lightness: Lightness::Light },
+            PietColor { hue: Hue::Smoke, lightness: Lightness::Normal },
+            PietColor { hue: Hue::Red, lightness: Lightness::Light },
+        ]];
+        let picture = picture(&colors, true);
+        let graph = build_graph(&picture);
+        let error = compile(&graph, &lower(&graph)).unwrap_err();
+        assert!(error.contains("syscall"), "{}", error);
+    }
+
+    #[test]
+    fn defines_the_runtime_functions() {
+        use instruction::Instruction::*;
+        let module = parse(&format!("(module {})", RUNTIME));
+        let instructions = [
+            Pop, Add, Sub, Mul, Div, Mod, Not, Greater, Duplicate, Roll, InNumber, InChar,
+            OutNumber, OutChar,
+        ];
+        for &instruction in instructions.iter() {
+            if let Some(name) = runtime_function(instruction) {
+                function(&module, name);
+            }
+        }
+    }
+}